@@ -16,7 +16,7 @@ use loops::GameLoop;
 use pollster::FutureExt;
 use rand::{rngs::StdRng, SeedableRng};
 use systems::{ParticleLifetime, Position, SpawnCommand};
-use tiefring::{Canvas, CanvasSettings, Color, SizeInPx};
+use tiefring::{Canvas, CanvasSettings, Color, InstanceDesc, SizeInPx};
 use winit::{
     dpi::LogicalSize,
     event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
@@ -206,14 +206,17 @@ fn main() {
                     .draw(|graphics| {
                         let mut world = world.lock().unwrap();
                         let mut query = world.query::<(&Position, &ParticleLifetime)>();
-                        let mut count: usize = 0;
-                        for (position, particle_lifetime) in query.iter(&world) {
-                            count += 1;
-                            graphics
-                                .draw_sprite(&star, (position.x, position.y))
-                                .rotate(TAU * particle_lifetime.freshness())
-                                .alpha(particle_lifetime.freshness());
-                        }
+                        let instances: Vec<_> = query
+                            .iter(&world)
+                            .map(|(position, particle_lifetime)| InstanceDesc {
+                                position: tiefring::Position::new(position.x, position.y),
+                                rotation: TAU * particle_lifetime.freshness(),
+                                alpha: particle_lifetime.freshness(),
+                                ..Default::default()
+                            })
+                            .collect();
+                        let count = instances.len();
+                        graphics.draw_instances(&star, &instances);
 
                         let fps = fps_counter.tick();
                         let text: Cow<_> = if count == 0 {
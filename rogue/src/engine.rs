@@ -134,9 +134,9 @@ impl Renderer {
     }
 
     fn render_game(&self, game: &mut Game, graphics: &mut Graphics) {
-        let SizeInPx { width, height } = graphics.size();
-        let cell_count_x = (width as f32 / TILE_SIZE).ceil() as i32;
-        let cell_count_y = (height as f32 / TILE_SIZE).ceil() as i32;
+        let (width, height) = graphics.world_size();
+        let cell_count_x = (width / TILE_SIZE).ceil() as i32;
+        let cell_count_y = (height / TILE_SIZE).ceil() as i32;
 
         let (dx, dy) = Renderer::calculate_translation_in_tiles(game, graphics);
         let translate_x = dx as f32 * TILE_SIZE;
@@ -366,7 +366,7 @@ impl StatBar {
             HEIGHT,
         );
 
-        graphics.draw_rect(rect, darker(&self.color));
+        graphics.draw_rect(rect, self.color.darken(0.75));
 
         graphics.draw_text(
             &mut self.font.borrow_mut(),
@@ -392,12 +392,3 @@ impl CommonData for World {
         self.get_resource::<PlayerData>().unwrap().entity
     }
 }
-
-pub fn darker(color: &Color) -> Color {
-    Color {
-        a: color.a,
-        r: color.r * 0.75,
-        g: color.g * 0.75,
-        b: color.b * 0.75,
-    }
-}
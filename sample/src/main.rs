@@ -3,7 +3,7 @@ use std::{
     time::{Duration, Instant},
 };
 
-use tiefring::{Canvas, CanvasSettings, Color, Position};
+use tiefring::{text::TextOptions, BlendMode, Canvas, CanvasSettings, Color, Position};
 use utils::Averager;
 use winit::{
     dpi::LogicalSize,
@@ -46,6 +46,7 @@ fn main() {
                     a: 1.0,
                 },
                 scale: 1.0,
+                ..Default::default()
             },
         ))
     }
@@ -192,6 +193,17 @@ fn main() {
                         graphics
                             .draw_sprite(&rocket, Position::new(300.0, 150.0))
                             .rotate(-0.25);
+                        // A radial gauge filled with the angle, its pattern turning while the square
+                        // stays put.
+                        if let Some(operation) =
+                            graphics.draw_sprite_tiled(&alien_3, [560, 150, 96, 96], (24, 32))
+                        {
+                            operation.rotate_uv(angle).clip_to_ring(
+                                0.5,
+                                -PI / 2.0,
+                                angle.rem_euclid(2.0 * PI),
+                            );
+                        }
 
                         graphics.draw_text(
                             &mut vt323_regular,
@@ -208,6 +220,29 @@ fn main() {
                             Position::new(10.0, 420.0),
                             Color::rgb(1.0, 1.0, 1.0),
                         );
+
+                        // A spread out copy, added under the sharp text, makes it glow.
+                        let glow = TextOptions {
+                            blend_mode: BlendMode::Additive,
+                            ..Default::default()
+                        };
+                        for (x, y) in [(-2.0, 0.0), (2.0, 0.0), (0.0, -2.0), (0.0, 2.0)] {
+                            graphics.draw_text_with_options(
+                                &mut roboto_regular,
+                                "Neon",
+                                48,
+                                Position::new(480.0 + x, 380.0 + y),
+                                Color::rgba(1.0, 0.2, 0.8, 0.4),
+                                &glow,
+                            );
+                        }
+                        graphics.draw_text(
+                            &mut roboto_regular,
+                            "Neon",
+                            48,
+                            Position::new(480.0, 380.0),
+                            Color::rgb(1.0, 0.9, 1.0),
+                        );
                     });
                 })
                 .unwrap();
@@ -0,0 +1,52 @@
+//! Draws a particle stream whose count changes every frame with each [`BufferStrategy`], and
+//! reports the CPU time and the allocations per frame of each. Needs a GPU adapter.
+//!
+//! Run it with `cargo bench -p tiefring --bench buffer_strategies`.
+
+mod common;
+
+use std::time::Instant;
+
+use common::{count_allocations, device, CountingAllocator};
+use tiefring::{BufferStrategy, CanvasSettings, Color, GraphicsRenderer, Rect};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const FRAMES: usize = 240;
+
+fn main() {
+    let (device, queue) = device();
+    let color = Color::rgb(1.0, 1.0, 1.0);
+
+    for buffer_strategy in [BufferStrategy::Pooled, BufferStrategy::Growable] {
+        let canvas_settings = CanvasSettings {
+            buffer_strategy,
+            ..Default::default()
+        };
+        let mut graphics_renderer =
+            GraphicsRenderer::new(&device, &queue, 640, 480, &canvas_settings);
+
+        let start = Instant::now();
+        let mut allocations = 0;
+        for frame in 0..FRAMES {
+            // Particles spawn and die, so their count changes every frame.
+            let count = 10_000 + frame * 997 % 10_000;
+            allocations += count_allocations(|| {
+                graphics_renderer.prepare(&device, &queue, |graphics| {
+                    for index in 0..count {
+                        let position = (index % 640) as f32;
+                        graphics.draw_rect(Rect::new(position, position, 4.0, 4.0), color);
+                    }
+                });
+            });
+            device.poll(wgpu::Maintain::Wait);
+        }
+
+        println!(
+            "{buffer_strategy:?}: {:?} and {} allocations per frame",
+            start.elapsed() / FRAMES as u32,
+            allocations / FRAMES
+        );
+    }
+}
@@ -0,0 +1,64 @@
+// Each benchmark uses only part of these.
+#![allow(dead_code)]
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use wgpu::{Device, Queue};
+
+/// Counts every allocation, to report how many a frame costs.
+pub struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// How many times `function` allocated. Benchmarks run on a single thread, so this is close to
+/// what `function` allocated, give or take wgpu's own threads.
+pub fn count_allocations(function: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    function();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+/// Polls the future until it's ready, which wgpu's native futures are right away.
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct NoopWaker;
+    impl std::task::Wake for NoopWaker {
+        fn wake(self: std::sync::Arc<Self>) {}
+    }
+
+    let waker = std::task::Waker::from(std::sync::Arc::new(NoopWaker));
+    let mut context = std::task::Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+/// A device to draw with, from a Vulkan, Metal or DX12 adapter.
+pub fn device() -> (Device, Queue) {
+    let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
+    let adapter = block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+        .expect("no Vulkan, Metal or DX12 adapter");
+    block_on(adapter.request_device(&wgpu::DeviceDescriptor::default(), None))
+        .expect("the device couldn't be created")
+}
@@ -0,0 +1,55 @@
+//! Prepares 50,000 particles drawn one by one with `draw_sprite`, then all at once with
+//! `draw_instances`, and reports the CPU time per frame of each. Needs a GPU adapter.
+//!
+//! Run it with `cargo bench -p tiefring --bench instances`.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::device;
+use tiefring::{CanvasSettings, GraphicsRenderer, InstanceDesc, Position};
+
+const PARTICLES: usize = 50_000;
+const FRAMES: u32 = 60;
+
+fn main() {
+    let (device, queue) = device();
+    let mut graphics_renderer =
+        GraphicsRenderer::new(&device, &queue, 640, 480, &CanvasSettings::default());
+    let sprite = graphics_renderer.resources(&device, &queue).white_sprite();
+    let particles: Vec<InstanceDesc> = (0..PARTICLES)
+        .map(|index| InstanceDesc {
+            position: Position::new((index % 640) as f32, (index % 480) as f32),
+            rotation: index as f32 * 0.01,
+            alpha: 0.5,
+            ..Default::default()
+        })
+        .collect();
+
+    let mut frame = |prepare: &mut dyn FnMut(&mut tiefring::Graphics)| {
+        // The fastest frame, which is the least disturbed by the rest of the machine.
+        let mut fastest = Duration::MAX;
+        for _ in 0..FRAMES {
+            let start = Instant::now();
+            graphics_renderer.prepare(&device, &queue, |graphics| prepare(graphics));
+            fastest = fastest.min(start.elapsed());
+            device.poll(wgpu::Maintain::Wait);
+        }
+        fastest
+    };
+
+    let through_operations = frame(&mut |graphics| {
+        for particle in &particles {
+            graphics
+                .draw_sprite(&sprite, particle.position)
+                .rotate(particle.rotation)
+                .alpha(particle.alpha);
+        }
+    });
+    let through_instances = frame(&mut |graphics| graphics.draw_instances(&sprite, &particles));
+
+    println!("{PARTICLES} particles, fastest frame of {FRAMES}:");
+    println!("- {through_operations:?} with draw_sprite");
+    println!("- {through_instances:?} with draw_instances");
+}
@@ -0,0 +1,430 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use wgpu::{Device, Queue};
+
+use crate::{
+    renderer::{ColorMatrix, RenderOperation},
+    shared::Shared,
+    sprite::{Texture, TextureContext},
+    text::{expand_tabs, TextLine, TextOptions},
+    Color, Error, Position, Rect, Transform,
+};
+
+struct BitmapCharacter {
+    tex_coords: Rect,
+    width: f32,
+    height: f32,
+    x_offset: f32,
+    y_offset: f32,
+    x_advance: f32,
+}
+
+/// The content of a `.fnt` file, for a page image of the given dimensions.
+struct FontDescriptor {
+    size: f32,
+    line_height: f32,
+    base: f32,
+    characters: HashMap<char, BitmapCharacter>,
+    kernings: HashMap<(char, char), f32>,
+}
+
+impl FontDescriptor {
+    fn parse(descriptor: &str, dimensions: (u32, u32), fnt_path: &Path) -> Result<Self, Error> {
+        let invalid = || Error::LoadingFailed(fnt_path.into());
+        let (width, height) = (dimensions.0 as f32, dimensions.1 as f32);
+
+        let mut size = None;
+        let mut line_height = None;
+        let mut base = None;
+        let mut characters = HashMap::new();
+        let mut kernings = HashMap::new();
+
+        for line in descriptor.lines() {
+            let (tag, attributes) = parse_line(line);
+            let number = |key: &str| -> Result<f32, Error> {
+                attributes
+                    .get(key)
+                    .and_then(|value| value.parse::<f32>().ok())
+                    .ok_or_else(invalid)
+            };
+
+            match tag {
+                "info" => size = Some(number("size")?.abs()),
+                "common" => {
+                    line_height = Some(number("lineHeight")?);
+                    base = Some(number("base")?);
+                }
+                "char" => {
+                    // Only the single page we were given is supported.
+                    if number("page").unwrap_or(0.0) != 0.0 {
+                        continue;
+                    }
+
+                    let character = match char::from_u32(number("id")? as u32) {
+                        Some(character) => character,
+                        None => continue,
+                    };
+                    let x = number("x")?;
+                    let y = number("y")?;
+                    let glyph_width = number("width")?;
+                    let glyph_height = number("height")?;
+
+                    characters.insert(
+                        character,
+                        BitmapCharacter {
+                            tex_coords: Rect::new(
+                                x / width,
+                                y / height,
+                                glyph_width / width,
+                                glyph_height / height,
+                            ),
+                            width: glyph_width,
+                            height: glyph_height,
+                            x_offset: number("xoffset")?,
+                            y_offset: number("yoffset")?,
+                            x_advance: number("xadvance")?,
+                        },
+                    );
+                }
+                "kerning" => {
+                    let first = char::from_u32(number("first")? as u32);
+                    let second = char::from_u32(number("second")? as u32);
+                    if let (Some(first), Some(second)) = (first, second) {
+                        kernings.insert((first, second), number("amount")?);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let line_height = line_height.ok_or_else(invalid)?;
+        let base = base.ok_or_else(invalid)?;
+        let size = size.filter(|size| *size > 0.0).unwrap_or(line_height);
+
+        Ok(Self {
+            size,
+            line_height,
+            base,
+            characters,
+            kernings,
+        })
+    }
+}
+
+/// A font pre-rendered into an atlas, described by an AngelCode BMFont `.fnt` text file.
+pub(crate) struct BitmapFont {
+    size: f32,
+    line_height: f32,
+    base: f32,
+    pub(crate) texture: Shared<Texture>,
+    characters: HashMap<char, BitmapCharacter>,
+    kernings: HashMap<(char, char), f32>,
+}
+
+impl BitmapFont {
+    pub(crate) fn load<P, I>(
+        device: &Device,
+        queue: &Queue,
+        texture_context: &TextureContext,
+        fnt_path: P,
+        page_image_path: I,
+    ) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+        I: AsRef<Path>,
+    {
+        let descriptor = fs::read_to_string(&fnt_path)
+            .map_err(|_e| Error::LoadingFailed(fnt_path.as_ref().into()))?;
+
+        let image = image::open(&page_image_path)
+            .map_err(|_e| Error::LoadingFailed(page_image_path.as_ref().into()))?;
+        let rgba = image.to_rgba8();
+        let dimensions = rgba.dimensions();
+        let descriptor = FontDescriptor::parse(&descriptor, dimensions, fnt_path.as_ref())?;
+        let texture =
+            Shared::new(texture_context.create_texture(device, queue, &rgba, dimensions.into())?);
+
+        Ok(Self {
+            size: descriptor.size,
+            line_height: descriptor.line_height,
+            base: descriptor.base,
+            texture,
+            characters: descriptor.characters,
+            kernings: descriptor.kernings,
+        })
+    }
+
+    pub(crate) fn measure(&self, character: char, px: u32) -> (f32, f32) {
+        let scale = self.scale(px);
+        self.characters
+            .get(&character)
+            .map(|character| (character.width * scale, character.height * scale))
+            .unwrap_or((0.0, 0.0))
+    }
+
+    pub(crate) fn ascent(&self, px: u32) -> f32 {
+        self.base * self.scale(px)
+    }
+
+    pub(crate) fn has_character(&self, character: char) -> bool {
+        self.characters.contains_key(&character)
+    }
+
+    pub(crate) fn advance(&self, previous: Option<char>, character: char, px: u32) -> f32 {
+        let glyph = match self.characters.get(&character) {
+            Some(glyph) => glyph,
+            None => return 0.0,
+        };
+        let kerning = previous
+            .and_then(|previous| self.kernings.get(&(previous, character)))
+            .unwrap_or(&0.0);
+        (glyph.x_advance + kerning) * self.scale(px)
+    }
+
+    pub(crate) fn line_height(&self, px: u32) -> f32 {
+        self.line_height * self.scale(px)
+    }
+
+    pub(crate) fn descent(&self, px: u32) -> f32 {
+        (self.line_height - self.base) * self.scale(px)
+    }
+
+    /// Creates the operations drawing the text, along with the extent of each line.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn render_operations(
+        &self,
+        text: &str,
+        px: u32,
+        color: Color,
+        position: Position,
+        transforms: Transform,
+        snap_to_pixel: bool,
+        options: &TextOptions,
+    ) -> (Vec<RenderOperation>, Vec<TextLine>) {
+        self.render_spans(
+            &[(text, color, px)],
+            position,
+            None,
+            transforms,
+            snap_to_pixel,
+            options,
+        )
+    }
+
+    /// Lays out the spans one after the other. Lines longer than `max_width` wrap at any
+    /// character, as there is no word information here.
+    pub(crate) fn render_spans(
+        &self,
+        spans: &[(&str, Color, u32)],
+        position: Position,
+        max_width: Option<f32>,
+        transforms: Transform,
+        snap_to_pixel: bool,
+        options: &TextOptions,
+    ) -> (Vec<RenderOperation>, Vec<TextLine>) {
+        let mut operations = vec![];
+        let mut x = 0.0;
+        let mut y = 0.0;
+        let mut previous: Option<char> = None;
+        let mut lines = vec![];
+        let mut line_right = 0.0f32;
+        let mut scale = 1.0;
+
+        for (text, color, px) in spans {
+            let text = expand_tabs(text, options.tab_width_spaces);
+            scale = self.scale(*px);
+            // The atlas holds the final colors of the glyphs, usually white, so we tint them.
+            let color_matrix = ColorMatrix::from_color(*color);
+            let line_advance = self.line_height * scale * options.line_height_multiplier;
+
+            for character in text.chars() {
+                if character == '\n' {
+                    push_line(&mut lines, position, line_right, y + self.base * scale);
+                    line_right = 0.0;
+                    x = 0.0;
+                    y += line_advance;
+                    previous = None;
+                    continue;
+                }
+
+                let glyph = match self.characters.get(&character) {
+                    Some(glyph) => glyph,
+                    None => {
+                        previous = None;
+                        continue;
+                    }
+                };
+
+                if let Some(previous) = previous {
+                    x += self.kernings.get(&(previous, character)).unwrap_or(&0.0) * scale;
+                }
+
+                if let Some(max_width) = max_width {
+                    if x > 0.0 && x + glyph.x_advance * scale > max_width {
+                        push_line(&mut lines, position, line_right, y + self.base * scale);
+                        line_right = 0.0;
+                        x = 0.0;
+                        y += line_advance;
+                    }
+                }
+
+                if glyph.width > 0.0 && glyph.height > 0.0 {
+                    let rect = Rect::new(0.0, 0.0, glyph.width * scale, glyph.height * scale);
+                    let mut left = position.left + x + glyph.x_offset * scale;
+                    let mut top = position.top + y + glyph.y_offset * scale;
+                    if snap_to_pixel {
+                        left = left.round();
+                        top = top.round();
+                    }
+                    let translation = Transform::from_translation(left, top);
+                    operations.push(RenderOperation::new(
+                        rect,
+                        glyph.tex_coords,
+                        color_matrix,
+                        transforms * translation,
+                    ));
+                    line_right = line_right.max(left - position.left + rect.width);
+                }
+
+                x += glyph.x_advance * scale;
+                previous = Some(character);
+            }
+        }
+
+        push_line(&mut lines, position, line_right, y + self.base * scale);
+
+        (operations, lines)
+    }
+
+    fn scale(&self, px: u32) -> f32 {
+        px as f32 / self.size
+    }
+}
+
+fn push_line(lines: &mut Vec<TextLine>, position: Position, width: f32, baseline: f32) {
+    if width > 0.0 {
+        lines.push(TextLine {
+            left: position.left,
+            right: position.left + width,
+            baseline: position.top + baseline,
+        });
+    }
+}
+
+/// Splits a line like `char id=32 x=0 face="Some font"` into its tag and its attributes.
+fn parse_line(line: &str) -> (&str, HashMap<&str, &str>) {
+    let line = line.trim();
+    let (tag, mut rest) = line.split_once(' ').unwrap_or((line, ""));
+
+    let mut attributes = HashMap::new();
+    loop {
+        rest = rest.trim_start();
+        let (key, after_key) = match rest.split_once('=') {
+            Some(split) => split,
+            None => break,
+        };
+
+        let (value, after_value) = if let Some(quoted) = after_key.strip_prefix('"') {
+            quoted.split_once('"').unwrap_or((quoted, ""))
+        } else {
+            after_key.split_once(' ').unwrap_or((after_key, ""))
+        };
+
+        attributes.insert(key.trim(), value);
+        rest = after_value;
+    }
+
+    (tag, attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTOR: &str = r#"info face="Pixel Font" size=-16 bold=0
+common lineHeight=18 base=14 scaleW=128 scaleH=64 pages=2
+page id=0 file="pixel font_0.png"
+page id=1 file="pixel font_1.png"
+chars count=3
+char id=65   x=0     y=0     width=8     height=12    xoffset=0     yoffset=2     xadvance=9     page=0  chnl=15
+char id=86   x=8     y=0     width=8     height=12    xoffset=0     yoffset=2     xadvance=9     page=0  chnl=15
+char id=87   x=0     y=0     width=10    height=12    xoffset=0     yoffset=2     xadvance=11    page=1  chnl=15
+kernings count=1
+kerning first=65 second=86 amount=-2
+"#;
+
+    fn parse(descriptor: &str) -> Result<FontDescriptor, Error> {
+        FontDescriptor::parse(descriptor, (128, 64), Path::new("font.fnt"))
+    }
+
+    #[test]
+    fn quoted_values_keep_their_spaces() {
+        let (tag, attributes) = parse_line(r#"info face="Pixel Font" size=-16 bold=0"#);
+
+        assert_eq!(tag, "info");
+        assert_eq!(attributes["face"], "Pixel Font");
+        assert_eq!(attributes["size"], "-16");
+        assert_eq!(attributes["bold"], "0");
+    }
+
+    #[test]
+    fn pages_are_parsed_with_their_ids() {
+        let (tag, attributes) = parse_line(r#"page id=1 file="pixel font_1.png""#);
+
+        assert_eq!(tag, "page");
+        assert_eq!(attributes["id"], "1");
+        assert_eq!(attributes["file"], "pixel font_1.png");
+    }
+
+    #[test]
+    fn kerning_lines_are_parsed() {
+        let (tag, attributes) = parse_line("kerning first=65 second=86 amount=-2");
+
+        assert_eq!(tag, "kerning");
+        assert_eq!(attributes["first"], "65");
+        assert_eq!(attributes["second"], "86");
+        assert_eq!(attributes["amount"], "-2");
+    }
+
+    #[test]
+    fn descriptor_keeps_the_characters_of_the_first_page_and_the_kernings() {
+        let descriptor = parse(DESCRIPTOR).unwrap();
+
+        assert_eq!(descriptor.size, 16.0);
+        assert_eq!(descriptor.line_height, 18.0);
+        assert_eq!(descriptor.base, 14.0);
+        assert!(descriptor.characters.contains_key(&'A'));
+        assert!(descriptor.characters.contains_key(&'V'));
+        assert!(!descriptor.characters.contains_key(&'W'));
+        assert_eq!(
+            descriptor.characters[&'V'].tex_coords,
+            Rect::new(8.0 / 128.0, 0.0, 8.0 / 128.0, 12.0 / 64.0)
+        );
+        assert_eq!(descriptor.kernings[&('A', 'V')], -2.0);
+    }
+
+    #[test]
+    fn malformed_lines_fail_to_load() {
+        for malformed in [
+            "char id=65 x=zero y=0 width=8 height=12 xoffset=0 yoffset=2 xadvance=9",
+            "char id=65 x=0 y=0 width=8 height=12",
+            "kerning first=65 second=86",
+            "common lineHeight=eighteen base=14",
+        ] {
+            let descriptor = format!("common lineHeight=18 base=14\n{malformed}");
+
+            assert!(
+                matches!(parse(&descriptor), Err(Error::LoadingFailed(path)) if path == Path::new("font.fnt")),
+                "{malformed} didn't fail"
+            );
+        }
+    }
+
+    #[test]
+    fn descriptor_without_common_line_fails_to_load() {
+        assert!(matches!(
+            parse("info size=16"),
+            Err(Error::LoadingFailed(_))
+        ));
+    }
+}
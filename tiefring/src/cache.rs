@@ -1,20 +1,34 @@
-use std::{collections::BTreeMap, mem::size_of};
+use std::collections::{BTreeMap, VecDeque};
 
 use wgpu::{util::DeviceExt, Buffer, BufferSlice, BufferUsages, Device, Queue};
 
+use crate::BufferStrategy;
+
 #[derive(Debug)]
 pub(crate) struct BufferCache {
-    vertex_map: BTreeMap<u64, Vec<ReusableBuffer>>,
-    index_map: BTreeMap<u64, Vec<ReusableBuffer>>,
+    strategy: BufferStrategy,
+    /// How many frames a pooled buffer that wasn't reused is kept, at least 1.
+    pool_frames: u32,
+    vertex_map: Pool<ReusableBuffer>,
+    index_map: Pool<ReusableBuffer>,
+    growable_buffers: VecDeque<ReusableBuffer>,
+    /// Where instances are written before being uploaded, kept from one block to the next so
+    /// that preparing a block doesn't allocate.
+    staging: Vec<u8>,
 }
 
 impl BufferCache {
-    pub fn new() -> Self {
-        let vertex_map = BTreeMap::new();
-        let index_map = BTreeMap::new();
+    pub fn new(strategy: BufferStrategy, pool_frames: u32) -> Self {
+        let vertex_map = Pool::new();
+        let index_map = Pool::new();
+        let growable_buffers = VecDeque::new();
         Self {
+            strategy,
+            pool_frames: pool_frames.max(1),
             vertex_map,
             index_map,
+            growable_buffers,
+            staging: Vec::new(),
         }
     }
 
@@ -25,7 +39,11 @@ impl BufferCache {
         content: &[u8],
         usage: BufferUsages,
     ) -> ReusableBuffer {
-        let capacity = (size_of::<u8>() * content.len()) as u64;
+        let capacity = content.len() as u64;
+
+        if self.strategy == BufferStrategy::Growable {
+            return self.get_growable_buffer(device, queue, content, usage);
+        }
 
         let buffer = self.buffer_with_capacity(capacity, usage);
 
@@ -37,25 +55,85 @@ impl BufferCache {
         }
     }
 
+    /// Like [`BufferCache::get_buffer`], for a vertex buffer holding the instances.
+    pub fn get_instance_buffer<T, I>(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        instances: I,
+    ) -> ReusableBuffer
+    where
+        T: bytemuck::Pod,
+        I: IntoIterator<Item = T>,
+    {
+        let mut staging = std::mem::take(&mut self.staging);
+        staging.clear();
+        for instance in instances {
+            staging.extend_from_slice(bytemuck::bytes_of(&instance));
+        }
+
+        let buffer = self.get_buffer(device, queue, &staging, BufferUsages::VERTEX);
+        self.staging = staging;
+        buffer
+    }
+
     pub fn release_buffer(&mut self, buffer: ReusableBuffer) {
         if (buffer.usage & BufferUsages::COPY_DST).is_empty() {
             return;
         }
 
+        if self.strategy == BufferStrategy::Growable {
+            // Released in draw order, so that each draw gets the same buffer next frame.
+            self.growable_buffers.push_back(buffer);
+            return;
+        }
+
         let map = match buffer.usage {
             usage if usage & BufferUsages::VERTEX == BufferUsages::VERTEX => &mut self.vertex_map,
             usage if usage & BufferUsages::INDEX == BufferUsages::INDEX => &mut self.index_map,
             _ => return,
         };
 
-        map.entry(buffer.max_size)
-            .or_insert_with(Vec::new)
-            .push(buffer);
+        map.insert(buffer.max_size, buffer);
     }
 
     pub fn clear(&mut self) {
         self.vertex_map.clear();
         self.index_map.clear();
+        self.growable_buffers.clear();
+    }
+
+    /// Called at the end of each frame, drops the pooled buffers that haven't been reused for
+    /// `pool_frames` frames. Growable buffers not reused this frame are dropped right away.
+    pub fn trim(&mut self) {
+        self.vertex_map.trim(self.pool_frames);
+        self.index_map.trim(self.pool_frames);
+        self.growable_buffers.clear();
+    }
+
+    fn get_growable_buffer(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        content: &[u8],
+        usage: BufferUsages,
+    ) -> ReusableBuffer {
+        let capacity = content.len() as u64;
+
+        match self.growable_buffers.pop_front() {
+            Some(mut buffer) if buffer.max_size >= capacity && buffer.usage.contains(usage) => {
+                buffer.update(queue, content);
+                buffer
+            }
+            previous => {
+                let previous_size = previous.map(|buffer| buffer.max_size).unwrap_or(0);
+                let max_size = capacity.max(previous_size * 2);
+                let mut buffer =
+                    ReusableBuffer::with_capacity(device, max_size, usage | BufferUsages::COPY_DST);
+                buffer.update(queue, content);
+                buffer
+            }
+        }
     }
 
     fn buffer_with_capacity(
@@ -69,20 +147,51 @@ impl BufferCache {
             _ => return None,
         };
 
-        let key = map.range(capacity..).next().map(|(key, _)| key).copied();
+        map.take(capacity)
+    }
+}
 
-        let buffer = if let Some(key) = key {
-            let buffers = map.get_mut(&key).expect("We searched for the key");
-            let buffer = buffers.pop();
-            if buffers.is_empty() {
-                map.remove(&key);
-            }
-            buffer
-        } else {
-            None
-        };
+/// Released buffers by size, each with the frames it has gone unused.
+#[derive(Debug)]
+struct Pool<T> {
+    entries: BTreeMap<u64, Vec<(T, u32)>>,
+}
 
-        buffer
+impl<T> Pool<T> {
+    fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn insert(&mut self, size: u64, item: T) {
+        self.entries.entry(size).or_default().push((item, 0));
+    }
+
+    /// The smallest item of at least `capacity`.
+    fn take(&mut self, capacity: u64) -> Option<T> {
+        let key = *self.entries.range(capacity..).next()?.0;
+        let items = self.entries.get_mut(&key).expect("We searched for the key");
+        let item = items.pop().map(|(item, _)| item);
+        if items.is_empty() {
+            self.entries.remove(&key);
+        }
+        item
+    }
+
+    /// Ends a frame, dropping the items that went unused for `pool_frames` frames.
+    fn trim(&mut self, pool_frames: u32) {
+        self.entries.retain(|_, items| {
+            items.retain_mut(|(_, idle_frames)| {
+                *idle_frames += 1;
+                *idle_frames < pool_frames
+            });
+            !items.is_empty()
+        });
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
     }
 }
 
@@ -96,7 +205,7 @@ pub(crate) struct ReusableBuffer {
 
 impl ReusableBuffer {
     pub fn new(device: &Device, content: &[u8], usage: BufferUsages) -> Self {
-        let current_size = (size_of::<u8>() * content.len()) as u64;
+        let current_size = content.len() as u64;
 
         let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Vertex Buffer"),
@@ -112,14 +221,89 @@ impl ReusableBuffer {
         }
     }
 
-    pub fn slice(&self) -> BufferSlice {
+    pub fn with_capacity(device: &Device, max_size: u64, usage: BufferUsages) -> Self {
+        // Buffer sizes must be a multiple of wgpu::COPY_BUFFER_ALIGNMENT.
+        let max_size = wgpu::util::align_to(max_size, wgpu::COPY_BUFFER_ALIGNMENT);
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Vertex Buffer"),
+            size: max_size,
+            usage,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            buffer,
+            usage,
+            max_size,
+            current_size: 0,
+        }
+    }
+
+    pub fn slice(&self) -> BufferSlice<'_> {
         self.buffer.slice(..self.current_size)
     }
 
     pub fn update(&mut self, queue: &Queue, content: &[u8]) {
-        let current_size = (size_of::<u8>() * content.len()) as u64;
+        let current_size = content.len() as u64;
         queue.write_buffer(&self.buffer, 0, content);
 
         self.current_size = current_size;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Pool;
+
+    /// Runs frames drawing buffers of the given sizes, like the canvas does: the buffers of the
+    /// previous frame are released, the new ones taken, then the pool is trimmed. Returns how
+    /// many buffers had to be allocated in each frame.
+    fn allocations(frames: &[&[u64]], pool_frames: u32) -> Vec<usize> {
+        let mut pool = Pool::new();
+        let mut in_use: Vec<u64> = vec![];
+        frames
+            .iter()
+            .map(|sizes| {
+                for size in in_use.drain(..) {
+                    pool.insert(size, size);
+                }
+                let mut allocated = 0;
+                for &size in sizes.iter() {
+                    let buffer = pool.take(size).unwrap_or_else(|| {
+                        allocated += 1;
+                        size
+                    });
+                    in_use.push(buffer);
+                }
+                pool.trim(pool_frames);
+                allocated
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_steady_workload_stops_allocating_after_warmup() {
+        let frames: [&[u64]; 4] = [&[64, 128], &[64, 128], &[64, 128], &[64, 128]];
+
+        assert_eq!(allocations(&frames, 1), [2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn buffers_are_kept_for_pool_frames_frames() {
+        // The big buffer is needed every third frame.
+        let frames: [&[u64]; 7] = [&[256], &[], &[], &[256], &[], &[], &[256]];
+
+        assert_eq!(allocations(&frames, 1), [1, 0, 0, 1, 0, 0, 1]);
+        assert_eq!(allocations(&frames, 3), [1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn clear_drops_everything() {
+        let mut pool = Pool::new();
+        pool.insert(64, ());
+        pool.clear();
+
+        assert!(pool.take(0).is_none());
+    }
+}
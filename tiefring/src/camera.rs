@@ -1,7 +1,9 @@
+use std::{rc::Rc, time::Duration};
+
 use glam::{Mat4, Vec3};
-use wgpu::{util::DeviceExt, BindGroup, BindGroupLayout, Buffer, Device};
+use wgpu::{util::DeviceExt, BindGroup, BindGroupLayout, Buffer, Device, Queue};
 
-use crate::Position;
+use crate::{Position, Rect, SizeInPx};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
@@ -11,9 +13,55 @@ struct CameraUniform {
 
 impl CameraUniform {}
 
+/// How the scale of a camera is chosen.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ScaleMode {
+    /// Uses the scale as set.
+    #[default]
+    Free,
+    /// Uses the largest integer scale, at least 1, at which the given size fits in the window,
+    /// and centers it. Fractional scales make pixel art shimmer.
+    IntegerNearest(SizeInPx),
+    /// Scales the given size as much as possible while keeping its aspect ratio, and centers it.
+    Fit(SizeInPx),
+}
+
+/// How far from 0 the z of operations can go, see [`crate::RenderOperation::with_z`].
+pub(crate) const DEPTH_RANGE: f32 = 100.0;
+
+/// How many times per second the shake changes direction.
+const SHAKE_FREQUENCY: f32 = 20.0;
+
+/// A trauma based screen shake: the offset grows with the square of the trauma, which decays
+/// over time, so that small hits barely move the camera and big ones settle down smoothly.
+#[derive(Debug, Clone, Copy)]
+struct Shake {
+    trauma: f32,
+    /// The trauma lost per second.
+    decay: f32,
+    /// The offset at full trauma, in world units.
+    max_offset: f32,
+    time: f32,
+    offset: Position,
+}
+
+impl Default for Shake {
+    fn default() -> Self {
+        Self {
+            trauma: 0.0,
+            decay: 1.0,
+            max_offset: 8.0,
+            time: 0.0,
+            offset: Position::new(0.0, 0.0),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct CameraSettings {
     pub(crate) scale: f32,
+    pub(crate) scale_mode: ScaleMode,
+    pub(crate) scale_factor: f32,
     pub(crate) translation: Position,
     pub(crate) width: u32,
     pub(crate) height: u32,
@@ -24,14 +72,66 @@ pub struct Camera {
     pub(crate) camera_settings: CameraSettings,
     pub(crate) camera_buffer: Buffer,
     pub(crate) camera_bind_group_layout: BindGroupLayout,
-    pub(crate) camera_bind_group: BindGroup,
+    pub(crate) camera_bind_group: Rc<BindGroup>,
     pub(crate) dirty: bool,
+    shake: Shake,
+}
+
+impl CameraSettings {
+    /// Returns whether the size changed. A zero size, as sent for minimized windows, is ignored,
+    /// as the projection would divide by it.
+    fn set_size(&mut self, width: u32, height: u32) -> bool {
+        if width == 0 || height == 0 || (self.width == width && self.height == height) {
+            return false;
+        }
+        self.width = width;
+        self.height = height;
+        true
+    }
+
+    fn viewport(&self) -> Option<(u32, u32, u32, u32)> {
+        let target = match self.scale_mode {
+            ScaleMode::Free => return None,
+            ScaleMode::IntegerNearest(target) | ScaleMode::Fit(target) => target,
+        };
+        let (scale, offset) = Camera::scale_and_offset(self);
+        let (left, top) = (offset.left as u32, offset.top as u32);
+
+        Some((
+            left,
+            top,
+            ((target.width as f32 * scale).round() as u32).min(self.width - left),
+            ((target.height as f32 * scale).round() as u32).min(self.height - top),
+        ))
+    }
+
+    fn screen_to_world(&self, screen: Position) -> Position {
+        let (scale, offset) = Camera::scale_and_offset(self);
+
+        Position::new(
+            (screen.left - offset.left) / scale - self.translation.left,
+            (screen.top - offset.top) / scale - self.translation.top,
+        )
+    }
+
+    fn visible_rect(&self) -> Rect {
+        let (x, y, width, height) = self.viewport().unwrap_or((0, 0, self.width, self.height));
+        let top_left = self.screen_to_world(Position::new(x as f32, y as f32));
+        let (scale, _) = Camera::scale_and_offset(self);
+
+        Rect::new(
+            top_left.left,
+            top_left.top,
+            width as f32 / scale,
+            height as f32 / scale,
+        )
+    }
 }
 
 impl Camera {
     pub(crate) fn new(device: &Device, camera_settings: CameraSettings) -> Self {
         let camera_uniform = CameraUniform {
-            matrix: Camera::matrix(&camera_settings),
+            matrix: Camera::matrix(&camera_settings, Position::new(0.0, 0.0)),
         };
 
         let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -40,20 +140,7 @@ impl Camera {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let camera_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-                label: Some("camera_bind_group_layout"),
-            });
+        let camera_bind_group_layout = bind_group_layout(device);
         let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &camera_bind_group_layout,
             entries: &[wgpu::BindGroupEntry {
@@ -67,30 +154,113 @@ impl Camera {
             camera_settings,
             camera_buffer,
             camera_bind_group_layout,
-            camera_bind_group,
+            camera_bind_group: Rc::new(camera_bind_group),
             dirty: true,
+            shake: Shake::default(),
         }
     }
 
-    pub(crate) fn set_scale(&mut self, scale: f32) {
+    pub fn scale(&self) -> f32 {
+        self.camera_settings.scale
+    }
+
+    pub fn set_scale(&mut self, scale: f32) {
         self.camera_settings.scale = scale;
         self.dirty = true;
     }
 
     pub(crate) fn set_size(&mut self, width: u32, height: u32) {
-        self.camera_settings.width = width;
-        self.camera_settings.height = height;
+        if self.camera_settings.set_size(width, height) {
+            self.dirty = true;
+        }
+    }
+
+    pub(crate) fn set_scale_factor(&mut self, scale_factor: f32) {
+        self.camera_settings.scale_factor = scale_factor;
+        self.dirty = true;
+    }
+
+    pub(crate) fn set_scale_mode(&mut self, scale_mode: ScaleMode) {
+        self.camera_settings.scale_mode = scale_mode;
         self.dirty = true;
     }
 
-    pub(crate) fn set_translation(&mut self, translation: Position) {
+    /// The scale actually used, which depends on the [`ScaleMode`].
+    pub fn effective_scale(&self) -> f32 {
+        Camera::scale_and_offset(&self.camera_settings).0
+    }
+
+    /// The part of the window drawn to, as `(x, y, width, height)`, when letterboxed.
+    pub(crate) fn viewport(&self) -> Option<(u32, u32, u32, u32)> {
+        self.camera_settings.viewport()
+    }
+
+    /// Converts a position in the window, like the mouse cursor, to a position in the world drawn
+    /// with this camera, accounting for letterboxing.
+    pub fn screen_to_world(&self, screen: Position) -> Position {
+        self.camera_settings.screen_to_world(screen)
+    }
+
+    /// The part of the world drawn with this camera, between the letterbox bars if any. Anything
+    /// outside of it can be skipped.
+    pub fn visible_rect(&self) -> Rect {
+        self.camera_settings.visible_rect()
+    }
+
+    pub fn translation(&self) -> Position {
+        self.camera_settings.translation
+    }
+
+    pub fn set_translation(&mut self, translation: Position) {
         self.camera_settings.translation = translation;
         self.dirty = true;
     }
 
+    /// Adds `trauma` to the shake, then advances it by `dt` seconds. Called every frame, even with
+    /// no trauma to add, it shakes the camera around its translation and settles back as the
+    /// trauma decays. The translation itself is left alone.
+    pub fn apply_shake(&mut self, trauma: f32, dt: Duration) {
+        let shake = &mut self.shake;
+        shake.trauma = (shake.trauma + trauma).clamp(0.0, 1.0);
+        shake.time += dt.as_secs_f32();
+
+        let offset = if shake.trauma > 0.0 {
+            let amount = shake.max_offset * shake.trauma * shake.trauma;
+            let t = shake.time * SHAKE_FREQUENCY;
+            Position::new(amount * noise(0, t), amount * noise(1, t))
+        } else {
+            Position::new(0.0, 0.0)
+        };
+        shake.trauma = (shake.trauma - shake.decay * dt.as_secs_f32()).max(0.0);
+
+        if offset != shake.offset {
+            shake.offset = offset;
+            self.dirty = true;
+        }
+    }
+
+    pub fn trauma(&self) -> f32 {
+        self.shake.trauma
+    }
+
+    /// Sets the trauma directly, between 0 and 1.
+    pub fn set_trauma(&mut self, trauma: f32) {
+        self.shake.trauma = trauma.clamp(0.0, 1.0);
+    }
+
+    /// How much trauma is lost per second, 1 by default.
+    pub fn set_trauma_decay(&mut self, decay: f32) {
+        self.shake.decay = decay.max(0.0);
+    }
+
+    /// The offset at full trauma, in world units, 8 by default.
+    pub fn set_max_shake(&mut self, max_offset: f32) {
+        self.shake.max_offset = max_offset;
+    }
+
     pub(crate) fn recalculate(&mut self, queue: &wgpu::Queue) {
         let camera_uniform = CameraUniform {
-            matrix: Camera::matrix(&self.camera_settings),
+            matrix: Camera::matrix(&self.camera_settings, self.shake.offset),
         };
 
         queue.write_buffer(
@@ -101,14 +271,69 @@ impl Camera {
         self.dirty = false;
     }
 
-    fn matrix(camera_settings: &CameraSettings) -> [f32; 16] {
+    /// A bind group with its own copy of the current matrix, taken from `snapshots`. Buffer
+    /// writes all land before the frame is drawn, so a camera drawn with several times in a
+    /// frame, with different sizes or translations, needs one for each time after the first.
+    pub(crate) fn snapshot(
+        &self,
+        snapshots: &mut CameraSnapshots,
+        device: &Device,
+        queue: &Queue,
+    ) -> Rc<BindGroup> {
+        snapshots.take(
+            device,
+            queue,
+            Camera::matrix(&self.camera_settings, self.shake.offset),
+        )
+    }
+
+    fn matrix(camera_settings: &CameraSettings, shake: Position) -> [f32; 16] {
+        let (scale, offset) = Camera::scale_and_offset(camera_settings);
+        let translation = camera_settings.translation;
         (Camera::projection_matrix(camera_settings.width, camera_settings.height)
-            * Camera::view_matrix(camera_settings.scale, camera_settings.translation))
+            * Mat4::from_translation(Vec3::new(offset.left, offset.top, 0.0))
+            * Camera::view_matrix(
+                scale,
+                Position::new(translation.left + shake.left, translation.top + shake.top),
+            ))
         .to_cols_array()
     }
 
+    fn scale_and_offset(camera_settings: &CameraSettings) -> (f32, Position) {
+        let (scale, target) = match camera_settings.scale_mode {
+            ScaleMode::Free => {
+                let scale = camera_settings.scale * camera_settings.scale_factor;
+                return (scale, Position::new(0.0, 0.0));
+            }
+            ScaleMode::IntegerNearest(target) => {
+                let horizontal = camera_settings.width / target.width.max(1);
+                let vertical = camera_settings.height / target.height.max(1);
+                (horizontal.min(vertical).max(1) as f32, target)
+            }
+            ScaleMode::Fit(target) => {
+                let horizontal = camera_settings.width as f32 / target.width.max(1) as f32;
+                let vertical = camera_settings.height as f32 / target.height.max(1) as f32;
+                (horizontal.min(vertical), target)
+            }
+        };
+
+        // Whole pixels, so that the letterbox bars don't cut through pixels.
+        let offset = Position::new(
+            ((camera_settings.width as f32 - target.width as f32 * scale).max(0.0) / 2.0).floor(),
+            ((camera_settings.height as f32 - target.height as f32 * scale).max(0.0) / 2.0).floor(),
+        );
+        (scale, offset)
+    }
+
     fn projection_matrix(width: u32, height: u32) -> Mat4 {
-        Mat4::orthographic_rh(0.0, width as f32, height as f32, 0.0, -100.0, 100.0)
+        Mat4::orthographic_rh(
+            0.0,
+            width as f32,
+            height as f32,
+            0.0,
+            -DEPTH_RANGE,
+            DEPTH_RANGE,
+        )
     }
 
     fn view_matrix(scale: f32, translate: Position) -> Mat4 {
@@ -116,3 +341,200 @@ impl Camera {
             * Mat4::from_translation(Vec3::new(translate.left, translate.top, 0.0))
     }
 }
+
+/// Smooth noise between -1 and 1, interpolated between random values at each integer `t`.
+fn noise(seed: u32, t: f32) -> f32 {
+    let step = t.floor();
+    let fraction = t - step;
+    let fraction = fraction * fraction * (3.0 - 2.0 * fraction);
+
+    let from = hash(seed, step as i32);
+    let to = hash(seed, step as i32 + 1);
+    from + (to - from) * fraction
+}
+
+/// A random value between -1 and 1 for each `x`.
+fn hash(seed: u32, x: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(0x9e37_79b9) ^ seed.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+    h as f32 / u32::MAX as f32 * 2.0 - 1.0
+}
+
+fn bind_group_layout(device: &Device) -> BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }],
+        label: Some("camera_bind_group_layout"),
+    })
+}
+
+/// The uniform buffers and bind groups of the camera snapshots of a frame, see
+/// [`Camera::snapshot`]. They are reused from one frame to the next, the way [`BufferCache`]
+/// reuses instance buffers, so that drawing with a camera several times a frame doesn't create
+/// new ones every frame.
+///
+/// [`BufferCache`]: crate::cache::BufferCache
+pub(crate) struct CameraSnapshots {
+    bind_group_layout: BindGroupLayout,
+    snapshots: Vec<(Buffer, Rc<BindGroup>)>,
+    /// How many snapshots were taken this frame.
+    used: usize,
+}
+
+impl CameraSnapshots {
+    pub(crate) fn new(device: &Device) -> Self {
+        Self {
+            bind_group_layout: bind_group_layout(device),
+            snapshots: vec![],
+            used: 0,
+        }
+    }
+
+    /// A bind group holding `matrix` until the end of the frame.
+    fn take(&mut self, device: &Device, queue: &Queue, matrix: [f32; 16]) -> Rc<BindGroup> {
+        if self.used == self.snapshots.len() {
+            let camera_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Projection matrix buffer"),
+                size: std::mem::size_of::<CameraUniform>() as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                }],
+                label: Some("camera_bind_group"),
+            });
+            self.snapshots
+                .push((camera_buffer, Rc::new(camera_bind_group)));
+        }
+
+        let (camera_buffer, camera_bind_group) = &self.snapshots[self.used];
+        self.used += 1;
+        queue.write_buffer(
+            camera_buffer,
+            0,
+            bytemuck::cast_slice(&[CameraUniform { matrix }]),
+        );
+        camera_bind_group.clone()
+    }
+
+    /// Starts a frame, the snapshots of the previous one being free again.
+    pub(crate) fn reset(&mut self) {
+        self.used = 0;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.snapshots.clear();
+        self.used = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec4;
+
+    use super::*;
+
+    fn settings(width: u32, height: u32) -> CameraSettings {
+        CameraSettings {
+            scale: 1.0,
+            scale_mode: ScaleMode::Free,
+            scale_factor: 1.0,
+            translation: Position::new(0.0, 0.0),
+            width,
+            height,
+        }
+    }
+
+    /// Where the world position ends up on screen, in pixels, through the camera matrix.
+    fn world_to_screen(camera_settings: &CameraSettings, world: Position) -> Position {
+        let matrix =
+            Mat4::from_cols_array(&Camera::matrix(camera_settings, Position::new(0.0, 0.0)));
+        let clip = matrix * Vec4::new(world.left, world.top, 0.0, 1.0);
+        Position::new(
+            (clip.x + 1.0) / 2.0 * camera_settings.width as f32,
+            (1.0 - clip.y) / 2.0 * camera_settings.height as f32,
+        )
+    }
+
+    fn assert_close(actual: Position, expected: Position) {
+        assert!(
+            (actual.left - expected.left).abs() < 1e-3 && (actual.top - expected.top).abs() < 1e-3,
+            "{actual:?} isn't close to {expected:?}"
+        );
+    }
+
+    #[test]
+    fn screen_to_world_round_trips_with_a_translated_scaled_camera() {
+        let mut camera_settings = settings(800, 600);
+        camera_settings.scale = 2.0;
+        camera_settings.translation = Position::new(-100.0, 50.0);
+
+        let world = Position::new(130.0, -20.0);
+        let screen = world_to_screen(&camera_settings, world);
+        assert_close(screen, Position::new(60.0, 60.0));
+        assert_close(camera_settings.screen_to_world(screen), world);
+    }
+
+    #[test]
+    fn visible_rect_of_a_translated_scaled_camera() {
+        let mut camera_settings = settings(800, 600);
+        camera_settings.scale = 2.0;
+        camera_settings.translation = Position::new(-100.0, 50.0);
+
+        assert_eq!(
+            camera_settings.visible_rect(),
+            Rect::new(100.0, -50.0, 400.0, 300.0)
+        );
+    }
+
+    #[test]
+    fn integer_nearest_letterboxes_640_by_480_in_800_by_600() {
+        let mut camera_settings = settings(800, 600);
+        camera_settings.scale_mode = ScaleMode::IntegerNearest(SizeInPx {
+            width: 640,
+            height: 480,
+        });
+
+        let (scale, offset) = Camera::scale_and_offset(&camera_settings);
+        assert_eq!(scale, 1.0);
+        assert_eq!(offset, Position::new(80.0, 60.0));
+        assert_eq!(camera_settings.viewport(), Some((80, 60, 640, 480)));
+        assert_eq!(
+            camera_settings.visible_rect(),
+            Rect::new(0.0, 0.0, 640.0, 480.0)
+        );
+    }
+
+    #[test]
+    fn a_zero_size_is_ignored() {
+        let mut camera_settings = settings(800, 600);
+        camera_settings.scale_mode = ScaleMode::IntegerNearest(SizeInPx {
+            width: 640,
+            height: 480,
+        });
+
+        assert!(!camera_settings.set_size(0, 0));
+        assert_eq!((camera_settings.width, camera_settings.height), (800, 600));
+        assert!(Camera::matrix(&camera_settings, Position::new(0.0, 0.0))
+            .iter()
+            .all(|value| value.is_finite()));
+        let visible_rect = camera_settings.visible_rect();
+        assert!(visible_rect.width.is_finite() && visible_rect.height.is_finite());
+    }
+}
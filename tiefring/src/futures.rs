@@ -1,10 +1,17 @@
 use std::{
     future::Future,
-    sync::mpsc::{channel, Receiver},
+    path::PathBuf,
+    sync::mpsc::{channel, Receiver, TryRecvError},
     task::Poll,
 };
 
-use wgpu::{BufferAsyncError, BufferSlice, BufferView, Device};
+use image::{ColorType, RgbaImage};
+use wgpu::{BufferAsyncError, BufferSlice, BufferView, Device, Queue};
+
+use crate::{
+    sprite::{decode_in_background, Sprite, TextureContext},
+    Error,
+};
 
 #[must_use]
 pub(crate) struct AsyncBufferView<'a> {
@@ -48,3 +55,61 @@ impl<'a> Future for AsyncBufferView<'a> {
         }
     }
 }
+
+#[must_use]
+pub(crate) struct AsyncSprite<'a> {
+    device: &'a Device,
+    queue: &'a Queue,
+    texture_context: &'a TextureContext,
+    path: PathBuf,
+    receiver: Receiver<Result<(RgbaImage, ColorType), Error>>,
+}
+
+impl<'a> AsyncSprite<'a> {
+    pub fn new(
+        device: &'a Device,
+        queue: &'a Queue,
+        texture_context: &'a TextureContext,
+        path: PathBuf,
+    ) -> Self {
+        let receiver = decode_in_background(path.clone());
+
+        AsyncSprite {
+            device,
+            queue,
+            texture_context,
+            path,
+            receiver,
+        }
+    }
+}
+
+impl<'a> Future for AsyncSprite<'a> {
+    type Output = Result<Sprite, Error>;
+
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        match self.receiver.try_recv() {
+            Ok(decoded) => Poll::Ready(decoded.and_then(|(rgba, color_type)| {
+                let mut sprite = Sprite::load_data(
+                    self.device,
+                    self.queue,
+                    self.texture_context,
+                    &rgba,
+                    rgba.dimensions(),
+                )?;
+                sprite.source_color_type = Some(color_type);
+                Ok(sprite)
+            })),
+            Err(TryRecvError::Empty) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Err(TryRecvError::Disconnected) => {
+                Poll::Ready(Err(Error::LoadingFailed(self.path.clone())))
+            }
+        }
+    }
+}
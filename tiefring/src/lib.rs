@@ -1,35 +1,57 @@
 use std::{
+    borrow::Cow,
+    f32::consts::TAU,
     ops::{Mul, MulAssign},
     path::{Path, PathBuf},
     rc::Rc,
+    time::{Duration, Instant},
 };
 
 use futures::AsyncBufferView;
 use glam::{Affine2, Mat2, Vec2};
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
-use renderer::prepare_draw_data;
+use renderer::{prepare_draw_data, prepare_instances};
 use resources::Resources;
 use thiserror::Error;
-use wgpu::{BufferAsyncError, CommandEncoder, Device, Queue, RenderPass};
+use wgpu::{BindGroup, BufferAsyncError, CommandEncoder, Device, Queue, RenderPass};
 
 use crate::{
     cache::{BufferCache, ReusableBuffer},
-    camera::{Camera, CameraSettings},
-    renderer::{ColorMatrix, RenderOperation, Renderer},
-    sprite::{Sprite, Texture, TextureContext},
-    text::{Font, TextConverter},
+    camera::{Camera, CameraSettings, CameraSnapshots, ScaleMode},
+    recorder::{FrameSink, Recorder},
+    renderer::{
+        ColorMatrix, RenderOperation, RenderState, Renderer, DEPTH_FORMAT, INSTANCE_ALPHA_TEST,
+        INSTANCE_RING, INSTANCE_SMOOTH_X, INSTANCE_SMOOTH_Y, PICKING_FORMAT,
+    },
+    shared::Shared,
+    sprite::{ExternalTexture, Mask, SamplerKind, Sprite, Texture, TextureContext, TileSet},
+    text::{
+        expand_tabs, Font, FontKind, RichText, SpanLayout, TextConverter, TextLine, TextOptions,
+    },
 };
 
+mod bmfont;
 mod cache;
-mod camera;
+pub mod camera;
 mod futures;
+mod recorder;
 mod renderer;
 pub mod resources;
+mod shared;
 pub mod sprite;
 pub mod text;
 
+pub use renderer::{BlendMode, Facing};
+
 const DEFAULT_COLOR_MATRIX: ColorMatrix = ColorMatrix::from_color(Color::rgb(1.0, 1.0, 1.0));
 const OPERATION_CAPACITY: usize = 2048;
+/// The most dashes drawn for a single line, see [`LineStyle::Dashed`].
+const MAX_DASHES: usize = 4096;
+/// The size of the lines queued with [`Canvas::debug_text`].
+#[cfg(feature = "debug-text")]
+const DEBUG_TEXT_PX: u32 = 16;
+/// The height in pixels of the horizontal slices glyphs are cut in for gradients.
+const GRADIENT_BAND_HEIGHT: f32 = 2.0;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -42,11 +64,62 @@ pub enum Error {
     #[error("Loading failed")]
     LoadingFailed(PathBuf),
 
+    #[error("The format of {0:?} isn't supported")]
+    UnsupportedImageFormat(PathBuf),
+
     #[error("Loading failed")]
     IOError(std::io::Error),
 
+    #[error("Loading failed for {0:?}")]
+    DirectoryLoadingFailed(Vec<PathBuf>),
+
+    #[error("Decoding failed for {0:?}")]
+    DecodingFailed(Vec<String>),
+
+    #[error("The image couldn't be decoded")]
+    ImageDecodingFailed(image::ImageError),
+
+    #[error("The font couldn't be parsed")]
+    FontParsingFailed,
+
+    #[error("{0} is too large for an atlas page")]
+    TooLargeForAtlas(String),
+
+    #[error("The name {0} is used by more than one image")]
+    DuplicateName(String),
+
+    #[error("The data doesn't match the dimensions {0:?}")]
+    InvalidDimensions(SizeInPx),
+
+    #[error("No room for the glyph {0:?} in the atlas")]
+    GlyphAtlasFull(char),
+
+    #[error("The texture of {requested:?} is larger than the limit of {max} pixels")]
+    TextureTooLarge { requested: SizeInPx, max: u32 },
+
+    #[error("A texture array of {requested} layers is over the limit of {max}")]
+    TooManyArrayLayers { requested: u32, max: u32 },
+
+    #[error("Expected an image of {expected:?}, found one of {found:?}")]
+    DimensionsMismatch { expected: SizeInPx, found: SizeInPx },
+
     #[error("Couldn't take screenshot")]
     ScreenshotFailed,
+
+    #[error("Couldn't save the recorded frame {0:?}")]
+    RecordingFailed(PathBuf),
+
+    #[error("The pixel ({x}, {y}) is outside of the surface")]
+    PixelOutOfBounds { x: u32, y: u32 },
+
+    #[error("The canvas renders straight to the surface, without an intermediate target to read")]
+    NoIntermediateTarget,
+
+    #[error("The adapter doesn't support the features {0:?}")]
+    UnsupportedFeatures(wgpu::Features),
+
+    #[error("The adapter doesn't support the limit {0}")]
+    UnsupportedLimit(&'static str),
 }
 
 impl From<std::io::Error> for Error {
@@ -60,28 +133,44 @@ pub struct GraphicsRenderer {
     renderer: Renderer,
     buffer_cache: BufferCache,
     camera: Camera,
+    ui_camera: Camera,
+    camera_snapshots: CameraSnapshots,
     size: SizeInPx,
     texture_context: TextureContext,
     text_converter: TextConverter,
 }
 
 impl GraphicsRenderer {
-    pub fn new(device: &Device, queue: &Queue, width: u32, height: u32, scale: f32) -> Self {
+    pub fn new(
+        device: &Device,
+        queue: &Queue,
+        width: u32,
+        height: u32,
+        canvas_settings: &CanvasSettings,
+    ) -> Self {
         let draw_datas = vec![];
         let camera = Camera::new(
             device,
             CameraSettings {
-                scale,
+                scale: canvas_settings.scale,
+                scale_mode: canvas_settings.effective_scale_mode(),
+                scale_factor: 1.0,
                 translation: Position::new(0.0, 0.0),
                 width,
                 height,
             },
         );
+        // Same as the main camera, but never translated.
+        let ui_camera = Camera::new(device, camera.camera_settings);
+        let camera_snapshots = CameraSnapshots::new(device);
 
-        let texture_context = TextureContext::new(device, queue);
+        let texture_context = TextureContext::new(device, queue, canvas_settings);
 
-        let renderer = Renderer::new(device, &texture_context, &camera);
-        let buffer_cache = BufferCache::new();
+        let renderer = Renderer::new(device, &texture_context, &camera, canvas_settings);
+        let buffer_cache = BufferCache::new(
+            canvas_settings.buffer_strategy,
+            canvas_settings.buffer_pool_frames,
+        );
         let size = SizeInPx { width, height };
 
         let text_converter = TextConverter::new();
@@ -91,6 +180,8 @@ impl GraphicsRenderer {
             renderer,
             buffer_cache,
             camera,
+            ui_camera,
+            camera_snapshots,
             size,
             texture_context,
             text_converter,
@@ -105,6 +196,9 @@ impl GraphicsRenderer {
         if self.camera.dirty {
             self.camera.recalculate(queue);
         }
+        if self.ui_camera.dirty {
+            self.ui_camera.recalculate(queue);
+        }
 
         let mut graphics = Graphics::new(
             self.size,
@@ -114,32 +208,85 @@ impl GraphicsRenderer {
             &mut self.draw_datas,
             &mut self.buffer_cache,
             &mut self.text_converter,
+            &mut self.camera_snapshots,
+            &self.camera,
+            &self.ui_camera,
         );
 
         prepare_function(&mut graphics);
-        graphics.prepare_current_block();
+        graphics.prepare_all_blocks();
+        graphics.prepare_transparent_operations();
+        // The sort is stable, so that within a layer things are drawn in the order they were
+        // submitted.
+        self.draw_datas.sort_by_key(DrawData::draw_order);
 
         self.cleanup();
     }
 
     pub fn render<'rpass>(&'rpass mut self, render_pass: &mut RenderPass<'rpass>) {
-        render_pass.set_bind_group(0, &self.camera.camera_bind_group, &[]);
-        self.renderer.render(render_pass, &self.draw_datas);
+        // Letterboxed modes leave the bars with the background color.
+        self.renderer.render(
+            render_pass,
+            &self.draw_datas,
+            &self.camera.camera_bind_group,
+            self.size,
+            self.camera.viewport(),
+        );
+    }
+
+    /// Whether nothing was drawn in the last prepared frame.
+    pub fn is_empty(&self) -> bool {
+        self.draw_datas.is_empty()
     }
 
     pub fn set_size(&mut self, width: u32, height: u32) {
         self.size = SizeInPx { width, height };
-        self.camera.set_size(width, height)
+        self.camera.set_size(width, height);
+        self.ui_camera.set_size(width, height);
     }
 
     pub fn set_scale(&mut self, scale: f32) {
         self.camera.set_scale(scale);
+        self.ui_camera.set_scale(scale);
+    }
+
+    pub fn set_scale_factor(&mut self, scale_factor: f32) {
+        self.camera.set_scale_factor(scale_factor);
+        self.ui_camera.set_scale_factor(scale_factor);
+    }
+
+    pub fn set_scale_mode(&mut self, scale_mode: ScaleMode) {
+        self.camera.set_scale_mode(scale_mode);
+        self.ui_camera.set_scale_mode(scale_mode);
+    }
+
+    /// The scale actually applied, which with [`ScaleMode::IntegerNearest`] is the integer factor.
+    pub fn effective_scale(&self) -> f32 {
+        self.camera.effective_scale()
     }
 
     pub fn set_translation(&mut self, translation: Position) {
         self.camera.set_translation(translation);
     }
 
+    /// Shakes the main camera, see [`Camera::apply_shake`]. The UI camera stays still.
+    pub fn apply_shake(&mut self, trauma: f32, dt: Duration) {
+        self.camera.apply_shake(trauma, dt);
+    }
+
+    /// The part of the world drawn with the main camera.
+    pub fn visible_rect(&self) -> Rect {
+        self.camera.visible_rect()
+    }
+
+    pub fn set_debug_mode(&mut self, debug_mode: DebugMode) {
+        self.renderer.debug_mode = debug_mode;
+    }
+
+    pub fn debug_mode(&self) -> DebugMode {
+        self.renderer.debug_mode
+    }
+
     pub fn resources<'a>(&'a self, device: &'a Device, queue: &'a Queue) -> Resources<'a> {
         Resources::new(device, queue, &self.texture_context)
     }
@@ -148,11 +295,79 @@ impl GraphicsRenderer {
         for draw_data in self.draw_datas.drain(..) {
             self.buffer_cache.release_buffer(draw_data.instance_buffer);
         }
+        self.camera_snapshots.reset();
     }
 
     fn cleanup(&mut self) {
-        // We cleanup buffers that were not reused previously.
+        // We cleanup buffers that were not reused for a while.
+        self.buffer_cache.trim();
+    }
+
+    /// Drops the buffers kept for reuse and the text laid out in previous frames.
+    pub fn reset_caches(&mut self) {
         self.buffer_cache.clear();
+        self.camera_snapshots.clear();
+        self.text_converter.clear();
+    }
+}
+
+/// The lines queued with [`Canvas::debug_text`], and what draws them.
+#[cfg(feature = "debug-text")]
+#[derive(Default)]
+struct DebugOverlay {
+    lines: Vec<String>,
+    font: Option<Font>,
+    camera: Option<Camera>,
+}
+
+#[cfg(feature = "debug-text")]
+impl DebugOverlay {
+    fn draw(&mut self, graphics: &mut Graphics, device: &Device, main: &CameraSettings) {
+        if self.lines.is_empty() {
+            return;
+        }
+        let text = std::mem::take(&mut self.lines).join("\n");
+
+        // Pixel for pixel, only scaled by the scale factor of the window.
+        let camera = self.camera.get_or_insert_with(|| {
+            Camera::new(
+                device,
+                CameraSettings {
+                    scale: 1.0,
+                    scale_mode: ScaleMode::Free,
+                    translation: Position::new(0.0, 0.0),
+                    ..*main
+                },
+            )
+        });
+        if camera.camera_settings.scale_factor != main.scale_factor {
+            camera.set_scale_factor(main.scale_factor);
+        }
+        let font = self.font.get_or_insert_with(|| {
+            Font::load_font_bytes(
+                include_bytes!("../fonts/VT323-Regular.ttf"),
+                text::FontSettings::default(),
+            )
+            .expect("The bundled font is valid")
+        });
+
+        let (width, height) = font.measure_text(&text, DEBUG_TEXT_PX);
+        graphics.with_camera(camera, |graphics| {
+            // Above anything drawn by the game.
+            graphics.with_layer(i32::MAX, |graphics| {
+                graphics.draw_rect(
+                    Rect::new(0.0, 0.0, width + 8.0, height + 8.0),
+                    Color::rgba(0.0, 0.0, 0.0, 0.6),
+                );
+                graphics.draw_text(
+                    font,
+                    &text,
+                    DEBUG_TEXT_PX,
+                    Position::new(4.0, 4.0),
+                    Color::rgb(1.0, 1.0, 1.0),
+                );
+            });
+        });
     }
 }
 
@@ -160,6 +375,12 @@ pub struct Canvas {
     wgpu_context: WgpuContext,
     graphics_renderer: GraphicsRenderer,
     canvas_settings: CanvasSettings,
+    last_frame_timing: Option<FrameTiming>,
+    last_frame_end: Option<Instant>,
+    last_frame_skipped: bool,
+    recorder: Option<Recorder>,
+    #[cfg(feature = "debug-text")]
+    debug_overlay: DebugOverlay,
 }
 
 impl Canvas {
@@ -172,19 +393,27 @@ impl Canvas {
     where
         W: HasRawWindowHandle + HasRawDisplayHandle,
     {
-        let wgpu_context = WgpuContext::new(window, width, height).await?;
+        // wgpu can't configure a surface without area, a window created minimized may report one.
+        let (width, height) = (width.max(1), height.max(1));
+        let wgpu_context = WgpuContext::new(window, width, height, &canvas_settings).await?;
         let graphics_renderer = GraphicsRenderer::new(
             &wgpu_context.device,
             &wgpu_context.queue,
             width,
             height,
-            canvas_settings.scale,
+            &canvas_settings,
         );
 
         Ok(Self {
             wgpu_context,
             graphics_renderer,
             canvas_settings,
+            last_frame_timing: None,
+            last_frame_end: None,
+            last_frame_skipped: false,
+            recorder: None,
+            #[cfg(feature = "debug-text")]
+            debug_overlay: DebugOverlay::default(),
         })
     }
 
@@ -192,11 +421,52 @@ impl Canvas {
     where
         F: FnOnce(&mut Graphics),
     {
+        self.draw_with_clear(Some(self.canvas_settings.background_color), draw_function)
+    }
+
+    /// Like [`Canvas::draw`], but clears with the given color instead of the background color.
+    /// With `None`, the previous frame is kept and drawn over, for incremental drawing.
+    pub fn draw_with_clear<F>(
+        &mut self,
+        clear_color: Option<Color>,
+        draw_function: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Graphics),
+    {
+        if self.wgpu_context.minimized {
+            // Without a surface to wait on, the loop would otherwise spin at full speed.
+            self.limit_frame_rate();
+            return Ok(());
+        }
+
+        let prepare_start = Instant::now();
+        #[cfg(feature = "debug-text")]
+        let draw_function = {
+            let main_camera_settings = self.graphics_renderer.camera.camera_settings;
+            let device = &self.wgpu_context.device;
+            let debug_overlay = &mut self.debug_overlay;
+            move |graphics: &mut Graphics| {
+                draw_function(graphics);
+                debug_overlay.draw(graphics, device, &main_camera_settings);
+            }
+        };
         self.graphics_renderer.prepare(
             &self.wgpu_context.device,
             &self.wgpu_context.queue,
             draw_function,
         );
+        let cpu_prepare = prepare_start.elapsed();
+
+        self.last_frame_skipped = self.canvas_settings.skip_unchanged_frames
+            && self.wgpu_context.has_frame
+            && self.graphics_renderer.is_empty();
+        if self.last_frame_skipped {
+            // Nothing prepared for the skipped frame may be drawn with the next one.
+            self.graphics_renderer.reset();
+            self.limit_frame_rate();
+            return Ok(());
+        }
 
         let surface_texture = self
             .wgpu_context
@@ -206,7 +476,37 @@ impl Canvas {
         let view = self
             .wgpu_context
             .buffer_texture
+            .as_ref()
+            .unwrap_or(&surface_texture.texture)
             .create_view(&wgpu::TextureViewDescriptor::default());
+        // With multisampling, we draw to the multisampled texture and resolve into the buffer.
+        let msaa_view =
+            self.wgpu_context.msaa_texture.as_ref().map(|msaa_texture| {
+                msaa_texture.create_view(&wgpu::TextureViewDescriptor::default())
+            });
+        let (view, resolve_target) = match &msaa_view {
+            Some(msaa_view) => (msaa_view, Some(&view)),
+            None => (&view, None),
+        };
+
+        let load = match (self.graphics_renderer.debug_mode(), clear_color) {
+            (DebugMode::Overdraw, Some(_)) => wgpu::LoadOp::Clear(Color::rgb(0.0, 0.0, 0.0).into()),
+            (_, Some(clear_color)) => wgpu::LoadOp::Clear(clear_color.into()),
+            (_, None) => wgpu::LoadOp::Load,
+        };
+
+        let id_view = self
+            .wgpu_context
+            .id_texture
+            .as_ref()
+            .map(|id_texture| id_texture.create_view(&wgpu::TextureViewDescriptor::default()));
+        let depth_view = self
+            .wgpu_context
+            .depth_texture
+            .as_ref()
+            .map(|depth_texture| {
+                depth_texture.create_view(&wgpu::TextureViewDescriptor::default())
+            });
 
         let mut encoder: CommandEncoder =
             self.wgpu_context
@@ -216,41 +516,113 @@ impl Canvas {
                 });
 
         {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
+            let mut color_attachments = vec![Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations { load, store: true },
+            })];
+            if let Some(id_view) = &id_view {
+                color_attachments.push(Some(wgpu::RenderPassColorAttachment {
+                    view: id_view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(self.canvas_settings.background_color.into()),
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
                         store: true,
                     },
-                })],
-                depth_stencil_attachment: None,
+                }));
+            }
+
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &color_attachments,
+                depth_stencil_attachment: depth_view.as_ref().map(|depth_view| {
+                    wgpu::RenderPassDepthStencilAttachment {
+                        view: depth_view,
+                        depth_ops: Some(wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(1.0),
+                            store: false,
+                        }),
+                        stencil_ops: None,
+                    }
+                }),
             });
 
             self.graphics_renderer.render(&mut render_pass);
         }
 
-        encoder.copy_texture_to_texture(
-            wgpu::ImageCopyTexture {
-                texture: &self.wgpu_context.buffer_texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            wgpu::ImageCopyTexture {
-                texture: &surface_texture.texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            wgpu::Extent3d {
-                width: self.wgpu_context.size.width,
-                height: self.wgpu_context.size.height,
-                depth_or_array_layers: 1,
-            },
-        );
+        self.wgpu_context
+            .copy_to_surface(&mut encoder, &surface_texture.texture);
+
+        let submit_start = Instant::now();
+        self.wgpu_context.queue.submit(Some(encoder.finish()));
+        let submit = submit_start.elapsed();
+
+        let present_start = Instant::now();
+        surface_texture.present();
+        let present = present_start.elapsed();
+        self.wgpu_context.has_frame = true;
+
+        if let (Some(recorder), Some(buffer_texture)) =
+            (&mut self.recorder, &self.wgpu_context.buffer_texture)
+        {
+            recorder.capture(
+                &self.wgpu_context.device,
+                &self.wgpu_context.queue,
+                buffer_texture,
+                self.wgpu_context.config.format,
+                self.wgpu_context.size,
+            );
+        }
+
+        self.last_frame_timing = Some(FrameTiming {
+            cpu_prepare,
+            submit,
+            present,
+        });
+        self.limit_frame_rate();
+
+        Ok(())
+    }
+
+    /// Queues a line drawn over the next frame at the top left of the window, with a bundled
+    /// font, whatever the cameras and the scale. Lines are only drawn once, so they are queued
+    /// every frame, like an FPS counter would be.
+    #[cfg(feature = "debug-text")]
+    pub fn debug_text<T: Into<String>>(&mut self, text: T) {
+        self.debug_overlay.lines.push(text.into());
+    }
+
+    /// Drops what is kept from frame to frame to go faster, like the instance buffers and the
+    /// laid out text, for instance between two scenes. The glyph atlases belong to the fonts, see
+    /// [`Font::clear_cache`]. The next frame rebuilds what it needs, which makes it slower.
+    pub fn reset_caches(&mut self) {
+        self.graphics_renderer.reset_caches();
+    }
+
+    /// Presents the last drawn frame again, without preparing anything. Does nothing if no frame
+    /// was drawn since the canvas was created or resized, or without an intermediate target.
+    pub fn redraw_last(&mut self) -> Result<(), Error> {
+        if !self.wgpu_context.has_frame
+            || self.wgpu_context.minimized
+            || self.wgpu_context.buffer_texture.is_none()
+        {
+            return Ok(());
+        }
+
+        let surface_texture = self
+            .wgpu_context
+            .surface
+            .get_current_texture()
+            .map_err(Error::RenderingFailed)?;
+
+        let mut encoder: CommandEncoder =
+            self.wgpu_context
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Redraw Encoder"),
+                });
+        self.wgpu_context
+            .copy_to_surface(&mut encoder, &surface_texture.texture);
 
         self.wgpu_context.queue.submit(Some(encoder.finish()));
         surface_texture.present();
@@ -258,11 +630,51 @@ impl Canvas {
         Ok(())
     }
 
+    /// A size of zero, as sent when the window is minimized, keeps the previous size and pauses
+    /// rendering until a real size comes back.
     pub fn set_size(&mut self, width: u32, height: u32) {
+        self.wgpu_context.minimized = width == 0 || height == 0;
+        if self.wgpu_context.minimized {
+            return;
+        }
+
         self.wgpu_context.resize(width, height);
         self.graphics_renderer.set_size(width, height);
     }
 
+    /// Sleeps for what is left of the frame budget when [`CanvasSettings::target_fps`] is set.
+    fn limit_frame_rate(&mut self) {
+        let target_fps = match self.canvas_settings.target_fps {
+            Some(target_fps) if target_fps > 0 => target_fps,
+            _ => return,
+        };
+
+        let budget = Duration::from_secs(1) / target_fps;
+        if let Some(last_frame_end) = self.last_frame_end {
+            let elapsed = last_frame_end.elapsed();
+            if elapsed < budget {
+                std::thread::sleep(budget - elapsed);
+            }
+        }
+        self.last_frame_end = Some(Instant::now());
+    }
+
+    /// How long the CPU side of the last drawn frame took, `None` until a frame was drawn.
+    pub fn last_frame_timing(&self) -> Option<FrameTiming> {
+        self.last_frame_timing
+    }
+
+    /// Whether the last [`Canvas::draw`] drew nothing and was skipped, with
+    /// [`CanvasSettings::skip_unchanged_frames`].
+    pub fn last_frame_skipped(&self) -> bool {
+        self.last_frame_skipped
+    }
+
+    /// Whether [`Canvas::draw`] is currently skipped because the window was minimized.
+    pub fn is_minimized(&self) -> bool {
+        self.wgpu_context.minimized
+    }
+
     pub fn size(&self) -> SizeInPx {
         self.graphics_renderer.size
     }
@@ -271,19 +683,119 @@ impl Canvas {
         self.canvas_settings.scale
     }
 
+    pub fn background_color(&self) -> Color {
+        self.canvas_settings.background_color
+    }
+
+    pub fn set_background_color(&mut self, background_color: Color) {
+        self.canvas_settings.background_color = background_color;
+    }
+
     pub fn set_scale(&mut self, scale: f32) {
         self.canvas_settings.scale = scale;
         self.graphics_renderer.set_scale(scale);
     }
 
+    pub fn scale_factor(&self) -> f32 {
+        self.graphics_renderer.camera.camera_settings.scale_factor
+    }
+
+    /// Sets the ratio between physical and logical pixels of the window, like winit's
+    /// `Window::scale_factor`. With the size given in physical pixels, as `inner_size()` returns,
+    /// content authored in logical pixels then renders at native resolution. It multiplies
+    /// [`CanvasSettings::scale`], and has no effect when the scale comes from a [`ScaleMode`]
+    /// fitting a size to the window.
+    pub fn set_scale_factor(&mut self, scale_factor: f32) {
+        self.graphics_renderer.set_scale_factor(scale_factor);
+    }
+
+    pub fn scale_mode(&self) -> ScaleMode {
+        self.canvas_settings.scale_mode
+    }
+
+    pub fn set_scale_mode(&mut self, scale_mode: ScaleMode) {
+        self.canvas_settings.scale_mode = scale_mode;
+        self.graphics_renderer
+            .set_scale_mode(self.canvas_settings.effective_scale_mode());
+    }
+
+    pub fn virtual_size(&self) -> Option<SizeInPx> {
+        self.canvas_settings.virtual_size
+    }
+
+    pub fn set_virtual_size(&mut self, virtual_size: Option<SizeInPx>) {
+        self.canvas_settings.virtual_size = virtual_size;
+        self.graphics_renderer
+            .set_scale_mode(self.canvas_settings.effective_scale_mode());
+    }
+
+    /// Converts a position in the window, like the mouse cursor, to a position in the world,
+    /// accounting for the scale, the translation and the letterboxing.
+    pub fn screen_to_world(&self, screen: Position) -> Position {
+        self.graphics_renderer.camera.screen_to_world(screen)
+    }
+
+    /// The scale actually applied, which with [`ScaleMode::IntegerNearest`] is the integer factor.
+    pub fn effective_scale(&self) -> f32 {
+        self.graphics_renderer.effective_scale()
+    }
+
     pub fn translation(&self) -> Position {
         self.graphics_renderer.camera.camera_settings.translation
     }
 
+    /// The part of the world drawn with the main camera.
+    pub fn visible_rect(&self) -> Rect {
+        self.graphics_renderer.visible_rect()
+    }
+
+    /// Creates a camera that [`Graphics::with_camera`] can draw with, independently of the main
+    /// one. It starts with the scale of the canvas and without translation.
+    pub fn create_camera(&self) -> Camera {
+        Camera::new(
+            &self.wgpu_context.device,
+            CameraSettings {
+                translation: Position::new(0.0, 0.0),
+                ..self.graphics_renderer.camera.camera_settings
+            },
+        )
+    }
+
+    /// Wireframe needs the adapter to support [`wgpu::Features::POLYGON_MODE_LINE`], and draws
+    /// normally otherwise.
+    pub fn set_debug_mode(&mut self, debug_mode: DebugMode) {
+        self.graphics_renderer.set_debug_mode(debug_mode);
+    }
+
+    pub fn debug_mode(&self) -> DebugMode {
+        self.graphics_renderer.debug_mode()
+    }
+
     pub fn set_translation(&mut self, translation: Position) {
         self.graphics_renderer.set_translation(translation)
     }
 
+    /// Shakes the main camera by adding `trauma` and advancing the shake by `dt`, to be called
+    /// every frame. See [`Camera::apply_shake`].
+    pub fn apply_shake(&mut self, trauma: f32, dt: Duration) {
+        self.graphics_renderer.apply_shake(trauma, dt)
+    }
+
+    /// Sets the trauma of the main camera directly, between 0 and 1.
+    pub fn set_trauma(&mut self, trauma: f32) {
+        self.graphics_renderer.camera.set_trauma(trauma)
+    }
+
+    /// How much trauma the main camera loses per second.
+    pub fn set_trauma_decay(&mut self, decay: f32) {
+        self.graphics_renderer.camera.set_trauma_decay(decay)
+    }
+
+    /// The offset of the main camera at full trauma, in world units.
+    pub fn set_max_shake(&mut self, max_offset: f32) {
+        self.graphics_renderer.camera.set_max_shake(max_offset)
+    }
+
     pub async fn screenshot<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
         let SizeInPx { width, height } = self.wgpu_context.size;
         let pixels = texture_to_cpu(
@@ -291,25 +803,132 @@ impl Canvas {
             &self.wgpu_context.queue,
             width,
             height,
-            &self.wgpu_context.buffer_texture,
+            self.wgpu_context.buffer_texture()?,
         )
         .await
         .map_err(|_| Error::ScreenshotFailed)?;
 
-        use image::{ImageBuffer, Rgba};
-        let mut buffer = ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, pixels).unwrap();
+        let buffer = to_rgba_image(self.wgpu_context.config.format, width, height, pixels);
+        buffer.save(path).map_err(|_| Error::ScreenshotFailed)?;
+
+        Ok(())
+    }
+
+    /// Saves one frame out of `every_n_frames` drawn from now on to `dir`, as numbered PNG files,
+    /// until [`Canvas::stop_recording`]. Fails without an intermediate target, see
+    /// [`CanvasSettings::use_intermediate_target`]. A recording in progress is stopped first, and
+    /// if it failed, its error is returned instead of starting the new one.
+    ///
+    /// Recording isn't free: every recorded frame is copied back from the GPU, and encoded on a
+    /// separate thread. Frames are read back a few draws later so that drawing doesn't wait for
+    /// the GPU, unless too many are in flight. Drawing also waits when the encoder falls too far
+    /// behind.
+    pub fn start_recording<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+        every_n_frames: u32,
+    ) -> Result<(), Error> {
+        self.wgpu_context.buffer_texture()?;
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir).map_err(Error::IOError)?;
+        self.start_recorder(FrameSink::Directory(dir), every_n_frames)
+    }
+
+    /// Like [`Canvas::start_recording`], but hands each recorded frame and its number to
+    /// `on_frame` instead of saving it. `on_frame` runs during [`Canvas::draw`], so slow work like
+    /// encoding is better sent to another thread.
+    pub fn start_recording_with<F>(&mut self, every_n_frames: u32, on_frame: F) -> Result<(), Error>
+    where
+        F: FnMut(u64, image::RgbaImage) + 'static,
+    {
+        self.wgpu_context.buffer_texture()?;
+        self.start_recorder(FrameSink::Callback(Box::new(on_frame)), every_n_frames)
+    }
 
-        for px in buffer.pixels_mut() {
-            let cmp = px.0;
-            *px = Rgba([cmp[2], cmp[1], cmp[0], cmp[3]]);
+    /// Stops recording, after waiting for the frames still being read back and saved. Fails with
+    /// the first frame that couldn't be saved, if any.
+    pub fn stop_recording(&mut self) -> Result<(), Error> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(&self.wgpu_context.device),
+            None => Ok(()),
         }
+    }
 
-        buffer.save(path).map_err(|_| Error::ScreenshotFailed)?;
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
 
+    fn start_recorder(&mut self, sink: FrameSink, every_n_frames: u32) -> Result<(), Error> {
+        self.stop_recording()?;
+        self.recorder = Some(Recorder::new(sink, every_n_frames));
         Ok(())
     }
 
-    pub fn resources(&self) -> Resources {
+    /// Reads the color of a single pixel of the last drawn frame, for eyedroppers or color based hit
+    /// testing. With [`CanvasSettings::srgb`], the color is linear like the ones drawn with.
+    pub async fn read_pixel(&self, x: u32, y: u32) -> Result<Color, Error> {
+        let SizeInPx { width, height } = self.wgpu_context.size;
+        if x >= width || y >= height {
+            return Err(Error::PixelOutOfBounds { x, y });
+        }
+
+        let texel = read_texel(
+            &self.wgpu_context.device,
+            &self.wgpu_context.queue,
+            self.wgpu_context.buffer_texture()?,
+            x,
+            y,
+        )
+        .await
+        .map_err(|_| Error::ScreenshotFailed)?;
+        let [r, g, b, a] = to_rgba(self.wgpu_context.config.format, texel);
+
+        let channel = |value: u8| {
+            let value = value as f32 / 255.0;
+            if self.canvas_settings.srgb {
+                srgb_to_linear(value)
+            } else {
+                value
+            }
+        };
+        Ok(Color::rgba(
+            channel(r),
+            channel(g),
+            channel(b),
+            a as f32 / 255.0,
+        ))
+    }
+
+    /// Returns the id of the operation drawn last at this position of the screen, as set with
+    /// [`RenderOperation::with_id`]. Picking must be enabled in the [`CanvasSettings`].
+    pub async fn pick(&self, screen: Position) -> Option<u32> {
+        let id_texture = self.wgpu_context.id_texture.as_ref()?;
+        let SizeInPx { width, height } = self.wgpu_context.size;
+        if screen.left < 0.0
+            || screen.top < 0.0
+            || screen.left >= width as f32
+            || screen.top >= height as f32
+        {
+            return None;
+        }
+
+        let texel = read_texel(
+            &self.wgpu_context.device,
+            &self.wgpu_context.queue,
+            id_texture,
+            screen.left as u32,
+            screen.top as u32,
+        )
+        .await
+        .ok()?;
+
+        match u32::from_ne_bytes(texel) {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    pub fn resources(&self) -> Resources<'_> {
         Resources::new(
             &self.wgpu_context.device,
             &self.wgpu_context.queue,
@@ -320,64 +939,399 @@ impl Canvas {
 
 pub struct CanvasSettings {
     pub scale: f32,
+    pub scale_mode: ScaleMode,
+    /// A fixed resolution to design against, scaled to fill the window while keeping its aspect
+    /// ratio, with bars of the background color around. Takes precedence over `scale_mode`.
+    pub virtual_size: Option<SizeInPx>,
     pub background_color: Color,
+    /// Generates mipmaps for loaded sprites and samples them with linear, anisotropic filtering.
+    /// This smooths sprites drawn smaller than their size, at the cost of more texture memory and
+    /// of the crisp look of pixel art.
+    pub mipmaps: bool,
+    /// Renders the ids set with [`RenderOperation::with_id`] to an extra target, so that
+    /// [`Canvas::pick`] can find what is under a point. Every frame then writes a second,
+    /// full-screen 32 bit target, and each pick waits for the GPU to read a pixel back.
+    /// Ignored when `msaa` is above 1, as integer ids can't be resolved from samples.
+    pub picking: bool,
+    /// The number of samples per pixel, for anti-aliased edges. 1 disables multisampling, and 4
+    /// is the only other count every adapter supports, so any count above 1 uses 4 samples.
+    pub msaa: u32,
+    /// Anti-aliases the edges of rects, lines, arcs and pies in the shader. Unlike `msaa`, which
+    /// applies to everything drawn, sprites keep their hard edges, which suits pixel art under a
+    /// smooth UI. Text is always smooth, and its underline and strikethrough are smoothed like
+    /// grids and other rects. Rects drawn side by side with [`Graphics::draw_rect`] or
+    /// [`Graphics::draw_rects`] show faint seams where they meet at fractional positions.
+    pub smooth_shapes: bool,
+    pub buffer_strategy: BufferStrategy,
+    /// With [`BufferStrategy::Pooled`], how many frames a buffer that wasn't reused is kept
+    /// before being freed, 1 by default. Raising it avoids reallocating buffers when the amount
+    /// drawn varies from frame to frame, at the cost of memory.
+    pub buffer_pool_frames: u32,
+    /// Premultiplies the alpha of loaded sprites and blends accordingly, which avoids dark fringes
+    /// around filtered or scaled sprites. Colors and [`RenderOperation::alpha`] keep working on
+    /// straight alpha, the conversion happens in the shader.
+    pub premultiplied_alpha: bool,
+    /// Renders to sRGB targets and samples sprites as sRGB, so that blending and filtering happen
+    /// in linear space. [`Color`] values, including the background, are then linear too.
+    pub srgb: bool,
+    /// Which GPU to prefer, on machines with both an integrated and a discrete one.
+    pub power_preference: wgpu::PowerPreference,
+    /// The graphics APIs wgpu may use, for instance to force Vulkan or GL.
+    pub backends: wgpu::Backends,
+    /// Features the device must support, [`Canvas::new`] fails otherwise.
+    pub features: wgpu::Features,
+    /// Limits the device must support, [`Canvas::new`] fails otherwise.
+    pub limits: wgpu::Limits,
+    /// Makes [`Canvas::draw`] sleep for the rest of the frame budget, which is mostly useful with
+    /// the `Immediate` present mode. It's a coarse sleep, not a precise vsync.
+    pub target_fps: Option<u32>,
+    /// Orders operations by their [`RenderOperation::with_z`] with a depth buffer, instead of by
+    /// the order of the calls. Opaque operations hide what is behind them whatever the order,
+    /// dropping their pixels under half alpha. Transparent ones, see
+    /// [`RenderOperation::transparent`], are drawn after them, from back to front, within each
+    /// layer.
+    pub depth_sorting: bool,
+    /// Makes [`Canvas::draw`] leave the previous frame on screen, without rendering nor
+    /// presenting, when the draw function draws nothing. This saves GPU time and power for tools
+    /// and turn based games that only draw when something changed, but the background isn't
+    /// cleared either. [`Canvas::last_frame_skipped`] tells when it happened.
+    pub skip_unchanged_frames: bool,
+    /// Renders to a texture of its own, copied to the surface every frame. The copy reads and
+    /// writes 4 bytes per pixel: about 16.6 MB a frame at 1920x1080, so 1 GB/s at 60 FPS, and
+    /// 66 MB a frame at 3840x2160, so 4 GB/s. Without it, frames are rendered straight to the
+    /// surface, which saves that copy, but there is nothing left to read back: screenshots,
+    /// [`Canvas::read_pixel`] and recordings fail with [`Error::NoIntermediateTarget`],
+    /// [`Canvas::redraw_last`] does nothing, and drawing without clearing starts from undefined
+    /// content instead of the previous frame.
+    pub use_intermediate_target: bool,
+}
+
+impl CanvasSettings {
+    pub(crate) fn picking_enabled(&self) -> bool {
+        self.picking && self.msaa <= 1
+    }
+
+    /// The samples per pixel actually used for `msaa`. Other counts would fail to create the
+    /// pipelines on most adapters.
+    pub(crate) fn sample_count(&self) -> u32 {
+        if self.msaa > 1 {
+            4
+        } else {
+            1
+        }
+    }
+
+    pub(crate) fn effective_scale_mode(&self) -> ScaleMode {
+        self.virtual_size
+            .map(ScaleMode::Fit)
+            .unwrap_or(self.scale_mode)
+    }
+
+    pub(crate) fn target_format(&self) -> wgpu::TextureFormat {
+        if self.srgb {
+            wgpu::TextureFormat::Bgra8UnormSrgb
+        } else {
+            wgpu::TextureFormat::Bgra8Unorm
+        }
+    }
 }
 
 impl Default for CanvasSettings {
     fn default() -> Self {
         Self {
             scale: 1.0,
+            scale_mode: ScaleMode::default(),
+            virtual_size: None,
             background_color: Color {
                 r: 0.0,
                 g: 0.0,
                 b: 0.0,
                 a: 1.0,
             },
+            mipmaps: false,
+            picking: false,
+            msaa: 1,
+            smooth_shapes: false,
+            buffer_strategy: BufferStrategy::default(),
+            buffer_pool_frames: 1,
+            premultiplied_alpha: false,
+            srgb: false,
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            backends: wgpu::Backends::all(),
+            features: wgpu::Features::empty(),
+            limits: wgpu::Limits::default(),
+            target_fps: None,
+            depth_sorting: false,
+            skip_unchanged_frames: false,
+            use_intermediate_target: true,
         }
     }
 }
 
+/// CPU timings of a frame, as measured by [`Canvas::draw`]. The GPU work itself runs
+/// asynchronously and isn't included.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameTiming {
+    /// Running the draw function and preparing the instance buffers.
+    pub cpu_prepare: Duration,
+    /// Submitting the command buffer to the queue.
+    pub submit: Duration,
+    /// Presenting the frame, which may block on vsync depending on the present mode.
+    pub present: Duration,
+}
+
+/// How the instance buffers of each frame are reused. The `buffer_strategies` benchmark compares
+/// the two on a particle count that changes every frame, run it with
+/// `cargo bench -p tiefring --bench buffer_strategies`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BufferStrategy {
+    /// Buffers are pooled by size, and each draw takes the smallest one that fits.
+    #[default]
+    Pooled,
+    /// Each draw, in order, keeps its own buffer from frame to frame, and only reallocates it,
+    /// doubling its capacity, when it is too small. Suits scenes drawing about the same thing
+    /// every frame, like a large particle stream.
+    Growable,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugMode {
+    None,
+    /// Draws the edges of the triangles instead of filling them.
+    Wireframe,
+    /// Draws every operation as the same additive flat color, on a black background.
+    Overdraw,
+}
+
 struct OperationBlock {
     operations: Vec<RenderOperation>,
-    texture: Rc<Texture>,
+    texture: Shared<Texture>,
+    render_state: RenderState,
+    camera: Option<Rc<BindGroup>>,
+    viewport: Option<Rect>,
+    layer: i32,
+    /// Folded into the color of the operations added, see [`Graphics::with_opacity`].
+    opacity: f32,
 }
 
 impl OperationBlock {
-    fn new(texture: Rc<Texture>) -> Self {
+    fn new(
+        texture: Shared<Texture>,
+        render_state: RenderState,
+        camera: Option<Rc<BindGroup>>,
+        viewport: Option<Rect>,
+        layer: i32,
+    ) -> Self {
         OperationBlock {
             operations: Vec::with_capacity(OPERATION_CAPACITY),
             texture,
+            render_state,
+            camera,
+            viewport,
+            layer,
+            opacity: 1.0,
         }
     }
 
-    fn push_render_operation(&mut self, render_operation: RenderOperation) -> &mut RenderOperation {
+    fn accepts(
+        &self,
+        texture: &Texture,
+        render_state: RenderState,
+        camera: &Option<Rc<BindGroup>>,
+        viewport: Option<Rect>,
+        layer: i32,
+    ) -> bool {
+        let same_camera = match (&self.camera, camera) {
+            (Some(camera), Some(other_camera)) => Rc::ptr_eq(camera, other_camera),
+            (None, None) => true,
+            _ => false,
+        };
+
+        self.texture.id == texture.id
+            && self.render_state == render_state
+            && same_camera
+            && self.viewport == viewport
+            && self.layer == layer
+            && self.operations.len() < OPERATION_CAPACITY
+    }
+
+    fn push_render_operation(
+        &mut self,
+        mut render_operation: RenderOperation,
+    ) -> &mut RenderOperation {
+        self.apply_opacity(&mut render_operation);
         self.operations.push(render_operation);
         self.operations.last_mut().expect("Just pushed an item")
     }
 
-    fn reuse(mut self, texture: Rc<Texture>) -> Self {
+    fn append_render_operations(&mut self, render_operations: &mut Vec<RenderOperation>) {
+        for render_operation in render_operations.iter_mut() {
+            self.apply_opacity(render_operation);
+        }
+        self.operations.append(render_operations);
+    }
+
+    fn apply_opacity(&self, render_operation: &mut RenderOperation) {
+        if self.opacity < 1.0 {
+            render_operation.color_matrix =
+                render_operation.color_matrix.multiply_alpha(self.opacity);
+            render_operation.transparent = true;
+        }
+    }
+
+    fn reuse(
+        mut self,
+        texture: Shared<Texture>,
+        render_state: RenderState,
+        camera: Option<Rc<BindGroup>>,
+        viewport: Option<Rect>,
+        layer: i32,
+    ) -> Self {
         self.operations.clear();
         self.texture = texture;
+        self.render_state = render_state;
+        self.camera = camera;
+        self.viewport = viewport;
+        self.layer = layer;
+        self.opacity = 1.0;
         self
     }
 }
 
+/// What [`OpenBlocks`] needs to know of a block.
+trait LayeredBlock {
+    fn layer(&self) -> i32;
+    fn operation_count(&self) -> usize;
+}
+
+impl LayeredBlock for OperationBlock {
+    fn layer(&self) -> i32 {
+        self.layer
+    }
+
+    fn operation_count(&self) -> usize {
+        self.operations.len()
+    }
+}
+
+/// The blocks still being filled: the current one, and the ones of the other layers, at most one
+/// per layer. Each layer keeps its own block open, so drawing on two layers in turn still
+/// batches.
+struct OpenBlocks<B> {
+    current: Option<B>,
+    parked: Vec<B>,
+}
+
+impl<B> Default for OpenBlocks<B> {
+    fn default() -> Self {
+        Self {
+            current: None,
+            parked: vec![],
+        }
+    }
+}
+
+impl<B: LayeredBlock> OpenBlocks<B> {
+    /// Parks the current block if it is on another layer, and makes the open block of `layer`,
+    /// if there is one, the current one.
+    fn switch_to_layer(&mut self, layer: i32) {
+        if !matches!(&self.current, Some(block) if block.layer() != layer) {
+            return;
+        }
+
+        if let Some(block) = self.current.take() {
+            self.parked.push(block);
+        }
+        if let Some(index) = self.parked.iter().position(|block| block.layer() == layer) {
+            self.current = Some(self.parked.swap_remove(index));
+        }
+    }
+
+    /// How many operations the open blocks hold.
+    fn operation_count(&self) -> usize {
+        self.current
+            .iter()
+            .chain(&self.parked)
+            .map(LayeredBlock::operation_count)
+            .sum()
+    }
+}
+
 struct DrawData {
     instance_buffer: ReusableBuffer,
     count: u32,
-    texture: Rc<Texture>,
+    texture: Shared<Texture>,
+    render_state: RenderState,
+    /// `None` draws with the main camera.
+    camera: Option<Rc<BindGroup>>,
+    /// The part of the surface drawn to, in pixels, `None` being all of it.
+    viewport: Option<Rect>,
+    layer: i32,
+    /// Drawn after the opaque operations of the layer, without writing the depth.
+    transparent: bool,
+}
+
+/// Where draw data goes in the frame: by layer, from the lowest, then, with depth sorting, the
+/// transparent operations of a layer after its opaque ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct DrawOrder {
+    layer: i32,
+    transparent: bool,
+}
+
+impl DrawData {
+    fn draw_order(&self) -> DrawOrder {
+        DrawOrder {
+            layer: self.layer,
+            transparent: self.transparent,
+        }
+    }
+}
+
+/// The order transparent operations are drawn in with depth sorting, from the farthest, the
+/// lowest z, to the closest.
+fn back_to_front(a: &RenderOperation, b: &RenderOperation) -> std::cmp::Ordering {
+    a.z.total_cmp(&b.z)
+}
+
+/// An operation set aside to be sorted by z, with depth sorting.
+struct TransparentOperation {
+    texture: Shared<Texture>,
+    render_state: RenderState,
+    camera: Option<Rc<BindGroup>>,
+    viewport: Option<Rect>,
+    layer: i32,
+    operation: RenderOperation,
 }
 
 pub struct Graphics<'a> {
     device: &'a Device,
     queue: &'a Queue,
     size: SizeInPx,
-    transforms: Vec<Transform>,
-    current_operation_block: Option<OperationBlock>,
+    transforms: TransformStack,
+    open_blocks: OpenBlocks<OperationBlock>,
+    transparent_operations: Vec<TransparentOperation>,
+    /// The operations and instances already turned into draw data this frame.
+    prepared_operation_count: usize,
+    layer: i32,
+    /// Multiplied into the alpha of everything drawn, see [`Graphics::with_opacity`].
+    opacity: f32,
     draw_datas: &'a mut Vec<DrawData>,
     buffer_cache: &'a mut BufferCache,
     texture_context: &'a TextureContext,
     text_converter: &'a mut TextConverter,
+    camera_snapshots: &'a mut CameraSnapshots,
+    camera: Option<Rc<BindGroup>>,
+    ui_camera: Rc<BindGroup>,
+    /// The cameras given to [`Graphics::with_camera`] so far this frame.
+    used_cameras: Vec<Rc<BindGroup>>,
+    viewport: Option<Rect>,
+    /// The scale of the current camera, and of the main one.
+    scale: f32,
+    main_scale: f32,
+    /// The part of the world drawn with the current camera, and with the UI one.
+    visible_rect: Rect,
+    ui_visible_rect: Rect,
 }
 
 impl<'a> Graphics<'a> {
@@ -390,16 +1344,33 @@ impl<'a> Graphics<'a> {
         draw_datas: &'a mut Vec<DrawData>,
         buffer_cache: &'a mut BufferCache,
         text_converter: &'a mut TextConverter,
+        camera_snapshots: &'a mut CameraSnapshots,
+        camera: &Camera,
+        ui_camera: &Camera,
     ) -> Self {
+        let scale = camera.effective_scale();
         Graphics {
-            current_operation_block: None,
+            camera: None,
+            ui_camera: ui_camera.camera_bind_group.clone(),
+            used_cameras: vec![],
+            viewport: None,
+            scale,
+            main_scale: scale,
+            visible_rect: camera.visible_rect(),
+            ui_visible_rect: ui_camera.visible_rect(),
+            open_blocks: OpenBlocks::default(),
+            transparent_operations: vec![],
+            prepared_operation_count: 0,
+            layer: 0,
+            opacity: 1.0,
             draw_datas,
             size,
-            transforms: vec![],
+            transforms: TransformStack::default(),
             texture_context,
             device,
             queue,
             text_converter,
+            camera_snapshots,
             buffer_cache,
         }
     }
@@ -411,17 +1382,291 @@ impl<'a> Graphics<'a> {
         let transforms = self.current_transform();
         let color_matrix = ColorMatrix::from_color(color);
 
-        let operation = RenderOperation {
-            rect,
-            color_matrix,
-            tex_coords,
-            transforms,
+        let mut operation = RenderOperation::new(rect, tex_coords, color_matrix, transforms);
+        operation.flags = self.smooth_edges(INSTANCE_SMOOTH_X | INSTANCE_SMOOTH_Y);
+
+        self.get_operation_block(&self.texture_context.white_texture, RenderState::default())
+            .push_render_operation(operation)
+    }
+
+    /// The edge flags to set on a shape, if shapes are smoothed.
+    fn smooth_edges(&self, flags: u32) -> u32 {
+        if self.texture_context.smooth_shapes {
+            flags
+        } else {
+            0
+        }
+    }
+
+    /// Draws a line of the given thickness, centered on the segment from `from` to `to`.
+    pub fn draw_line<P: Into<Position>>(
+        &mut self,
+        from: P,
+        to: P,
+        stroke: Stroke,
+        style: LineStyle,
+    ) {
+        let thickness = self.stroke_width(stroke);
+        let color = stroke.color;
+        let from: Position = from.into();
+        let to: Position = to.into();
+        let (dx, dy) = (to.left - from.left, to.top - from.top);
+        let length = (dx * dx + dy * dy).sqrt();
+
+        let mut transforms = self.current_transform();
+        transforms.translate(from.left, from.top);
+        transforms.rotate(dy.atan2(dx));
+        transforms.translate(0.0, -thickness / 2.0);
+
+        let (dash, gap) = match style {
+            LineStyle::Solid => (length, 0.0),
+            LineStyle::Dashed { dash, gap } => (dash, gap),
+            LineStyle::Dotted => (thickness, thickness),
         };
+        // A dash without length would never get to the end of the line.
+        let (dash, gap) = if dash > 0.0 {
+            (dash, gap.max(0.0))
+        } else {
+            (length, 0.0)
+        };
+        // Past the limit, dashes and gaps are stretched alike so that the count stays bounded.
+        let stretch = (length / (MAX_DASHES as f32 * (dash + gap))).max(1.0);
+        let (dash, gap) = (dash * stretch, gap * stretch);
+
+        let color_matrix = ColorMatrix::from_color(color);
+        let tex_coords = Rect::new(0.0, 0.0, 1.0, 1.0);
+        let texture_context = self.texture_context;
+        let flags = self.smooth_edges(INSTANCE_SMOOTH_X | INSTANCE_SMOOTH_Y);
+
+        // Each dash starts at a multiple of dash + gap from the start, the last one is cut short.
+        let mut start = 0.0;
+        while start < length {
+            let dash_length = dash.min(length - start);
+            let mut operation = RenderOperation::new(
+                Rect::new(0.0, 0.0, dash_length, thickness),
+                tex_coords,
+                color_matrix,
+                transforms * Transform::from_translation(start, 0.0),
+            );
+            operation.flags = flags;
+            self.get_operation_block(&texture_context.white_texture, RenderState::default())
+                .push_render_operation(operation);
+
+            start += dash + gap;
+        }
+    }
 
-        self.get_operation_block(&self.texture_context.white_texture)
+    /// Draws the part of a ring, centered on `radius`, between two angles in radians. Angles go
+    /// clockwise from the right, like rotations, and the sweep is at most a full turn.
+    pub fn draw_arc<P: Into<Position>>(
+        &mut self,
+        center: P,
+        radius: f32,
+        start_angle: f32,
+        end_angle: f32,
+        stroke: Stroke,
+    ) {
+        let thickness = self.stroke_width(stroke);
+        let inner = (radius - thickness / 2.0).max(0.0);
+        let outer = radius + thickness / 2.0;
+        self.draw_ring_sector(
+            center.into(),
+            inner,
+            outer,
+            start_angle,
+            end_angle,
+            stroke.color,
+        );
+    }
+
+    /// Draws a ring between two radii, or a disc when `inner_radius` is 0, with its edges
+    /// softened over `smoothness` pixels: 1 anti-aliases them, 0 keeps them hard, and more blurs
+    /// them. It's a single quad, crisp at any scale, which suits progress rings and dials.
+    pub fn draw_ring<P: Into<Position>>(
+        &mut self,
+        center: P,
+        inner_radius: f32,
+        outer_radius: f32,
+        color: Color,
+        smoothness: f32,
+    ) -> &mut RenderOperation {
+        let center = center.into();
+        let outer_radius = outer_radius.max(0.0);
+        let inner_radius = inner_radius.clamp(0.0, outer_radius);
+        let rect = Rect::new(
+            center.left - outer_radius,
+            center.top - outer_radius,
+            outer_radius * 2.0,
+            outer_radius * 2.0,
+        );
+
+        let tex_coords = Rect::new(0.0, 0.0, 1.0, 1.0);
+        let transforms = self.current_transform();
+        let mut operation =
+            RenderOperation::new(rect, tex_coords, ColorMatrix::from_color(color), transforms);
+        operation.flags = INSTANCE_RING;
+        operation.ring = [
+            inner_radius / outer_radius.max(f32::EPSILON),
+            smoothness.max(0.0),
+            0.0,
+            TAU,
+        ];
+
+        self.get_operation_block(&self.texture_context.white_texture, RenderState::default())
             .push_render_operation(operation)
     }
 
+    /// Draws a slice of a disc between two angles in radians, see [`Graphics::draw_arc`].
+    pub fn draw_pie<P: Into<Position>>(
+        &mut self,
+        center: P,
+        radius: f32,
+        start_angle: f32,
+        end_angle: f32,
+        color: Color,
+    ) {
+        self.draw_ring_sector(center.into(), 0.0, radius, start_angle, end_angle, color);
+    }
+
+    /// Draws the sector as a ring with angle bounds, see [`Graphics::draw_ring`], so that it stays
+    /// crisp at any scale. Edges are only smoothed with [`CanvasSettings::smooth_shapes`].
+    fn draw_ring_sector(
+        &mut self,
+        center: Position,
+        inner: f32,
+        outer: f32,
+        start_angle: f32,
+        end_angle: f32,
+        color: Color,
+    ) {
+        let sweep = if end_angle - start_angle >= TAU {
+            TAU
+        } else {
+            (end_angle - start_angle).rem_euclid(TAU)
+        };
+        if sweep <= 0.0 || outer <= 0.0 {
+            return;
+        }
+
+        let smoothness = if self.texture_context.smooth_shapes {
+            1.0
+        } else {
+            0.0
+        };
+        let operation = self.draw_ring(center, inner, outer, color, smoothness);
+        operation.ring[2] = start_angle;
+        operation.ring[3] = sweep;
+    }
+
+    /// Draws the lines of a grid of `cell` sized cells, starting at the top left of `bounds` and
+    /// clipped to it.
+    pub fn draw_grid<S: Into<SizeInPx>>(&mut self, bounds: Rect, cell: S, stroke: Stroke) {
+        let line_width = self.stroke_width(stroke);
+        let color = stroke.color;
+        let cell: SizeInPx = cell.into();
+        if cell.width == 0 || cell.height == 0 || line_width <= 0.0 {
+            return;
+        }
+
+        let right = bounds.left + bounds.width;
+        let bottom = bounds.top + bounds.height;
+        let half_width = line_width / 2.0;
+        let mut rects = vec![];
+
+        let mut x = bounds.left;
+        while x <= right {
+            let left = (x - half_width).max(bounds.left);
+            let width = (x + half_width).min(right) - left;
+            rects.push((Rect::new(left, bounds.top, width, bounds.height), color));
+            x += cell.width as f32;
+        }
+
+        let mut y = bounds.top;
+        while y <= bottom {
+            let top = (y - half_width).max(bounds.top);
+            let height = (y + half_width).min(bottom) - top;
+            rects.push((Rect::new(bounds.left, top, bounds.width, height), color));
+            y += cell.height as f32;
+        }
+
+        self.draw_rects(&rects);
+    }
+
+    /// Draws many rects at once, in a single instanced draw as long as nothing else is drawn
+    /// in between. Their edges are smoothed like those of [`Graphics::draw_rect`], see
+    /// [`CanvasSettings::smooth_shapes`].
+    pub fn draw_rects(&mut self, rects: &[(Rect, Color)]) {
+        let flags = self.smooth_edges(INSTANCE_SMOOTH_X | INSTANCE_SMOOTH_Y);
+        let tex_coords = Rect::new(0.0, 0.0, 1.0, 1.0);
+        let transforms = self.current_transform();
+        let texture_context = self.texture_context;
+
+        let mut remaining = rects;
+        while !remaining.is_empty() {
+            let operation_block =
+                self.get_operation_block(&texture_context.white_texture, RenderState::default());
+            let room = OPERATION_CAPACITY - operation_block.operations.len();
+            let (chunk, rest) = remaining.split_at(room.min(remaining.len()));
+
+            for (rect, color) in chunk {
+                let mut operation = RenderOperation::new(
+                    *rect,
+                    tex_coords,
+                    ColorMatrix::from_color(*color),
+                    transforms,
+                );
+                operation.flags = flags;
+                operation_block.push_render_operation(operation);
+            }
+            remaining = rest;
+        }
+    }
+
+    /// Draws many copies of a sprite, converted straight to GPU instances without going through
+    /// [`RenderOperation`]s. Suits large amounts of particles.
+    pub fn draw_instances(&mut self, sprite: &Sprite, instances: &[InstanceDesc]) {
+        // Whatever was drawn before must stay below.
+        self.prepare_all_blocks();
+
+        if let Some(mut draw_data) = prepare_instances(
+            self.buffer_cache,
+            self.device,
+            self.queue,
+            sprite,
+            instances,
+            self.current_transform(),
+            self.opacity,
+            self.camera.clone(),
+        ) {
+            draw_data.viewport = self.viewport;
+            draw_data.layer = self.layer;
+            // Instances are never alpha tested, so with depth sorting they mustn't write the
+            // depth. Without it, they keep their place in the order of the calls.
+            draw_data.transparent = self.texture_context.depth_sorting;
+            self.prepared_operation_count += draw_data.count as usize;
+            self.draw_datas.push(draw_data);
+        }
+    }
+
+    /// Fills the rect with the color, replacing what was drawn there before instead of blending
+    /// with it, alpha included.
+    pub fn clear_rect<R: Into<Rect>>(&mut self, rect: R, color: Color) {
+        let tex_coords = Rect::new(0.0, 0.0, 1.0, 1.0);
+
+        let rect: Rect = rect.into();
+        let transforms = self.current_transform();
+        let color_matrix = ColorMatrix::from_color(color);
+
+        let operation = RenderOperation::new(rect, tex_coords, color_matrix, transforms);
+
+        let render_state = RenderState {
+            blend_mode: BlendMode::Replace,
+            ..Default::default()
+        };
+        self.get_operation_block(&self.texture_context.white_texture, render_state)
+            .push_render_operation(operation);
+    }
+
     pub fn draw_sprite<P: Into<Position>>(
         &mut self,
         sprite: &Sprite,
@@ -430,6 +1675,27 @@ impl<'a> Graphics<'a> {
         self.draw_sprite_in_rect(sprite, (position.into(), sprite.dimensions))
     }
 
+    /// Draws the sprite with its anchor at the position, for instance its center for entities
+    /// and particles. Rotating the operation still turns it around its center.
+    pub fn draw_sprite_anchored<P: Into<Position>>(
+        &mut self,
+        sprite: &Sprite,
+        position: P,
+        anchor: Anchor,
+    ) -> &mut RenderOperation {
+        let position: Position = position.into();
+        let (x, y) = anchor.fraction();
+        let width = sprite.dimensions.width as f32;
+        let height = sprite.dimensions.height as f32;
+        let rect = Rect::new(
+            position.left - width * x,
+            position.top - height * y,
+            width,
+            height,
+        );
+        self.draw_sprite_in_rect(sprite, rect)
+    }
+
     pub fn draw_sprite_in_rect<R: Into<Rect>>(
         &mut self,
         sprite: &Sprite,
@@ -440,52 +1706,797 @@ impl<'a> Graphics<'a> {
         let rect: Rect = rect.into();
         let transforms = self.current_transform();
         let color_matrix = DEFAULT_COLOR_MATRIX;
-        let operation = RenderOperation {
-            rect,
-            color_matrix,
-            tex_coords,
+        let mut operation = RenderOperation::new(rect, tex_coords, color_matrix, transforms);
+        operation.array_layer = sprite.array_layer;
+        self.get_operation_block(&sprite.texture, RenderState::default())
+            .push_render_operation(operation)
+    }
+
+    /// Draws the region `src` of the sprite, in pixels from its top left corner, to `dest`.
+    /// Handy for one-off regions of a big atlas sprite, without building a sprite for each.
+    /// `src` is clamped to the sprite.
+    pub fn draw_sprite_region<R: Into<Rect>>(
+        &mut self,
+        sprite: &Sprite,
+        src: Rect,
+        dest: R,
+    ) -> &mut RenderOperation {
+        let (width, height) = (
+            sprite.dimensions.width as f32,
+            sprite.dimensions.height as f32,
+        );
+        let left = src.left.clamp(0.0, width);
+        let top = src.top.clamp(0.0, height);
+        let right = (src.left + src.width).clamp(left, width);
+        let bottom = (src.top + src.height).clamp(top, height);
+
+        let tex_coords = sprite.tex_coords;
+        let tex_coords = Rect {
+            left: tex_coords.left + left / width.max(1.0) * tex_coords.width,
+            top: tex_coords.top + top / height.max(1.0) * tex_coords.height,
+            width: (right - left) / width.max(1.0) * tex_coords.width,
+            height: (bottom - top) / height.max(1.0) * tex_coords.height,
+        };
+
+        let transforms = self.current_transform();
+        let mut operation =
+            RenderOperation::new(dest.into(), tex_coords, DEFAULT_COLOR_MATRIX, transforms);
+        operation.array_layer = sprite.array_layer;
+        self.get_operation_block(&sprite.texture, RenderState::default())
+            .push_render_operation(operation)
+    }
+
+    /// Draws a panel of any size out of nine tiles of the tileset, given by index row by row:
+    /// the top left corner, the top edge, the top right corner, the left edge, the center, and
+    /// so on. Corners keep the size of a tile, shrunk if the panel is too small for them, while
+    /// the edges and the center fill the rest according to `mode`.
+    pub fn draw_sprite_nine_grid_from_tileset(
+        &mut self,
+        tileset: &TileSet,
+        tiles: [usize; 9],
+        dest: Rect,
+        mode: NineGridMode,
+    ) {
+        let tile_width = tileset.tile_dimensions.width as f32;
+        let tile_height = tileset.tile_dimensions.height as f32;
+        let corner_width = tile_width.min(dest.width / 2.0);
+        let corner_height = tile_height.min(dest.height / 2.0);
+
+        let columns = [
+            (dest.left, corner_width),
+            (dest.left + corner_width, dest.width - 2.0 * corner_width),
+            (dest.left + dest.width - corner_width, corner_width),
+        ];
+        let rows = [
+            (dest.top, corner_height),
+            (dest.top + corner_height, dest.height - 2.0 * corner_height),
+            (dest.top + dest.height - corner_height, corner_height),
+        ];
+
+        for (row, &(top, height)) in rows.iter().enumerate() {
+            for (column, &(left, width)) in columns.iter().enumerate() {
+                let sprite = match tileset.sprite_with_index(tiles[row * 3 + column]) {
+                    Some(sprite) if width > 0.0 && height > 0.0 => sprite,
+                    _ => continue,
+                };
+                let cell = Rect::new(left, top, width, height);
+                match mode {
+                    NineGridMode::Tile => {
+                        // Only the middle row and column repeat, corners are drawn once.
+                        let step_x = if column == 1 { tile_width } else { width };
+                        let step_y = if row == 1 { tile_height } else { height };
+                        self.draw_sprite_repeated(sprite, cell, step_x, step_y);
+                    }
+                    NineGridMode::Stretch => {
+                        self.draw_sprite_in_rect(sprite, cell);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Fills `rect` with copies of the sprite of `step_x` by `step_y` pixels, showing only part
+    /// of the sprite in the copies cut by the edges of `rect`. Unlike the repeating sampler of
+    /// [`Graphics::draw_sprite_tiled`], this works for sprites sharing a texture.
+    fn draw_sprite_repeated(&mut self, sprite: &Sprite, rect: Rect, step_x: f32, step_y: f32) {
+        let sprite_width = sprite.dimensions.width as f32;
+        let sprite_height = sprite.dimensions.height as f32;
+
+        let mut top = rect.top;
+        while top < rect.top + rect.height {
+            let height = step_y.min(rect.top + rect.height - top);
+            let mut left = rect.left;
+            while left < rect.left + rect.width {
+                let width = step_x.min(rect.left + rect.width - left);
+                let src = Rect::new(
+                    0.0,
+                    0.0,
+                    sprite_width * width / step_x,
+                    sprite_height * height / step_y,
+                );
+                self.draw_sprite_region(sprite, src, Rect::new(left, top, width, height));
+                left += step_x;
+            }
+            top += step_y;
+        }
+    }
+
+    /// Draws a sprite with its own filtering, like a smoothly scaled background among pixel art
+    /// sprites. Only nearest and linear filtering are available, without repeating.
+    pub fn draw_sprite_filtered<R: Into<Rect>>(
+        &mut self,
+        sprite: &Sprite,
+        rect: R,
+        filter: wgpu::FilterMode,
+    ) -> &mut RenderOperation {
+        let sampler_kind = match filter {
+            wgpu::FilterMode::Nearest => SamplerKind::Nearest,
+            wgpu::FilterMode::Linear => SamplerKind::Linear,
+        };
+        sprite
+            .texture
+            .prepare_bind_group(self.device, self.texture_context, sampler_kind);
+
+        let transforms = self.current_transform();
+        let mut operation = RenderOperation::new(
+            rect.into(),
+            sprite.tex_coords,
+            DEFAULT_COLOR_MATRIX,
             transforms,
+        );
+        operation.array_layer = sprite.array_layer;
+        let render_state = RenderState {
+            sampler_kind,
+            ..Default::default()
         };
-        self.get_operation_block(&sprite.texture)
+        self.get_operation_block(&sprite.texture, render_state)
+            .push_render_operation(operation)
+    }
+
+    /// Draws a sprite at its size multiplied by `scale`, rotated by `rotation` radians around its
+    /// center.
+    pub fn draw_sprite_ex<P: Into<Position>>(
+        &mut self,
+        sprite: &Sprite,
+        position: P,
+        scale: f32,
+        rotation: f32,
+    ) -> &mut RenderOperation {
+        let position: Position = position.into();
+        let rect = Rect::new(
+            position.left,
+            position.top,
+            sprite.dimensions.width as f32 * scale,
+            sprite.dimensions.height as f32 * scale,
+        );
+        self.draw_sprite_in_rect(sprite, rect).rotate(rotation)
+    }
+
+    /// Draws the `tex_coords` part of the texture, in normalized coordinates, stretched over `dest`.
+    pub fn draw_texture<R: Into<Rect>>(
+        &mut self,
+        texture: &ExternalTexture,
+        dest: R,
+        tex_coords: Rect,
+    ) -> &mut RenderOperation {
+        let transforms = self.current_transform();
+        let operation =
+            RenderOperation::new(dest.into(), tex_coords, DEFAULT_COLOR_MATRIX, transforms);
+        self.get_operation_block(&texture.texture, RenderState::default())
+            .push_render_operation(operation)
+    }
+
+    /// Fills `dest` with the color, using the mask as its coverage.
+    pub fn draw_mask<R: Into<Rect>>(
+        &mut self,
+        mask: &Mask,
+        dest: R,
+        color: Color,
+    ) -> &mut RenderOperation {
+        let tex_coords = Rect::new(0.0, 0.0, 1.0, 1.0);
+        let transforms = self.current_transform();
+        let operation = RenderOperation::new(
+            dest.into(),
+            tex_coords,
+            ColorMatrix::for_text(color),
+            transforms,
+        );
+        self.get_operation_block(&mask.texture, RenderState::default())
             .push_render_operation(operation)
     }
 
+    /// Fills `rect` with copies of the sprite of `tile_size` pixels, for grounds and backgrounds.
+    /// Sprites that are their whole texture are drawn as a single operation, with a repeating
+    /// sampler, which is returned. Sprites from an atlas or a [`TileSet`] share their texture, so
+    /// they are drawn as one operation per copy instead, and `None` is returned.
+    pub fn draw_sprite_tiled<R, S>(
+        &mut self,
+        sprite: &Sprite,
+        rect: R,
+        tile_size: S,
+    ) -> Option<&mut RenderOperation>
+    where
+        R: Into<Rect>,
+        S: Into<SizeInPx>,
+    {
+        let rect: Rect = rect.into();
+        let tile_size: SizeInPx = tile_size.into();
+        if !sprite.covers_texture() {
+            // The repeating sampler would wrap around the whole texture.
+            let step_x = tile_size.width.max(1) as f32;
+            let step_y = tile_size.height.max(1) as f32;
+            self.draw_sprite_repeated(sprite, rect, step_x, step_y);
+            return None;
+        }
+
+        let tex_coords = Rect {
+            left: sprite.tex_coords.left,
+            top: sprite.tex_coords.top,
+            width: sprite.tex_coords.width * rect.width / tile_size.width.max(1) as f32,
+            height: sprite.tex_coords.height * rect.height / tile_size.height.max(1) as f32,
+        };
+
+        let transforms = self.current_transform();
+        let color_matrix = DEFAULT_COLOR_MATRIX;
+        let mut operation = RenderOperation::new(rect, tex_coords, color_matrix, transforms);
+        operation.array_layer = sprite.array_layer;
+        let render_state = RenderState {
+            sampler_kind: SamplerKind::Repeat,
+            ..Default::default()
+        };
+        Some(
+            self.get_operation_block(&sprite.texture, render_state)
+                .push_render_operation(operation),
+        )
+    }
+
     pub fn draw_text<T, P>(&mut self, font: &mut Font, text: T, px: u32, position: P, color: Color)
     where
         T: AsRef<str>,
         P: Into<Position>,
     {
+        self.draw_text_with_options(font, text, px, position, color, &TextOptions::default());
+    }
+
+    pub fn draw_text_with_options<T, P>(
+        &mut self,
+        font: &mut Font,
+        text: T,
+        px: u32,
+        position: P,
+        color: Color,
+        options: &TextOptions,
+    ) where
+        T: AsRef<str>,
+        P: Into<Position>,
+    {
+        let text: Cow<str> = match options.truncate {
+            Some(max_width) => {
+                let text = expand_tabs(text.as_ref(), options.tab_width_spaces);
+                Cow::Owned(font.truncate(&text, px, max_width).into_owned())
+            }
+            None => Cow::Borrowed(text.as_ref()),
+        };
+        if text.is_empty() {
+            return;
+        }
+        let text = text.as_ref();
         let position = position.into();
 
         let transforms = self.current_transform();
-        let font_for_px = font.get_font_for_px(px);
-        let mut operations = self.text_converter.render_operation(
-            text.as_ref(),
-            color,
-            position,
-            &font_for_px,
-            transforms,
-            self.device,
-            self.queue,
-            self.texture_context,
+        let snap_to_pixel = font.snap_to_pixel;
+        let render_state = RenderState {
+            blend_mode: options.blend_mode,
+            ..Default::default()
+        };
+        let lines = match &mut font.kind {
+            FontKind::TrueType(true_type_font) => {
+                let font_for_px = true_type_font.get_font_for_px(px);
+                let operations = self.text_converter.render_operation(
+                    text,
+                    px,
+                    color,
+                    position,
+                    &font_for_px,
+                    transforms,
+                    snap_to_pixel,
+                    options,
+                    self.device,
+                    self.queue,
+                    self.texture_context,
+                );
+
+                // Glyphs can be spread over several atlas pages, each with its own texture.
+                for (texture, operation) in operations {
+                    self.get_operation_block(&texture, render_state)
+                        .push_render_operation(operation);
+                }
+                self.text_converter.lines()
+            }
+            FontKind::Bitmap(bitmap_font) => {
+                let (mut operations, lines) = bitmap_font.render_operations(
+                    text,
+                    px,
+                    color,
+                    position,
+                    transforms,
+                    snap_to_pixel,
+                    options,
+                );
+                self.get_operation_block(&bitmap_font.texture, render_state)
+                    .append_render_operations(&mut operations);
+                lines
+            }
+        };
+
+        self.draw_text_decorations(font, px, color, options, &lines);
+    }
+
+    /// Draws many strings with the same font, as `(text, px, position, color)`, like damage
+    /// numbers or labels, in order, later ones on top. Consecutive strings of the same size share
+    /// an atlas, so they are laid out together and batched, instead of switching blocks on every
+    /// call.
+    pub fn draw_texts(&mut self, font: &mut Font, items: &[(String, u32, Position, Color)]) {
+        let items = items.iter().filter(|(text, ..)| !text.is_empty());
+
+        let options = TextOptions::default();
+        let transforms = self.current_transform();
+        let snap_to_pixel = font.snap_to_pixel;
+        match &mut font.kind {
+            FontKind::TrueType(true_type_font) => {
+                let mut current = None;
+                let mut operations = vec![];
+                for (text, px, position, color) in items {
+                    if !matches!(&current, Some((current_px, _)) if current_px == px) {
+                        current = Some((*px, true_type_font.get_font_for_px(*px)));
+                    }
+                    let (_, font_for_px) = current.as_ref().unwrap();
+                    operations.extend(self.text_converter.render_operation(
+                        text,
+                        *px,
+                        *color,
+                        *position,
+                        font_for_px,
+                        transforms,
+                        snap_to_pixel,
+                        &options,
+                        self.device,
+                        self.queue,
+                        self.texture_context,
+                    ));
+                }
+
+                for (texture, operation) in operations {
+                    self.get_operation_block(&texture, RenderState::default())
+                        .push_render_operation(operation);
+                }
+            }
+            FontKind::Bitmap(bitmap_font) => {
+                let mut operations = vec![];
+                for (text, px, position, color) in items {
+                    let (mut item_operations, _) = bitmap_font.render_operations(
+                        text,
+                        *px,
+                        *color,
+                        *position,
+                        transforms,
+                        snap_to_pixel,
+                        &options,
+                    );
+                    operations.append(&mut item_operations);
+                }
+                self.get_operation_block(&bitmap_font.texture, RenderState::default())
+                    .append_render_operations(&mut operations);
+            }
+        }
+    }
+
+    /// Draws text with a color going from `top_color` to `bottom_color` across the height of the
+    /// whole text. Glyphs are drawn in thin slices, each with its own color.
+    pub fn draw_text_gradient<T, P>(
+        &mut self,
+        font: &mut Font,
+        text: T,
+        px: u32,
+        position: P,
+        top_color: Color,
+        bottom_color: Color,
+    ) where
+        T: AsRef<str>,
+        P: Into<Position>,
+    {
+        let position = position.into();
+        let transforms = self.current_transform();
+        let white = Color::rgb(1.0, 1.0, 1.0);
+
+        // The TrueType atlas only holds coverage, while bitmap fonts hold the final colors.
+        let color_matrix_for: fn(Color) -> ColorMatrix = match font.kind {
+            FontKind::TrueType(_) => ColorMatrix::for_text,
+            FontKind::Bitmap(_) => ColorMatrix::from_color,
+        };
+        let operations =
+            self.untransformed_text_operations(font, text.as_ref(), px, position, white);
+
+        let (top, bottom) =
+            operations
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(top, bottom), (_, operation)| {
+                    let y = operation.transforms.affine.translation.y;
+                    (top.min(y), bottom.max(y + operation.rect.height))
+                });
+        let height = (bottom - top).max(1.0);
+
+        for (texture, operation) in operations {
+            let Vec2 { x, y } = operation.transforms.affine.translation;
+            let (rect, tex_coords) = (operation.rect, operation.tex_coords);
+
+            let mut band_top = 0.0;
+            while band_top < rect.height {
+                let band_height = GRADIENT_BAND_HEIGHT.min(rect.height - band_top);
+                let progress = ((y + band_top + band_height / 2.0 - top) / height).clamp(0.0, 1.0);
+                let color = Color::rgba(
+                    top_color.r + (bottom_color.r - top_color.r) * progress,
+                    top_color.g + (bottom_color.g - top_color.g) * progress,
+                    top_color.b + (bottom_color.b - top_color.b) * progress,
+                    top_color.a + (bottom_color.a - top_color.a) * progress,
+                );
+
+                let band_tex_coords = Rect::new(
+                    tex_coords.left,
+                    tex_coords.top + tex_coords.height * band_top / rect.height,
+                    tex_coords.width,
+                    tex_coords.height * band_height / rect.height,
+                );
+                let mut band = RenderOperation::new(
+                    Rect::new(0.0, 0.0, rect.width, band_height),
+                    band_tex_coords,
+                    color_matrix_for(color),
+                    transforms * Transform::from_translation(x, y + band_top),
+                );
+                band.flags = operation.flags;
+                self.get_operation_block(&texture, RenderState::default())
+                    .push_render_operation(band);
+
+                band_top += band_height;
+            }
+        }
+    }
+
+    /// Draws the text aligned within `rect`, cutting the glyphs that overflow it. Lines are
+    /// aligned as a block. Returns the part of `rect` the text covers.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_text_in_rect<T: AsRef<str>>(
+        &mut self,
+        font: &mut Font,
+        text: T,
+        px: u32,
+        rect: Rect,
+        h_align: HorizontalAlign,
+        v_align: VerticalAlign,
+        color: Color,
+    ) -> Rect {
+        let text = text.as_ref();
+        let (width, height) = font.measure_text(text, px);
+        let left = match h_align {
+            HorizontalAlign::Left => rect.left,
+            HorizontalAlign::Center => rect.left + (rect.width - width) / 2.0,
+            HorizontalAlign::Right => rect.left + rect.width - width,
+        };
+        let top = match v_align {
+            VerticalAlign::Top => rect.top,
+            VerticalAlign::Center => rect.top + (rect.height - height) / 2.0,
+            VerticalAlign::Bottom => rect.top + rect.height - height,
+        };
+        let right = rect.left + rect.width;
+        let bottom = rect.top + rect.height;
+
+        let transforms = self.current_transform();
+        let operations =
+            self.untransformed_text_operations(font, text, px, Position::new(left, top), color);
+        for (texture, mut operation) in operations {
+            let Vec2 { x, y } = operation.transforms.affine.translation;
+            let (glyph, tex_coords) = (operation.rect, operation.tex_coords);
+
+            let clipped_left = x.max(rect.left);
+            let clipped_top = y.max(rect.top);
+            let clipped_width = (x + glyph.width).min(right) - clipped_left;
+            let clipped_height = (y + glyph.height).min(bottom) - clipped_top;
+            if clipped_width <= 0.0 || clipped_height <= 0.0 {
+                continue;
+            }
+
+            operation.tex_coords = Rect::new(
+                tex_coords.left + tex_coords.width * (clipped_left - x) / glyph.width,
+                tex_coords.top + tex_coords.height * (clipped_top - y) / glyph.height,
+                tex_coords.width * clipped_width / glyph.width,
+                tex_coords.height * clipped_height / glyph.height,
+            );
+            operation.rect = Rect::new(0.0, 0.0, clipped_width, clipped_height);
+            operation.transforms =
+                transforms * Transform::from_translation(clipped_left, clipped_top);
+            self.get_operation_block(&texture, RenderState::default())
+                .push_render_operation(operation);
+        }
+
+        let drawn_left = left.max(rect.left);
+        let drawn_top = top.max(rect.top);
+        Rect::new(
+            drawn_left,
+            drawn_top,
+            ((left + width).min(right) - drawn_left).max(0.0),
+            ((top + height).min(bottom) - drawn_top).max(0.0),
+        )
+    }
+
+    /// Lays out the text without the current transforms, so that the translation of each glyph
+    /// is its position, for callers that want to cut the glyphs before drawing them.
+    fn untransformed_text_operations(
+        &mut self,
+        font: &mut Font,
+        text: &str,
+        px: u32,
+        position: Position,
+        color: Color,
+    ) -> Vec<(Shared<Texture>, RenderOperation)> {
+        let snap_to_pixel = font.snap_to_pixel;
+        let options = TextOptions::default();
+        match &mut font.kind {
+            FontKind::TrueType(true_type_font) => {
+                let font_for_px = true_type_font.get_font_for_px(px);
+                self.text_converter.render_operation(
+                    text,
+                    px,
+                    color,
+                    position,
+                    &font_for_px,
+                    Transform::new(),
+                    snap_to_pixel,
+                    &options,
+                    self.device,
+                    self.queue,
+                    self.texture_context,
+                )
+            }
+            FontKind::Bitmap(bitmap_font) => {
+                let (operations, _) = bitmap_font.render_operations(
+                    text,
+                    px,
+                    color,
+                    position,
+                    Transform::new(),
+                    snap_to_pixel,
+                    &options,
+                );
+                operations
+                    .into_iter()
+                    .map(|operation| (bitmap_font.texture.clone(), operation))
+                    .collect()
+            }
+        }
+    }
+
+    /// Draws text made of several spans, each with its own color and size.
+    pub fn draw_rich_text<P: Into<Position>>(
+        &mut self,
+        font: &mut Font,
+        text: &RichText,
+        position: P,
+    ) {
+        let position = position.into();
+        let transforms = self.current_transform();
+        let snap_to_pixel = font.snap_to_pixel;
+        let options = TextOptions::default();
+
+        match &mut font.kind {
+            FontKind::TrueType(true_type_font) => {
+                let spans: Vec<SpanLayout> = text
+                    .spans
+                    .iter()
+                    .map(|span| SpanLayout {
+                        text: &span.text,
+                        px: text.span_px(span),
+                        color: span.color,
+                        font_for_px: true_type_font.get_font_for_px(text.span_px(span)),
+                    })
+                    .collect();
+                let operations = self.text_converter.render_spans(
+                    &spans,
+                    position,
+                    text.max_width,
+                    transforms,
+                    snap_to_pixel,
+                    &options,
+                    self.device,
+                    self.queue,
+                    self.texture_context,
+                );
+
+                for (texture, operation) in operations {
+                    self.get_operation_block(&texture, RenderState::default())
+                        .push_render_operation(operation);
+                }
+            }
+            FontKind::Bitmap(bitmap_font) => {
+                let spans: Vec<(&str, Color, u32)> = text
+                    .spans
+                    .iter()
+                    .map(|span| (span.text.as_str(), span.color, text.span_px(span)))
+                    .collect();
+                let (mut operations, _) = bitmap_font.render_spans(
+                    &spans,
+                    position,
+                    text.max_width,
+                    transforms,
+                    snap_to_pixel,
+                    &options,
+                );
+                self.get_operation_block(&bitmap_font.texture, RenderState::default())
+                    .append_render_operations(&mut operations);
+            }
+        }
+    }
+
+    fn draw_text_decorations(
+        &mut self,
+        font: &Font,
+        px: u32,
+        color: Color,
+        options: &TextOptions,
+        lines: &[TextLine],
+    ) {
+        let (underline_offset, strikethrough_offset, thickness) = font.decoration_metrics(px);
+        let color = options.decoration_color.unwrap_or(color);
+
+        let offsets = [
+            (options.underline, underline_offset),
+            (options.strikethrough, strikethrough_offset),
+        ];
+        let rects: Vec<(Rect, Color)> = lines
+            .iter()
+            .flat_map(|line| {
+                offsets
+                    .iter()
+                    .filter(|(enabled, _)| *enabled)
+                    .map(|(_, offset)| {
+                        let mut top = line.baseline + offset - thickness / 2.0;
+                        if font.snap_to_pixel {
+                            top = top.round();
+                        }
+                        let rect = Rect::new(line.left, top, line.right - line.left, thickness);
+                        (rect, color)
+                    })
+            })
+            .collect();
+        self.draw_rects(&rects);
+    }
+
+    /// Draws with another camera, created with [`Canvas::create_camera`], for instance to
+    /// scroll a world layer on its own.
+    pub fn with_camera<F>(&mut self, camera: &mut Camera, function: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        camera.set_size(self.size.width, self.size.height);
+        let used = self
+            .used_cameras
+            .iter()
+            .any(|bind_group| Rc::ptr_eq(bind_group, &camera.camera_bind_group));
+        let bind_group = if used {
+            // Rewriting its buffer would move what was already drawn with it.
+            camera.snapshot(self.camera_snapshots, self.device, self.queue)
+        } else {
+            if camera.dirty {
+                camera.recalculate(self.queue);
+            }
+            self.used_cameras.push(camera.camera_bind_group.clone());
+            camera.camera_bind_group.clone()
+        };
+
+        self.with_camera_bind_group(
+            Some(bind_group),
+            camera.effective_scale(),
+            camera.visible_rect(),
+            function,
         );
+    }
 
-        let texture = font_for_px
-            .borrow_mut()
-            .get_or_create_texture(self.device, self.texture_context);
-        self.get_operation_block(&texture)
-            .operations
-            .append(&mut operations);
+    /// Draws with the scale of the canvas but without its translation, which suits UI drawn on
+    /// top of a scrolling scene.
+    pub fn with_ui_camera<F>(&mut self, function: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        // The UI camera only differs from the main one by its translation.
+        self.with_camera_bind_group(
+            Some(self.ui_camera.clone()),
+            self.main_scale,
+            self.ui_visible_rect,
+            function,
+        );
     }
 
-    pub fn with_translation<F>(&mut self, translation: Position, function: F)
+    /// Draws with `camera` into `viewport`, a part of the surface in pixels, for split screens or
+    /// minimaps. The camera is sized to the viewport, so its scale and translation work as they
+    /// would for the whole surface, and drawing is clipped to the viewport. It can be called for
+    /// several viewports in the same frame, even with the same scene and the same camera.
+    ///
+    /// The frame is only cleared once, before everything: a viewport that needs its own
+    /// background fills it first, with [`Graphics::clear_rect`] on [`Graphics::visible_rect`] for
+    /// instance. [`Graphics::with_ui_camera`] still draws for the whole surface.
+    pub fn draw_viewport<R, F>(&mut self, viewport: R, camera: &mut Camera, function: F)
     where
+        R: Into<Rect>,
         F: FnOnce(&mut Self),
     {
-        let mut transform = self.current_transform();
-        transform.translate(translation.left, translation.top);
+        let viewport: Rect = viewport.into();
+        let size = SizeInPx {
+            width: viewport.width.round().max(1.0) as u32,
+            height: viewport.height.round().max(1.0) as u32,
+        };
 
-        self.transforms.push(transform);
+        let previous_size = std::mem::replace(&mut self.size, size);
+        let previous_viewport = self.viewport.replace(viewport);
+        self.with_camera(camera, function);
+        self.size = previous_size;
+        self.viewport = previous_viewport;
+    }
+
+    /// Draws on another layer, the default one being 0. Layers are drawn from the lowest to the
+    /// highest whatever the order of the calls, so an overlay can be drawn on a higher layer as
+    /// the scene goes. Within a layer, things are drawn in the order of the calls.
+    pub fn with_layer<F>(&mut self, layer: i32, function: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        let previous_layer = std::mem::replace(&mut self.layer, layer);
+        function(self);
+        self.layer = previous_layer;
+    }
+
+    /// Multiplies the alpha of everything drawn in the function, text included, for instance to
+    /// fade a whole part of the scene. Nested calls multiply their opacities.
+    pub fn with_opacity<F>(&mut self, alpha: f32, function: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        let previous_opacity = self.opacity;
+        self.opacity *= alpha.clamp(0.0, 1.0);
+        function(self);
+        self.opacity = previous_opacity;
+    }
+
+    fn with_camera_bind_group<F>(
+        &mut self,
+        camera: Option<Rc<BindGroup>>,
+        scale: f32,
+        visible_rect: Rect,
+        function: F,
+    ) where
+        F: FnOnce(&mut Self),
+    {
+        let previous_camera = std::mem::replace(&mut self.camera, camera);
+        let previous_scale = std::mem::replace(&mut self.scale, scale);
+        let previous_visible_rect = std::mem::replace(&mut self.visible_rect, visible_rect);
+        function(self);
+        self.camera = previous_camera;
+        self.scale = previous_scale;
+        self.visible_rect = previous_visible_rect;
+    }
+
+    fn stroke_width(&self, stroke: Stroke) -> f32 {
+        if stroke.screen_space {
+            stroke.width / self.scale
+        } else {
+            stroke.width
+        }
+    }
+
+    pub fn with_translation<F>(&mut self, translation: Position, function: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        self.transforms
+            .push(|transform| transform.translate(translation.left, translation.top));
         function(self);
         self.transforms.pop();
     }
@@ -494,38 +2505,74 @@ impl<'a> Graphics<'a> {
     where
         F: FnOnce(&mut Self),
     {
-        let mut transform = self.current_transform();
-        transform.rotate(angle);
-
-        self.transforms.push(transform);
+        self.transforms.push(|transform| transform.rotate(angle));
         function(self);
         self.transforms.pop();
     }
 
+    /// The size of the target, in pixels.
     pub fn size(&self) -> SizeInPx {
         self.size
     }
 
-    fn get_operation_block(&mut self, texture: &Rc<Texture>) -> &mut OperationBlock {
-        let need_new = !matches!(&self.current_operation_block, Some(operation_block) if operation_block.texture.id == texture.id && operation_block.operations.len() < OPERATION_CAPACITY);
-        if need_new {
+    /// The part of the world drawn with the current camera, the canonical bounds for culling.
+    /// Transforms pushed with [`Graphics::with_translation`] and the like are not accounted for.
+    pub fn visible_rect(&self) -> Rect {
+        self.visible_rect
+    }
+
+    /// How many operations were drawn so far this frame, instances included.
+    pub fn operation_count(&self) -> usize {
+        self.prepared_operation_count
+            + self.open_blocks.operation_count()
+            + self.transparent_operations.len()
+    }
+
+    /// Whether nothing was drawn so far this frame.
+    pub fn is_empty(&self) -> bool {
+        self.operation_count() == 0
+    }
+
+    /// The width and height of the world visible through the current camera, the size of
+    /// [`Graphics::visible_rect`]. Letterbox bars are left out.
+    pub fn world_size(&self) -> (f32, f32) {
+        (self.visible_rect.width, self.visible_rect.height)
+    }
+
+    fn get_operation_block(
+        &mut self,
+        texture: &Shared<Texture>,
+        render_state: RenderState,
+    ) -> &mut OperationBlock {
+        let layer = self.layer;
+        self.open_blocks.switch_to_layer(layer);
+
+        let need_new = !matches!(&self.open_blocks.current, Some(operation_block) if operation_block.accepts(texture, render_state, &self.camera, self.viewport, layer));
+        let opacity = self.opacity;
+        let operation_block = if need_new {
+            let camera = self.camera.clone();
+            let viewport = self.viewport;
             let new_block = if let Some(previous_block) = self.prepare_current_block() {
-                previous_block.reuse(texture.clone())
+                previous_block.reuse(texture.clone(), render_state, camera, viewport, layer)
             } else {
-                OperationBlock::new(texture.clone())
+                OperationBlock::new(texture.clone(), render_state, camera, viewport, layer)
             };
 
-            self.current_operation_block.insert(new_block)
+            self.open_blocks.current.insert(new_block)
         } else {
-            self.current_operation_block.as_mut().unwrap()
-        }
+            self.open_blocks.current.as_mut().unwrap()
+        };
+        operation_block.opacity = opacity;
+        operation_block
     }
 
     fn prepare_current_block(&mut self) -> Option<OperationBlock> {
-        if let Some(operation_block) = self.current_operation_block.take() {
+        if let Some(mut operation_block) = self.open_blocks.current.take() {
+            self.set_aside_transparent_operations(&mut operation_block);
             if let Some(draw_data) =
                 prepare_draw_data(self.buffer_cache, self.device, self.queue, &operation_block)
             {
+                self.prepared_operation_count += draw_data.count as usize;
                 self.draw_datas.push(draw_data);
             }
 
@@ -535,16 +2582,104 @@ impl<'a> Graphics<'a> {
         }
     }
 
-    fn current_transform(&self) -> Transform {
-        if let Some(last) = self.transforms.last() {
-            *last
-        } else {
-            Transform::default()
+    fn prepare_all_blocks(&mut self) {
+        self.prepare_current_block();
+        for mut operation_block in std::mem::take(&mut self.open_blocks.parked) {
+            self.set_aside_transparent_operations(&mut operation_block);
+            if let Some(draw_data) =
+                prepare_draw_data(self.buffer_cache, self.device, self.queue, &operation_block)
+            {
+                self.prepared_operation_count += draw_data.count as usize;
+                self.draw_datas.push(draw_data);
+            }
+        }
+    }
+
+    /// With depth sorting, keeps the opaque operations in the block, and sets the transparent ones
+    /// aside to sort them once everything is drawn.
+    fn set_aside_transparent_operations(&mut self, operation_block: &mut OperationBlock) {
+        if !self.texture_context.depth_sorting {
+            return;
+        }
+
+        let operations = std::mem::replace(
+            &mut operation_block.operations,
+            Vec::with_capacity(OPERATION_CAPACITY),
+        );
+        for mut operation in operations {
+            if operation.is_transparent() {
+                self.transparent_operations.push(TransparentOperation {
+                    texture: operation_block.texture.clone(),
+                    render_state: operation_block.render_state,
+                    camera: operation_block.camera.clone(),
+                    viewport: operation_block.viewport,
+                    layer: operation_block.layer,
+                    operation,
+                });
+            } else {
+                operation.flags |= INSTANCE_ALPHA_TEST;
+                operation_block.operations.push(operation);
+            }
         }
     }
+
+    /// Batches the transparent operations set aside, from the farthest to the closest.
+    fn prepare_transparent_operations(&mut self) {
+        let mut operations = std::mem::take(&mut self.transparent_operations);
+        // Stable, so that operations at the same z keep the order of the calls.
+        operations.sort_by(|a, b| back_to_front(&a.operation, &b.operation));
+
+        let mut current_block: Option<OperationBlock> = None;
+        for operation in operations {
+            let TransparentOperation {
+                texture,
+                render_state,
+                camera,
+                viewport,
+                layer,
+                operation,
+            } = operation;
+            if !matches!(&current_block, Some(operation_block) if operation_block.accepts(&texture, render_state, &camera, viewport, layer))
+            {
+                if let Some(operation_block) = current_block.take() {
+                    self.push_transparent_block(&operation_block);
+                }
+                current_block = Some(OperationBlock::new(
+                    texture,
+                    render_state,
+                    camera,
+                    viewport,
+                    layer,
+                ));
+            }
+            if let Some(operation_block) = current_block.as_mut() {
+                operation_block.push_render_operation(operation);
+            }
+        }
+        if let Some(operation_block) = current_block {
+            self.push_transparent_block(&operation_block);
+        }
+    }
+
+    fn push_transparent_block(&mut self, operation_block: &OperationBlock) {
+        if let Some(mut draw_data) =
+            prepare_draw_data(self.buffer_cache, self.device, self.queue, operation_block)
+        {
+            draw_data.transparent = true;
+            self.prepared_operation_count += draw_data.count as usize;
+            self.draw_datas.push(draw_data);
+        }
+    }
+
+    /// The transform of the current `with_*` scopes, composed, to convert positions drawn in
+    /// them to positions around them with [`Transform::transform_point`].
+    pub fn current_transform(&self) -> Transform {
+        self.transforms.current()
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Rect {
     pub left: f32,
     pub top: f32,
@@ -579,6 +2714,43 @@ impl Rect {
             height: self.height,
         }
     }
+
+    /// Multiplies the size by `factor`, keeping the same center.
+    pub fn scaled(&self, factor: f32) -> Self {
+        self.resized(self.width * factor, self.height * factor)
+    }
+
+    /// Grows the rect by `dx` on the left and on the right, and by `dy` on the top and on the
+    /// bottom. Negative values shrink it, down to nothing at its center.
+    pub fn inflated(&self, dx: f32, dy: f32) -> Self {
+        self.resized(self.width + 2.0 * dx, self.height + 2.0 * dy)
+    }
+
+    /// The part of the rect inside `bounds`. Without overlap, it's empty, on the closest edge of
+    /// `bounds`.
+    pub fn clamped_to(&self, bounds: Rect) -> Self {
+        let right = bounds.left + bounds.width;
+        let bottom = bounds.top + bounds.height;
+        let left = self.left.clamp(bounds.left, right);
+        let top = self.top.clamp(bounds.top, bottom);
+
+        Self {
+            left,
+            top,
+            width: ((self.left + self.width).min(right) - left).max(0.0),
+            height: ((self.top + self.height).min(bottom) - top).max(0.0),
+        }
+    }
+
+    fn resized(&self, width: f32, height: f32) -> Self {
+        let (width, height) = (width.max(0.0), height.max(0.0));
+        Self {
+            left: self.left + (self.width - width) / 2.0,
+            top: self.top + (self.height - height) / 2.0,
+            width,
+            height,
+        }
+    }
 }
 
 impl From<[i32; 4]> for Rect {
@@ -603,6 +2775,12 @@ impl From<[f32; 4]> for Rect {
     }
 }
 
+impl From<Rect> for [f32; 4] {
+    fn from(rect: Rect) -> Self {
+        [rect.left, rect.top, rect.width, rect.height]
+    }
+}
+
 impl From<(Position, SizeInPx)> for Rect {
     fn from((position, size): (Position, SizeInPx)) -> Self {
         Rect::new(
@@ -614,7 +2792,144 @@ impl From<(Position, SizeInPx)> for Rect {
     }
 }
 
+#[cfg(feature = "glam")]
+impl From<(Vec2, Vec2)> for Rect {
+    fn from((position, size): (Vec2, Vec2)) -> Self {
+        Rect::new(position.x, position.y, size.x, size.y)
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<Rect> for (Vec2, Vec2) {
+    fn from(rect: Rect) -> Self {
+        (
+            Vec2::new(rect.left, rect.top),
+            Vec2::new(rect.width, rect.height),
+        )
+    }
+}
+
+/// The width and color of the lines drawn by [`Graphics::draw_line`], [`Graphics::draw_arc`] and
+/// [`Graphics::draw_grid`].
 #[derive(Clone, Copy, Debug)]
+pub struct Stroke {
+    /// In world units, so it grows and shrinks with the camera scale.
+    pub width: f32,
+    pub color: Color,
+    /// Keeps the width in pixels whatever the camera scale, which UI usually wants.
+    pub screen_space: bool,
+}
+
+impl Stroke {
+    pub fn new(width: f32, color: Color) -> Self {
+        Self {
+            width,
+            color,
+            screen_space: false,
+        }
+    }
+
+    pub fn screen_space(width: f32, color: Color) -> Self {
+        Self {
+            width,
+            color,
+            screen_space: true,
+        }
+    }
+}
+
+/// How [`Graphics::draw_line`] fills the line.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum LineStyle {
+    #[default]
+    Solid,
+    /// Dashes of `dash` pixels, separated by `gap` pixels, starting with a dash at the start of
+    /// each line. Past 4096 dashes on a line, they are stretched to keep that many.
+    Dashed { dash: f32, gap: f32 },
+    /// Square dots the size of the thickness, as far apart.
+    Dotted,
+}
+
+/// The point of a sprite placed at the position by [`Graphics::draw_sprite_anchored`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+    /// A fraction of the width and of the height, from the top left corner.
+    Custom(f32, f32),
+}
+
+impl Anchor {
+    fn fraction(self) -> (f32, f32) {
+        match self {
+            Anchor::TopLeft => (0.0, 0.0),
+            Anchor::TopRight => (1.0, 0.0),
+            Anchor::BottomLeft => (0.0, 1.0),
+            Anchor::BottomRight => (1.0, 1.0),
+            Anchor::Center => (0.5, 0.5),
+            Anchor::Custom(x, y) => (x, y),
+        }
+    }
+}
+
+/// How [`Graphics::draw_sprite_nine_grid_from_tileset`] fills the edges and the center.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum NineGridMode {
+    /// Stretches each piece over its part of the panel.
+    #[default]
+    Stretch,
+    /// Repeats each piece at the size of a tile, cutting the last one.
+    Tile,
+}
+
+/// Where [`Graphics::draw_text_in_rect`] puts the text horizontally.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum HorizontalAlign {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// Where [`Graphics::draw_text_in_rect`] puts the text vertically.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum VerticalAlign {
+    #[default]
+    Top,
+    Center,
+    Bottom,
+}
+
+/// One copy of a sprite drawn with [`Graphics::draw_instances`].
+#[derive(Clone, Copy, Debug)]
+pub struct InstanceDesc {
+    /// The top left corner, before rotation.
+    pub position: Position,
+    /// Around the center of the scaled sprite.
+    pub rotation: f32,
+    pub scale: f32,
+    pub alpha: f32,
+    pub tint: Color,
+}
+
+impl Default for InstanceDesc {
+    fn default() -> Self {
+        Self {
+            position: Position::new(0.0, 0.0),
+            rotation: 0.0,
+            scale: 1.0,
+            alpha: 1.0,
+            tint: Color::rgb(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Position {
     pub left: f32,
     pub top: f32,
@@ -639,7 +2954,61 @@ impl From<(f32, f32)> for Position {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+impl From<(i32, i32)> for Position {
+    fn from((left, top): (i32, i32)) -> Self {
+        Self {
+            left: left as f32,
+            top: top as f32,
+        }
+    }
+}
+
+impl From<[f32; 2]> for Position {
+    fn from([left, top]: [f32; 2]) -> Self {
+        Self { left, top }
+    }
+}
+
+impl From<[i32; 2]> for Position {
+    fn from([left, top]: [i32; 2]) -> Self {
+        Self {
+            left: left as f32,
+            top: top as f32,
+        }
+    }
+}
+
+impl From<Position> for (f32, f32) {
+    fn from(position: Position) -> Self {
+        (position.left, position.top)
+    }
+}
+
+impl From<Position> for [f32; 2] {
+    fn from(position: Position) -> Self {
+        [position.left, position.top]
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<Vec2> for Position {
+    fn from(vec: Vec2) -> Self {
+        Self {
+            left: vec.x,
+            top: vec.y,
+        }
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<Position> for Vec2 {
+    fn from(position: Position) -> Self {
+        Vec2::new(position.left, position.top)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SizeInPx {
     pub width: u32,
     pub height: u32,
@@ -661,6 +3030,7 @@ impl From<(u32, u32)> for SizeInPx {
 }
 
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Color {
     pub r: f32,
     pub g: f32,
@@ -687,6 +3057,44 @@ impl Color {
     pub const fn rgba(r: f32, g: f32, b: f32, a: f32) -> Self {
         Self { r, g, b, a }
     }
+
+    pub const fn with_alpha(self, a: f32) -> Self {
+        Self { a, ..self }
+    }
+
+    /// Multiplies the color channels by `factor`: `1.0` keeps the color, `0.0` makes it black.
+    /// Alpha is kept.
+    pub fn darken(self, factor: f32) -> Self {
+        Self {
+            r: self.r * factor,
+            g: self.g * factor,
+            b: self.b * factor,
+            a: self.a,
+        }
+    }
+
+    /// Keeps `factor` of the distance to white: `1.0` keeps the color, `0.0` makes it white.
+    /// Alpha is kept.
+    pub fn lighten(self, factor: f32) -> Self {
+        let lighten = |channel: f32| 1.0 - (1.0 - channel) * factor;
+        Self {
+            r: lighten(self.r),
+            g: lighten(self.g),
+            b: lighten(self.b),
+            a: self.a,
+        }
+    }
+
+    /// The gray of the same perceived brightness.
+    pub fn grayscale(self) -> Self {
+        let luma = self.r * 0.299 + self.g * 0.587 + self.b * 0.114;
+        Self {
+            r: luma,
+            g: luma,
+            b: luma,
+            a: self.a,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -696,30 +3104,61 @@ pub(crate) struct WgpuContext {
     queue: Queue,
     config: wgpu::SurfaceConfiguration,
     size: SizeInPx,
-    buffer_texture: wgpu::Texture,
+    /// Absent when rendering straight to the surface.
+    buffer_texture: Option<wgpu::Texture>,
+    msaa_texture: Option<wgpu::Texture>,
+    sample_count: u32,
+    id_texture: Option<wgpu::Texture>,
+    depth_texture: Option<wgpu::Texture>,
+    has_frame: bool,
+    minimized: bool,
 }
 
 impl WgpuContext {
-    async fn new<W>(window: &W, width: u32, height: u32) -> Result<WgpuContext, Error>
+    async fn new<W>(
+        window: &W,
+        width: u32,
+        height: u32,
+        canvas_settings: &CanvasSettings,
+    ) -> Result<WgpuContext, Error>
     where
         W: HasRawWindowHandle + HasRawDisplayHandle,
     {
-        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let instance = wgpu::Instance::new(canvas_settings.backends);
         let surface = unsafe { instance.create_surface(window) };
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
+                power_preference: canvas_settings.power_preference,
                 compatible_surface: Some(&surface),
                 force_fallback_adapter: false,
             })
             .await
             .ok_or(Error::InitializationFailed)?;
 
+        let missing_features = canvas_settings.features - adapter.features();
+        if !missing_features.is_empty() {
+            return Err(Error::UnsupportedFeatures(missing_features));
+        }
+
+        let mut unsupported_limit = None;
+        canvas_settings
+            .limits
+            .check_limits_with_fail_fn(&adapter.limits(), true, |name, _, _| {
+                unsupported_limit = Some(name)
+            });
+        if let Some(name) = unsupported_limit {
+            return Err(Error::UnsupportedLimit(name));
+        }
+
+        // Only used by the wireframe debug mode, so we don't fail if it is missing.
+        let features =
+            canvas_settings.features | (adapter.features() & wgpu::Features::POLYGON_MODE_LINE);
+
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
-                    limits: wgpu::Limits::default(),
+                    features,
+                    limits: canvas_settings.limits.clone(),
                     label: None,
                 },
                 None,
@@ -729,7 +3168,7 @@ impl WgpuContext {
 
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_DST,
-            format: wgpu::TextureFormat::Bgra8Unorm,
+            format: canvas_settings.target_format(),
             width,
             height,
             present_mode: wgpu::PresentMode::Fifo,
@@ -739,19 +3178,20 @@ impl WgpuContext {
 
         let size = SizeInPx { width, height };
 
-        let buffer_texture = device.create_texture(&wgpu::TextureDescriptor {
-            size: wgpu::Extent3d {
-                width,
-                height,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Bgra8Unorm,
-            usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
-            label: None,
-        });
+        let buffer_texture = canvas_settings
+            .use_intermediate_target
+            .then(|| WgpuContext::create_buffer_texture(&device, size, config.format));
+
+        let id_texture = canvas_settings
+            .picking_enabled()
+            .then(|| WgpuContext::create_id_texture(&device, size));
+
+        let sample_count = canvas_settings.sample_count();
+        let msaa_texture = (sample_count > 1)
+            .then(|| WgpuContext::create_msaa_texture(&device, size, config.format, sample_count));
+        let depth_texture = canvas_settings
+            .depth_sorting
+            .then(|| WgpuContext::create_depth_texture(&device, size, sample_count));
 
         Ok(WgpuContext {
             surface,
@@ -760,6 +3200,12 @@ impl WgpuContext {
             queue,
             size,
             buffer_texture,
+            msaa_texture,
+            sample_count,
+            id_texture,
+            depth_texture,
+            has_frame: false,
+            minimized: false,
         })
     }
 
@@ -769,19 +3215,138 @@ impl WgpuContext {
         self.config.height = height;
 
         self.surface.configure(&self.device, &self.config);
-        self.buffer_texture = self.device.create_texture(&wgpu::TextureDescriptor {
+        if self.buffer_texture.is_some() {
+            self.buffer_texture = Some(WgpuContext::create_buffer_texture(
+                &self.device,
+                self.size,
+                self.config.format,
+            ));
+        }
+        if self.id_texture.is_some() {
+            self.id_texture = Some(WgpuContext::create_id_texture(&self.device, self.size));
+        }
+        if self.msaa_texture.is_some() {
+            self.msaa_texture = Some(WgpuContext::create_msaa_texture(
+                &self.device,
+                self.size,
+                self.config.format,
+                self.sample_count,
+            ));
+        }
+        if self.depth_texture.is_some() {
+            self.depth_texture = Some(WgpuContext::create_depth_texture(
+                &self.device,
+                self.size,
+                self.sample_count,
+            ));
+        }
+        self.has_frame = false;
+    }
+
+    fn buffer_texture(&self) -> Result<&wgpu::Texture, Error> {
+        self.buffer_texture
+            .as_ref()
+            .ok_or(Error::NoIntermediateTarget)
+    }
+
+    fn create_buffer_texture(
+        device: &Device,
+        size: SizeInPx,
+        format: wgpu::TextureFormat,
+    ) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
             size: wgpu::Extent3d {
+                width: size.width,
+                height: size.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            label: None,
+        })
+    }
+
+    /// Does nothing when rendering straight to the surface.
+    fn copy_to_surface(&self, encoder: &mut CommandEncoder, surface_texture: &wgpu::Texture) {
+        let buffer_texture = match &self.buffer_texture {
+            Some(buffer_texture) => buffer_texture,
+            None => return,
+        };
+        encoder.copy_texture_to_texture(
+            wgpu::ImageCopyTexture {
+                texture: buffer_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyTexture {
+                texture: surface_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::Extent3d {
                 width: self.size.width,
                 height: self.size.height,
                 depth_or_array_layers: 1,
             },
+        );
+    }
+
+    fn create_msaa_texture(
+        device: &Device,
+        size: SizeInPx,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: size.width,
+                height: size.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            label: Some("msaa_texture"),
+        })
+    }
+
+    fn create_depth_texture(device: &Device, size: SizeInPx, sample_count: u32) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: size.width,
+                height: size.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            label: Some("depth_texture"),
+        })
+    }
+
+    fn create_id_texture(device: &Device, size: SizeInPx) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: size.width,
+                height: size.height,
+                depth_or_array_layers: 1,
+            },
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Bgra8Unorm,
+            format: PICKING_FORMAT,
             usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
-            label: None,
-        });
+            label: Some("id_texture"),
+        })
     }
 }
 
@@ -821,6 +3386,21 @@ impl Transform {
         let translation = Vec2::new(x * -cos + x + y * sin, -x * sin + y * -cos + y);
         self.affine = self.affine * Affine2::from_mat2_translation(matrix2, translation);
     }
+
+    /// Applies the transform to a position.
+    pub fn transform_point(&self, position: Position) -> Position {
+        let point = self
+            .affine
+            .transform_point2(Vec2::new(position.left, position.top));
+        Position::new(point.x, point.y)
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<Transform> for Affine2 {
+    fn from(transform: Transform) -> Self {
+        transform.affine
+    }
 }
 
 impl Mul for Transform {
@@ -857,6 +3437,30 @@ impl MulAssign<Option<&Transform>> for Transform {
     }
 }
 
+/// The transforms of the nested [`Graphics::with_translation`] and [`Graphics::with_rotation`]
+/// calls, each one composed with the ones around it.
+#[derive(Default)]
+struct TransformStack {
+    transforms: Vec<Transform>,
+}
+
+impl TransformStack {
+    fn current(&self) -> Transform {
+        self.transforms.last().copied().unwrap_or_default()
+    }
+
+    /// Applies `change` on top of the current transform, until the next [`TransformStack::pop`].
+    fn push<F: FnOnce(&mut Transform)>(&mut self, change: F) {
+        let mut transform = self.current();
+        change(&mut transform);
+        self.transforms.push(transform);
+    }
+
+    fn pop(&mut self) {
+        self.transforms.pop();
+    }
+}
+
 impl Default for Transform {
     fn default() -> Self {
         Self {
@@ -924,8 +3528,516 @@ async fn texture_to_cpu(
     Ok(pixels)
 }
 
+async fn read_texel(
+    device: &Device,
+    queue: &Queue,
+    texture: &wgpu::Texture,
+    x: u32,
+    y: u32,
+) -> Result<[u8; 4], BufferAsyncError> {
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+    let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    encoder.copy_texture_to_buffer(
+        wgpu::ImageCopyTexture {
+            aspect: wgpu::TextureAspect::All,
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d { x, y, z: 0 },
+        },
+        wgpu::ImageCopyBuffer {
+            buffer: &output_buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT),
+                rows_per_image: std::num::NonZeroU32::new(1),
+            },
+        },
+        wgpu::Extent3d {
+            width: 1,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+    );
+    queue.submit(Some(encoder.finish()));
+
+    let data = AsyncBufferView::new(output_buffer.slice(..), device).await?;
+
+    let mut texel = [0; 4];
+    texel.copy_from_slice(&data[..4]);
+    Ok(texel)
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Reorders a pixel read back from a texture of this format to RGBA.
+fn to_rgba(format: wgpu::TextureFormat, [first, second, third, alpha]: [u8; 4]) -> [u8; 4] {
+    match format {
+        wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => {
+            [third, second, first, alpha]
+        }
+        _ => [first, second, third, alpha],
+    }
+}
+
+/// Turns the tightly packed pixels of a texture of this format into an RGBA image.
+fn to_rgba_image(
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+) -> image::RgbaImage {
+    let mut buffer = image::RgbaImage::from_raw(width, height, pixels).unwrap();
+    for px in buffer.pixels_mut() {
+        *px = image::Rgba(to_rgba(format, px.0));
+    }
+    buffer
+}
+
+/// Copies between a texture and a buffer need rows aligned to
+/// [`wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`]. Uploads with `Queue::write_texture` don't, wgpu stages
+/// them itself, which is why sprites and glyphs are uploaded with tightly packed rows.
 fn padded_bytes_per_row(width: u32) -> usize {
+    let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize;
     let bytes_per_row = width as usize * 4;
-    let padding = (256 - bytes_per_row % 256) % 256;
+    let padding = (alignment - bytes_per_row % alignment) % alignment;
     bytes_per_row + padding
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readback_rows_are_padded_to_the_copy_alignment() {
+        // Four bytes per pixel.
+        assert_eq!(padded_bytes_per_row(17), 256);
+        assert_eq!(padded_bytes_per_row(64), 256);
+        assert_eq!(padded_bytes_per_row(65), 512);
+    }
+
+    fn channels(color: Color) -> [f32; 4] {
+        [color.r, color.g, color.b, color.a]
+    }
+
+    #[test]
+    fn darken_by_one_keeps_the_color() {
+        let color = Color::rgba(0.2, 0.4, 0.8, 0.5);
+
+        assert_eq!(channels(color.darken(1.0)), channels(color));
+    }
+
+    #[test]
+    fn darken_by_zero_makes_black_and_keeps_alpha() {
+        let color = Color::rgba(0.2, 0.4, 0.8, 0.5);
+
+        assert_eq!(channels(color.darken(0.0)), [0.0, 0.0, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn msaa_uses_one_or_four_samples() {
+        let sample_count = |msaa| {
+            CanvasSettings {
+                msaa,
+                ..Default::default()
+            }
+            .sample_count()
+        };
+
+        assert_eq!(
+            [0, 1, 2, 3, 4, 8, 16].map(sample_count),
+            [1, 1, 4, 4, 4, 4, 4]
+        );
+    }
+
+    #[test]
+    fn bgra_pixels_are_swapped_to_rgba() {
+        let bgra = [0x30, 0x20, 0x10, 0xff].repeat(4);
+
+        for format in [
+            wgpu::TextureFormat::Bgra8Unorm,
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+        ] {
+            let image = to_rgba_image(format, 2, 2, bgra.clone());
+
+            assert!(image.pixels().all(|px| px.0 == [0x10, 0x20, 0x30, 0xff]));
+        }
+    }
+
+    #[test]
+    fn rgba_pixels_are_kept_as_is() {
+        let rgba = [0x10, 0x20, 0x30, 0xff].repeat(4);
+
+        for format in [
+            wgpu::TextureFormat::Rgba8Unorm,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+        ] {
+            let image = to_rgba_image(format, 2, 2, rgba.clone());
+
+            assert!(image.pixels().all(|px| px.0 == [0x10, 0x20, 0x30, 0xff]));
+        }
+    }
+
+    #[test]
+    fn inflated_with_negative_amounts_shrinks_around_the_center() {
+        let rect = Rect::new(10.0, 20.0, 100.0, 50.0);
+
+        assert_eq!(
+            rect.inflated(-10.0, -5.0),
+            Rect::new(20.0, 25.0, 80.0, 40.0)
+        );
+    }
+
+    #[test]
+    fn inflated_past_nothing_stays_empty_at_the_center() {
+        let rect = Rect::new(10.0, 20.0, 100.0, 50.0);
+
+        assert_eq!(rect.inflated(-60.0, -30.0), Rect::new(60.0, 45.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn nested_translations_add_up_and_unwind() {
+        let origin = Position::new(0.0, 0.0);
+        let mut transforms = TransformStack::default();
+
+        transforms.push(|transform| transform.translate(10.0, 5.0));
+        transforms.push(|transform| transform.translate(3.0, 4.0));
+        assert_eq!(
+            transforms.current().transform_point(origin),
+            Position::new(13.0, 9.0)
+        );
+
+        transforms.pop();
+        assert_eq!(
+            transforms.current().transform_point(origin),
+            Position::new(10.0, 5.0)
+        );
+
+        transforms.pop();
+        assert_eq!(transforms.current().transform_point(origin), origin);
+    }
+
+    /// Polls the future until it's ready, which wgpu's native futures are right away.
+    pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        struct NoopWaker;
+        impl std::task::Wake for NoopWaker {
+            fn wake(self: std::sync::Arc<Self>) {}
+        }
+
+        let waker = std::task::Waker::from(std::sync::Arc::new(NoopWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    /// A device to draw with, from a Vulkan, Metal or DX12 adapter. The GL backend can't
+    /// translate the shader for the vertex stage. Tests using it are ignored by default, run them
+    /// with `cargo test -- --ignored` on a machine with a GPU.
+    pub(crate) fn device() -> (Device, Queue) {
+        let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
+        let adapter = block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+            .expect("no Vulkan, Metal or DX12 adapter");
+        block_on(adapter.request_device(&wgpu::DeviceDescriptor::default(), None))
+            .expect("the device couldn't be created")
+    }
+
+    #[test]
+    fn draw_data_is_ordered_by_layer_then_opaque_first() {
+        let order = |layer, transparent| DrawOrder { layer, transparent };
+        // With the order they were submitted in.
+        let mut draw_orders = [
+            (order(1, true), 0),
+            (order(0, false), 1),
+            (order(1, false), 2),
+            (order(-1, true), 3),
+            (order(0, false), 4),
+            (order(1, false), 5),
+        ];
+
+        draw_orders.sort_by_key(|(draw_order, _)| *draw_order);
+
+        let submitted: Vec<usize> = draw_orders.iter().map(|(_, index)| *index).collect();
+        assert_eq!(submitted, [3, 1, 4, 2, 5, 0]);
+    }
+
+    #[test]
+    fn transparent_operations_are_drawn_back_to_front_keeping_ties_in_order() {
+        let operation = |z, id| {
+            let mut operation = RenderOperation::new(
+                Rect::new(0.0, 0.0, 1.0, 1.0),
+                Rect::new(0.0, 0.0, 1.0, 1.0),
+                ColorMatrix::from_color(Color::rgb(1.0, 1.0, 1.0)),
+                Transform::default(),
+            );
+            operation.with_z(z).with_id(id);
+            operation
+        };
+        let mut operations = [
+            operation(10.0, 0),
+            operation(-5.0, 1),
+            operation(10.0, 2),
+            operation(0.0, 3),
+            operation(-5.0, 4),
+        ];
+
+        operations.sort_by(back_to_front);
+
+        let ids: Vec<u32> = operations.iter().map(|operation| operation.id).collect();
+        assert_eq!(ids, [1, 4, 3, 0, 2]);
+    }
+
+    /// A block of `operations` operations on `layer`, standing in for an [`OperationBlock`].
+    struct TestBlock {
+        layer: i32,
+        operations: usize,
+    }
+
+    impl LayeredBlock for TestBlock {
+        fn layer(&self) -> i32 {
+            self.layer
+        }
+
+        fn operation_count(&self) -> usize {
+            self.operations
+        }
+    }
+
+    /// Adds an operation on `layer`, the way `Graphics::get_operation_block` picks its block.
+    fn draw_on(open_blocks: &mut OpenBlocks<TestBlock>, layer: i32) {
+        open_blocks.switch_to_layer(layer);
+        open_blocks
+            .current
+            .get_or_insert(TestBlock {
+                layer,
+                operations: 0,
+            })
+            .operations += 1;
+    }
+
+    #[test]
+    fn switching_layers_keeps_each_layer_open_and_the_count() {
+        let mut open_blocks = OpenBlocks::default();
+        for _ in 0..3 {
+            draw_on(&mut open_blocks, 0);
+        }
+        assert_eq!(open_blocks.operation_count(), 3);
+
+        for layer in [1, 0, 1] {
+            draw_on(&mut open_blocks, layer);
+        }
+
+        assert_eq!(open_blocks.operation_count(), 6);
+        assert_eq!(
+            open_blocks.current.as_ref().map(|block| block.layer),
+            Some(1)
+        );
+        assert_eq!(open_blocks.parked.len(), 1);
+        assert_eq!(open_blocks.parked[0].operations, 4);
+    }
+
+    #[test]
+    fn preparing_the_current_block_moves_its_count_to_the_prepared_one() {
+        let mut open_blocks = OpenBlocks::default();
+        for layer in [0, 1, 1] {
+            draw_on(&mut open_blocks, layer);
+        }
+
+        // Another camera, or anything the current block doesn't accept, prepares it.
+        let prepared = open_blocks
+            .current
+            .take()
+            .map_or(0, |block| block.operations);
+        draw_on(&mut open_blocks, 1);
+
+        assert_eq!(prepared, 2);
+        assert_eq!(prepared + open_blocks.operation_count(), 4);
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn batching_does_not_change_the_operation_count() {
+        let (device, queue) = device();
+        let mut graphics_renderer =
+            GraphicsRenderer::new(&device, &queue, 64, 64, &CanvasSettings::default());
+        let mut camera = Camera::new(&device, graphics_renderer.camera.camera_settings);
+        let rect = Rect::new(0.0, 0.0, 8.0, 8.0);
+        let color = Color::rgb(1.0, 0.0, 0.0);
+
+        let mut counts = vec![];
+        graphics_renderer.prepare(&device, &queue, |graphics| {
+            counts.push(graphics.operation_count());
+            assert!(graphics.is_empty());
+
+            // All in the same block.
+            for _ in 0..3 {
+                graphics.draw_rect(rect, color);
+            }
+            counts.push(graphics.operation_count());
+
+            // A block for each layer, the first one kept open aside.
+            for layer in [1, 0, 1] {
+                graphics.with_layer(layer, |graphics| {
+                    graphics.draw_rect(rect, color);
+                });
+            }
+            counts.push(graphics.operation_count());
+
+            // Another camera turns the open block into draw data.
+            graphics.with_camera(&mut camera, |graphics| {
+                graphics.draw_rect(rect, color);
+            });
+            counts.push(graphics.operation_count());
+            assert!(!graphics.is_empty());
+        });
+
+        assert_eq!(counts, [0, 3, 6, 7]);
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn the_same_frame_is_drawn_in_the_same_order() {
+        let (device, queue) = device();
+        let mut graphics_renderer =
+            GraphicsRenderer::new(&device, &queue, 64, 64, &CanvasSettings::default());
+        let rect = Rect::new(0.0, 0.0, 8.0, 8.0);
+        let color = Color::rgb(1.0, 0.0, 0.0);
+
+        let mut draw_order = || {
+            graphics_renderer.prepare(&device, &queue, |graphics| {
+                for (layer, count) in [(1, 2), (0, 1), (2, 4), (1, 3), (0, 5)] {
+                    graphics.with_layer(layer, |graphics| {
+                        for _ in 0..count {
+                            graphics.draw_rect(rect, color);
+                        }
+                    });
+                }
+            });
+            graphics_renderer
+                .draw_datas
+                .iter()
+                .map(|draw_data| (draw_data.layer, draw_data.count))
+                .collect::<Vec<_>>()
+        };
+
+        let first = draw_order();
+        assert_eq!(first, draw_order());
+        assert_eq!(first, [(0, 6), (1, 5), (2, 4)]);
+    }
+
+    /// Draws a frame over black on a texture of the target format, and reads back the top left
+    /// pixel, as RGBA.
+    fn draw_offscreen(
+        canvas_settings: &CanvasSettings,
+        prepare_function: impl FnOnce(&mut Graphics),
+    ) -> [u8; 4] {
+        let (device, queue) = device();
+        let format = canvas_settings.target_format();
+        let mut graphics_renderer = GraphicsRenderer::new(&device, &queue, 4, 4, canvas_settings);
+        graphics_renderer.prepare(&device, &queue, prepare_function);
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            graphics_renderer.render(&mut render_pass);
+        }
+        queue.submit(Some(encoder.finish()));
+
+        to_rgba(
+            format,
+            block_on(read_texel(&device, &queue, &texture, 0, 0)).unwrap(),
+        )
+    }
+
+    #[test]
+    fn half_white_over_black_is_stored_as_188_with_srgb() {
+        // What the blend unit computes, in linear space with srgb, on the stored values without.
+        let blended = 1.0 * 0.5 + 0.0 * (1.0 - 0.5);
+
+        // An sRGB target stores the byte whose linear value is the closest to the blend.
+        let distance = |byte: &u8| (srgb_to_linear(*byte as f32 / 255.0) - blended).abs();
+        let stored = (0..=u8::MAX)
+            .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+            .unwrap();
+        assert_eq!(stored, 188);
+        // A plain target stores the blend as is.
+        assert_eq!((blended * 255.0f32).round() as u8, 128);
+
+        // Read back from a BGRA surface, the gray stays the same.
+        let [red, _, _, _] = to_rgba(wgpu::TextureFormat::Bgra8UnormSrgb, [188, 188, 188, 255]);
+        assert!((srgb_to_linear(red as f32 / 255.0) - blended).abs() < 0.01);
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn half_white_over_black_blends_in_linear_space_with_srgb() {
+        let red_of_half_white = |srgb| {
+            let canvas_settings = CanvasSettings {
+                srgb,
+                ..Default::default()
+            };
+            draw_offscreen(&canvas_settings, |graphics| {
+                graphics.draw_rect(
+                    Rect::new(0.0, 0.0, 4.0, 4.0),
+                    Color::rgba(1.0, 1.0, 1.0, 0.5),
+                );
+            })[0]
+        };
+
+        // Blended as stored, half way between 0 and 255.
+        assert!(red_of_half_white(false).abs_diff(128) <= 1);
+        // Blended in linear space, then encoded: a linear 0.5 is 188 in sRGB.
+        let srgb = red_of_half_white(true);
+        assert!(srgb.abs_diff(188) <= 1);
+        assert!((srgb_to_linear(srgb as f32 / 255.0) - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn srgb_to_linear_matches_the_reference_values() {
+        assert_eq!(srgb_to_linear(0.0), 0.0);
+        assert!((srgb_to_linear(1.0) - 1.0).abs() < 1e-6);
+        assert!((srgb_to_linear(188.0 / 255.0) - 0.5).abs() < 0.01);
+        assert!((srgb_to_linear(0.04) - 0.04 / 12.92).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::mpsc::{channel, sync_channel, Receiver, SyncSender, TryRecvError},
+    thread::{self, JoinHandle},
+};
+
+use image::RgbaImage;
+use wgpu::{Buffer, BufferAsyncError, Device, Queue};
+
+use crate::{padded_bytes_per_row, to_rgba, Error, SizeInPx};
+
+/// How many recorded frames can wait for the GPU before the next one waits for them, and how
+/// many can wait for the encoder thread before drawing waits for it.
+const MAX_PENDING_FRAMES: usize = 8;
+
+pub(crate) enum FrameSink {
+    Directory(PathBuf),
+    Callback(Box<dyn FnMut(u64, RgbaImage)>),
+}
+
+/// Where the frames read back go.
+enum Output {
+    /// Encoding a PNG takes longer than a frame, so it happens on a single thread off the render
+    /// loop, which stops at the first frame it fails to save.
+    Encoder {
+        frames: SyncSender<(u64, RgbaImage)>,
+        encoder: JoinHandle<Result<(), Error>>,
+    },
+    Callback(Box<dyn FnMut(u64, RgbaImage)>),
+}
+
+impl Output {
+    fn new(sink: FrameSink) -> Self {
+        match sink {
+            FrameSink::Directory(directory) => {
+                let (frames, receiver) = sync_channel::<(u64, RgbaImage)>(MAX_PENDING_FRAMES);
+                let encoder = thread::spawn(move || {
+                    for (index, image) in receiver {
+                        let path = directory.join(format!("frame_{index:06}.png"));
+                        image
+                            .save(&path)
+                            .map_err(|_e| Error::RecordingFailed(path))?;
+                    }
+                    Ok(())
+                });
+                Output::Encoder { frames, encoder }
+            }
+            FrameSink::Callback(callback) => Output::Callback(callback),
+        }
+    }
+
+    /// Waits when the encoder is too far behind.
+    fn send(&mut self, index: u64, image: RgbaImage) {
+        match self {
+            // The encoder only hangs up after an error, which `finish` reports.
+            Output::Encoder { frames, .. } => {
+                let _ = frames.send((index, image));
+            }
+            Output::Callback(callback) => callback(index, image),
+        }
+    }
+
+    /// Waits for the encoder to save the frames sent so far, and returns its first error.
+    fn finish(self) -> Result<(), Error> {
+        match self {
+            Output::Encoder { frames, encoder } => {
+                drop(frames);
+                encoder
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            }
+            Output::Callback(_) => Ok(()),
+        }
+    }
+}
+
+struct PendingFrame {
+    index: u64,
+    size: SizeInPx,
+    format: wgpu::TextureFormat,
+    buffer: Buffer,
+    receiver: Receiver<Result<(), BufferAsyncError>>,
+}
+
+pub(crate) struct Recorder {
+    output: Output,
+    every_n_frames: u32,
+    /// Frames to draw before the next one to record.
+    skipped_frames: u32,
+    recorded_count: u64,
+    pending: VecDeque<PendingFrame>,
+    /// Readback buffers done with, to copy the next frames to, all of `buffer_size`.
+    free_buffers: Vec<Buffer>,
+    buffer_size: u64,
+}
+
+impl Recorder {
+    pub fn new(sink: FrameSink, every_n_frames: u32) -> Self {
+        Recorder {
+            output: Output::new(sink),
+            every_n_frames: every_n_frames.max(1),
+            skipped_frames: 0,
+            recorded_count: 0,
+            pending: VecDeque::new(),
+            free_buffers: Vec::with_capacity(MAX_PENDING_FRAMES),
+            buffer_size: 0,
+        }
+    }
+
+    /// Copies the frame in `texture` to a buffer if it is one to record, and hands the frames
+    /// read back so far to the sink.
+    pub fn capture(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        texture: &wgpu::Texture,
+        format: wgpu::TextureFormat,
+        size: SizeInPx,
+    ) {
+        if self.skipped_frames == 0 {
+            if self.pending.len() >= MAX_PENDING_FRAMES {
+                device.poll(wgpu::Maintain::Wait);
+                self.collect();
+            }
+            let frame = self.copy_frame(device, queue, texture, format, size);
+            self.pending.push_back(frame);
+            self.skipped_frames = self.every_n_frames;
+        }
+        self.skipped_frames -= 1;
+
+        device.poll(wgpu::Maintain::Poll);
+        self.collect();
+    }
+
+    /// Waits for the frames still being read back and hands them to the sink, then for the last
+    /// ones to be saved. Returns the first frame that couldn't be.
+    pub fn finish(mut self, device: &Device) -> Result<(), Error> {
+        device.poll(wgpu::Maintain::Wait);
+        self.collect();
+        self.output.finish()
+    }
+
+    fn copy_frame(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        texture: &wgpu::Texture,
+        format: wgpu::TextureFormat,
+        size: SizeInPx,
+    ) -> PendingFrame {
+        let padded_bytes_per_row = padded_bytes_per_row(size.width);
+        let buffer_size = padded_bytes_per_row as u64 * size.height as u64;
+        if buffer_size != self.buffer_size {
+            // Resized: the buffers of the old size are of no use anymore.
+            self.free_buffers.clear();
+            self.buffer_size = buffer_size;
+        }
+        let buffer = self.free_buffers.pop().unwrap_or_else(|| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Recorded frame"),
+                size: buffer_size,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            })
+        });
+
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(padded_bytes_per_row as u32),
+                    rows_per_image: std::num::NonZeroU32::new(size.height),
+                },
+            },
+            wgpu::Extent3d {
+                width: size.width,
+                height: size.height,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit(Some(encoder.finish()));
+
+        let (sender, receiver) = channel();
+        buffer.slice(..).map_async(wgpu::MapMode::Read, move |v| {
+            // The recording may have been dropped in the meantime.
+            let _ = sender.send(v);
+        });
+
+        let index = self.recorded_count;
+        self.recorded_count += 1;
+        PendingFrame {
+            index,
+            size,
+            format,
+            buffer,
+            receiver,
+        }
+    }
+
+    /// Hands the frames read back to the sink, in order, stopping at the first one still pending.
+    fn collect(&mut self) {
+        while let Some(frame) = self.pending.front() {
+            match frame.receiver.try_recv() {
+                Ok(Ok(())) => {}
+                // The frame couldn't be read back and is skipped, along with its buffer.
+                Ok(Err(_)) | Err(TryRecvError::Disconnected) => {
+                    self.pending.pop_front();
+                    continue;
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+
+            let frame = self.pending.pop_front().unwrap();
+            let image = frame.to_image();
+            if frame.buffer.size() == self.buffer_size {
+                self.free_buffers.push(frame.buffer);
+            }
+            self.output.send(frame.index, image);
+        }
+    }
+}
+
+impl PendingFrame {
+    fn to_image(&self) -> RgbaImage {
+        let SizeInPx { width, height } = self.size;
+        let padded_bytes_per_row = padded_bytes_per_row(width);
+        let unpadded_bytes_per_row = width as usize * 4;
+
+        let mut pixels: Vec<u8> = Vec::with_capacity(unpadded_bytes_per_row * height as usize);
+        {
+            let padded_data = self.buffer.slice(..).get_mapped_range();
+            for row in padded_data.chunks_exact(padded_bytes_per_row) {
+                for px in row[..unpadded_bytes_per_row].chunks_exact(4) {
+                    pixels.extend_from_slice(&to_rgba(self.format, [px[0], px[1], px[2], px[3]]));
+                }
+            }
+        }
+        self.buffer.unmap();
+
+        RgbaImage::from_raw(width, height, pixels).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use image::RgbaImage;
+
+    use super::{FrameSink, Output};
+    use crate::Error;
+
+    #[test]
+    fn frames_are_saved_in_the_directory() {
+        let directory =
+            std::env::temp_dir().join(format!("tiefring-recording-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut output = Output::new(FrameSink::Directory(directory.clone()));
+        for index in 0..3 {
+            output.send(index, RgbaImage::new(2, 2));
+        }
+        let finished = output.finish();
+        let saved = std::fs::read_dir(&directory).unwrap().count();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(finished.is_ok());
+        assert_eq!(saved, 3);
+    }
+
+    #[test]
+    fn the_first_frame_that_fails_to_save_is_reported() {
+        let directory = PathBuf::from("/this/directory/does/not/exist");
+
+        let mut output = Output::new(FrameSink::Directory(directory.clone()));
+        for index in 0..3 {
+            output.send(index, RgbaImage::new(2, 2));
+        }
+
+        match output.finish() {
+            Err(Error::RecordingFailed(path)) => {
+                assert_eq!(path, directory.join("frame_000000.png"))
+            }
+            _ => panic!("The directory doesn't exist"),
+        }
+    }
+}
@@ -1,13 +1,53 @@
+use std::rc::Rc;
+
 use glam::{Affine2, Vec2};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    Buffer, BufferUsages, Device, Queue, RenderPass, RenderPipeline, VertexBufferLayout,
+    BindGroup, Buffer, BufferUsages, Device, PipelineLayout, Queue, RenderPass, RenderPipeline,
+    ShaderModule, VertexBufferLayout,
 };
 
 use crate::{
-    camera::Camera, sprite::TextureContext, Color, DrawData, OperationBlock, Rect, Transform,
+    camera::{Camera, DEPTH_RANGE},
+    sprite::{SamplerKind, Sprite, TextureContext},
+    CanvasSettings, Color, DebugMode, DrawData, InstanceDesc, OperationBlock, Position, Rect,
+    SizeInPx, Transform,
 };
 
+pub(crate) const PICKING_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Uint;
+pub(crate) const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// How what is drawn is combined with what was drawn before.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum BlendMode {
+    /// Blends according to the alpha.
+    #[default]
+    Alpha,
+    /// Adds the colors, weighted by the alpha, which suits glows and light effects.
+    Additive,
+    /// Replaces the previous pixels, alpha included.
+    Replace,
+}
+
+/// Which way an entity looks, for sprites drawn facing right, see [`RenderOperation::face`].
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum Facing {
+    #[default]
+    Right,
+    /// Mirrored horizontally.
+    Left,
+    /// Turned towards an angle in radians, 0 being right, and mirrored when looking left so that
+    /// the sprite never ends up upside down.
+    Towards(f32),
+}
+
+/// Everything that, besides the texture, requires a separate draw call.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub(crate) struct RenderState {
+    pub sampler_kind: SamplerKind,
+    pub blend_mode: BlendMode,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub(crate) struct Vertex {
@@ -59,6 +99,22 @@ impl ColorMatrix {
         let adjust = [color.r, color.g, color.b, 0.0];
         Self { matrix, adjust }
     }
+
+    /// Scales the resulting alpha, whichever channels it comes from.
+    /// Whether the alpha of the texture doesn't make it through whole, like with translucent
+    /// colors or the coverage of text.
+    pub fn scales_alpha_down(&self) -> bool {
+        self.matrix[3][3] < 1.0
+    }
+
+    pub fn multiply_alpha(mut self, alpha: f32) -> Self {
+        for column in self.matrix.iter_mut() {
+            column[3] *= alpha;
+        }
+        self.adjust[3] *= alpha;
+
+        self
+    }
 }
 
 #[repr(C)]
@@ -85,10 +141,44 @@ pub struct Instance {
     tex_coords: [f32; 4],
     position_matrix: PositionMatrix,
     color_matrix: ColorMatrix,
+    id: u32,
+    /// See [`INSTANCE_SDF`] and [`INSTANCE_SMOOTH_X`].
+    flags: u32,
+    array_layer: u32,
+    z: f32,
+    alpha_cutoff: f32,
+    ring: [f32; 4],
+    /// The angle the texture turns by, and the size of the quad in pixels, so that it turns in
+    /// the space of the screen rather than stretched with the texture coordinates.
+    uv_rotation: [f32; 3],
 }
 
+/// The texture holds a signed distance field, to turn into coverage.
+pub(crate) const INSTANCE_SDF: u32 = 1;
+/// Fades the left and right edges of the quad over a pixel.
+pub(crate) const INSTANCE_SMOOTH_X: u32 = 2;
+/// Fades the top and bottom edges of the quad over a pixel.
+pub(crate) const INSTANCE_SMOOTH_Y: u32 = 4;
+/// Drops the pixels under half alpha, so that opaque operations don't write the depth of their
+/// transparent pixels.
+pub(crate) const INSTANCE_ALPHA_TEST: u32 = 8;
+/// Draws a ring or a disc inscribed in the quad, see [`crate::Graphics::draw_ring`].
+pub(crate) const INSTANCE_RING: u32 = 16;
+
 impl Instance {
-    fn new(tex_coords: Rect, position: RenderPosition, color_matrix: ColorMatrix) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        tex_coords: Rect,
+        position: RenderPosition,
+        color_matrix: ColorMatrix,
+        id: u32,
+        flags: u32,
+        array_layer: u32,
+        z: f32,
+        alpha_cutoff: f32,
+        ring: [f32; 4],
+        uv_rotation: f32,
+    ) -> Self {
         let tex_coords = [
             tex_coords.width,
             tex_coords.left,
@@ -96,10 +186,24 @@ impl Instance {
             tex_coords.top,
         ];
 
+        let affine2 = position.into_affine2();
+        let uv_rotation = [
+            uv_rotation,
+            affine2.matrix2.x_axis.length(),
+            affine2.matrix2.y_axis.length(),
+        ];
+
         Self {
             tex_coords,
-            position_matrix: position.into_affine2().into(),
+            position_matrix: affine2.into(),
             color_matrix,
+            id,
+            flags,
+            array_layer,
+            z,
+            alpha_cutoff,
+            ring,
+            uv_rotation,
         }
     }
 
@@ -149,19 +253,72 @@ impl Instance {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 32]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Uint32,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 33]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Uint32,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 34]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Uint32,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 35]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float32,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 36]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Float32,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 37]>() as wgpu::BufferAddress,
+                    shader_location: 14,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 41]>() as wgpu::BufferAddress,
+                    shader_location: 15,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
             ],
         }
     }
 }
 
+/// Every pipeline, for each blend mode and debug mode, is created up front with the canvas, so
+/// that the first frames using them don't stutter.
 pub(crate) struct Renderer {
     render_pipeline: RenderPipeline,
+    replace_pipeline: RenderPipeline,
+    additive_pipeline: RenderPipeline,
+    /// With depth sorting, alpha blends without writing the depth, for transparent operations.
+    transparent_pipeline: Option<RenderPipeline>,
+    wireframe_pipeline: Option<RenderPipeline>,
+    overdraw_pipeline: RenderPipeline,
+    pub(crate) debug_mode: DebugMode,
     vertex_buffer: Buffer,
     index_buffer: Buffer,
 }
 
 impl Renderer {
-    pub(crate) fn new(device: &Device, texture_context: &TextureContext, camera: &Camera) -> Self {
+    pub(crate) fn new(
+        device: &Device,
+        texture_context: &TextureContext,
+        camera: &Camera,
+        canvas_settings: &CanvasSettings,
+    ) -> Self {
+        let sample_count = canvas_settings.sample_count();
+        // Every pipeline must match the depth attachment when there is one.
+        let depth = |write: bool| canvas_settings.depth_sorting.then_some(write);
+
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Shader"),
             source: wgpu::ShaderSource::Wgsl(include_str!("shaders/render.wgsl").into()),
@@ -177,40 +334,152 @@ impl Renderer {
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Texture Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[Vertex::description(), Instance::description()],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: wgpu::TextureFormat::Bgra8Unorm,
-                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
+        let blend = if canvas_settings.premultiplied_alpha {
+            wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING
+        } else {
+            wgpu::BlendState::ALPHA_BLENDING
+        };
+        let color_target = Some(wgpu::ColorTargetState {
+            format: canvas_settings.target_format(),
+            blend: Some(blend),
+            write_mask: wgpu::ColorWrites::ALL,
+        });
+        // The picking target stores the id of the last operation drawn on each pixel.
+        let id_target = canvas_settings
+            .picking_enabled()
+            .then_some(wgpu::ColorTargetState {
+                format: PICKING_FORMAT,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            });
+        let (entry_point, targets) =
+            match (id_target.is_some(), canvas_settings.premultiplied_alpha) {
+                (true, false) => ("fs_picking", vec![color_target.clone(), id_target.clone()]),
+                (true, true) => (
+                    "fs_picking_premultiplied",
+                    vec![color_target.clone(), id_target.clone()],
+                ),
+                (false, false) => ("fs_main", vec![color_target]),
+                (false, true) => ("fs_premultiplied", vec![color_target]),
+            };
+
+        let render_pipeline = create_pipeline(
+            device,
+            &render_pipeline_layout,
+            &shader,
+            "Texture Render Pipeline",
+            entry_point,
+            &targets,
+            wgpu::PolygonMode::Fill,
+            sample_count,
+            depth(true),
+        );
+        let transparent_pipeline = canvas_settings.depth_sorting.then(|| {
+            create_pipeline(
+                device,
+                &render_pipeline_layout,
+                &shader,
+                "Transparent Render Pipeline",
+                entry_point,
+                &targets,
+                wgpu::PolygonMode::Fill,
+                sample_count,
+                depth(false),
+            )
+        });
+
+        let mut replace_targets = targets.clone();
+        if let Some(color_target) = replace_targets[0].as_mut() {
+            color_target.blend = Some(wgpu::BlendState::REPLACE);
+        }
+        let replace_pipeline = create_pipeline(
+            device,
+            &render_pipeline_layout,
+            &shader,
+            "Replace Render Pipeline",
+            entry_point,
+            &replace_targets,
+            wgpu::PolygonMode::Fill,
+            sample_count,
+            depth(true),
+        );
+
+        let mut additive_targets = targets.clone();
+        if let Some(color_target) = additive_targets[0].as_mut() {
+            let src_factor = if canvas_settings.premultiplied_alpha {
+                wgpu::BlendFactor::One
+            } else {
+                wgpu::BlendFactor::SrcAlpha
+            };
+            color_target.blend = Some(wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent::OVER,
+            });
+        }
+        let additive_pipeline = create_pipeline(
+            device,
+            &render_pipeline_layout,
+            &shader,
+            "Additive Render Pipeline",
+            entry_point,
+            &additive_targets,
+            wgpu::PolygonMode::Fill,
+            sample_count,
+            depth(false),
+        );
+
+        let wireframe_pipeline = device
+            .features()
+            .contains(wgpu::Features::POLYGON_MODE_LINE)
+            .then(|| {
+                create_pipeline(
+                    device,
+                    &render_pipeline_layout,
+                    &shader,
+                    "Wireframe Render Pipeline",
+                    entry_point,
+                    &targets,
+                    wgpu::PolygonMode::Line,
+                    sample_count,
+                    depth(false),
+                )
+            });
+
+        // Each layer adds the same flat color, so the brightest areas are the most overdrawn.
+        let additive_blend = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::One,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let overdraw_target = Some(wgpu::ColorTargetState {
+            format: canvas_settings.target_format(),
+            blend: Some(wgpu::BlendState {
+                color: additive_blend,
+                alpha: additive_blend,
             }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                polygon_mode: wgpu::PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
+            write_mask: wgpu::ColorWrites::ALL,
         });
+        let overdraw_targets = if let Some(mut id_target) = id_target {
+            id_target.write_mask = wgpu::ColorWrites::empty();
+            vec![overdraw_target, Some(id_target)]
+        } else {
+            vec![overdraw_target]
+        };
+        let overdraw_pipeline = create_pipeline(
+            device,
+            &render_pipeline_layout,
+            &shader,
+            "Overdraw Render Pipeline",
+            "fs_overdraw",
+            &overdraw_targets,
+            wgpu::PolygonMode::Fill,
+            sample_count,
+            depth(false),
+        );
 
         let vertices = [
             Vertex {
@@ -241,6 +510,12 @@ impl Renderer {
 
         Self {
             render_pipeline,
+            replace_pipeline,
+            additive_pipeline,
+            transparent_pipeline,
+            wireframe_pipeline,
+            overdraw_pipeline,
+            debug_mode: DebugMode::None,
             vertex_buffer,
             index_buffer,
         }
@@ -250,17 +525,148 @@ impl Renderer {
         &'a self,
         render_pass: &mut RenderPass<'a>,
         draw_data: &'a [DrawData],
+        main_camera: &'a BindGroup,
+        size: SizeInPx,
+        main_scissor: Option<(u32, u32, u32, u32)>,
     ) {
-        render_pass.set_pipeline(&self.render_pipeline);
         render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
         render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
 
+        let mut current_blend_mode = None;
+        let mut current_camera = None;
+        let mut current_viewport = None;
+        let mut visible = true;
         for draw_data in draw_data.iter() {
-            render_pass.set_bind_group(1, &draw_data.texture.texture_bind_group, &[]);
+            if current_viewport != Some(draw_data.viewport) {
+                let full = Rect::new(0.0, 0.0, size.width as f32, size.height as f32);
+                let viewport = draw_data.viewport.unwrap_or(full);
+                let scissor = match draw_data.viewport {
+                    Some(viewport) => scissor_rect(viewport, size),
+                    None => main_scissor.or(Some((0, 0, size.width, size.height))),
+                };
+                render_pass.set_viewport(
+                    viewport.left,
+                    viewport.top,
+                    viewport.width,
+                    viewport.height,
+                    0.0,
+                    1.0,
+                );
+                visible = scissor.is_some();
+                if let Some((x, y, width, height)) = scissor {
+                    render_pass.set_scissor_rect(x, y, width, height);
+                }
+                current_viewport = Some(draw_data.viewport);
+            }
+            if !visible {
+                continue;
+            }
+
+            let camera = draw_data.camera.as_deref().unwrap_or(main_camera);
+            if !matches!(current_camera, Some(current_camera) if std::ptr::eq(current_camera, camera))
+            {
+                render_pass.set_bind_group(0, camera, &[]);
+                current_camera = Some(camera);
+            }
+
+            let blend_mode = (draw_data.render_state.blend_mode, draw_data.transparent);
+            if current_blend_mode != Some(blend_mode) {
+                render_pass.set_pipeline(self.pipeline(blend_mode.0, blend_mode.1));
+                current_blend_mode = Some(blend_mode);
+            }
+
+            render_pass.set_bind_group(
+                1,
+                draw_data
+                    .texture
+                    .bind_group(draw_data.render_state.sampler_kind),
+                &[],
+            );
             render_pass.set_vertex_buffer(1, draw_data.instance_buffer.slice());
             render_pass.draw_indexed(0..6, 0, 0..draw_data.count);
         }
     }
+
+    fn pipeline(&self, blend_mode: BlendMode, transparent: bool) -> &RenderPipeline {
+        match self.debug_mode {
+            DebugMode::None => match blend_mode {
+                BlendMode::Alpha if transparent => self
+                    .transparent_pipeline
+                    .as_ref()
+                    .unwrap_or(&self.render_pipeline),
+                BlendMode::Alpha => &self.render_pipeline,
+                BlendMode::Additive => &self.additive_pipeline,
+                BlendMode::Replace => &self.replace_pipeline,
+            },
+            // Not every adapter can draw lines, in which case we keep drawing normally.
+            DebugMode::Wireframe => self
+                .wireframe_pipeline
+                .as_ref()
+                .unwrap_or(&self.render_pipeline),
+            DebugMode::Overdraw => &self.overdraw_pipeline,
+        }
+    }
+}
+
+/// The part of `viewport` within the surface, in whole pixels, if any.
+fn scissor_rect(viewport: Rect, size: SizeInPx) -> Option<(u32, u32, u32, u32)> {
+    let left = viewport.left.max(0.0).round() as u32;
+    let top = viewport.top.max(0.0).round() as u32;
+    let right = ((viewport.left + viewport.width).round().max(0.0) as u32).min(size.width);
+    let bottom = ((viewport.top + viewport.height).round().max(0.0) as u32).min(size.height);
+
+    (left < right && top < bottom).then(|| (left, top, right - left, bottom - top))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader: &ShaderModule,
+    label: &str,
+    fragment_entry_point: &str,
+    targets: &[Option<wgpu::ColorTargetState>],
+    polygon_mode: wgpu::PolygonMode,
+    sample_count: u32,
+    depth_write: Option<bool>,
+) -> RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[Vertex::description(), Instance::description()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: fragment_entry_point,
+            targets,
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            polygon_mode,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        // Closer operations have a smaller depth.
+        depth_stencil: depth_write.map(|depth_write_enabled| wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
 }
 
 struct RenderPosition {
@@ -291,27 +697,223 @@ pub struct RenderOperation {
     pub(crate) rect: Rect,
     pub(crate) color_matrix: ColorMatrix,
     pub(crate) transforms: Transform,
+    pub(crate) id: u32,
+    pub(crate) alpha: f32,
+    /// Made of the `INSTANCE_` flags.
+    pub(crate) flags: u32,
+    pub(crate) array_layer: u32,
+    pub(crate) z: f32,
+    pub(crate) transparent: bool,
+    /// 0 when blending, see [`RenderOperation::alpha_cutoff`].
+    pub(crate) alpha_cutoff: f32,
+    /// With [`INSTANCE_RING`], the inner radius as a fraction of the outer one, the softness of
+    /// the edges in pixels, and the start angle and sweep of the sector drawn.
+    pub(crate) ring: [f32; 4],
+    pub(crate) uv_rotation: f32,
 }
 
 impl RenderOperation {
+    pub(crate) fn new(
+        rect: Rect,
+        tex_coords: Rect,
+        color_matrix: ColorMatrix,
+        transforms: Transform,
+    ) -> Self {
+        Self {
+            tex_coords,
+            rect,
+            color_matrix,
+            transforms,
+            id: 0,
+            alpha: 1.0,
+            flags: 0,
+            array_layer: 0,
+            z: 0.0,
+            transparent: false,
+            alpha_cutoff: 0.0,
+            ring: [0.0; 4],
+            uv_rotation: 0.0,
+        }
+    }
+
+    /// Rotates around the center of the operation.
     pub fn rotate(&mut self, angle: f32) -> &mut Self {
+        let center = Position::new(self.rect.width / 2.0, self.rect.height / 2.0);
+        self.rotate_around(angle, center)
+    }
+
+    /// Rotates the texture within the operation, around its center, leaving the operation itself
+    /// in place, for swirls or turning patterns. Adds to the previous rotation. The corners then
+    /// sample outside of the texture coordinates, so it's best used with repeating sprites, see
+    /// [`crate::Graphics::draw_sprite_tiled`].
+    pub fn rotate_uv(&mut self, angle: f32) -> &mut Self {
+        self.uv_rotation += angle;
+
+        self
+    }
+
+    /// Only keeps the sector of the ring inscribed in the operation that starts at `start_angle`
+    /// and sweeps `sweep` radians, `inner` being the inner radius as a fraction of the outer one.
+    /// With [`RenderOperation::rotate_uv`], it turns a tiled sprite into a radial gauge.
+    pub fn clip_to_ring(&mut self, inner: f32, start_angle: f32, sweep: f32) -> &mut Self {
+        self.flags |= INSTANCE_RING;
+        self.ring = [
+            inner.clamp(0.0, 1.0),
+            1.0,
+            start_angle,
+            sweep.clamp(0.0, std::f32::consts::TAU),
+        ];
+
+        self
+    }
+
+    /// Rotates around a pivot in pixels, relative to the top left corner of the operation.
+    pub fn rotate_around(&mut self, angle: f32, pivot: Position) -> &mut Self {
         self.transforms
-            .rotate_centered(angle, self.rect.width / 2.0, self.rect.height / 2.0);
+            .rotate_centered(angle, pivot.left, pivot.top);
+
+        self
+    }
+
+    /// Mirrors the operation horizontally, within its rect.
+    pub fn flip_x(&mut self) -> &mut Self {
+        self.tex_coords.left += self.tex_coords.width;
+        self.tex_coords.width = -self.tex_coords.width;
 
         self
     }
 
+    /// Mirrors the operation vertically, within its rect.
+    pub fn flip_y(&mut self) -> &mut Self {
+        self.tex_coords.top += self.tex_coords.height;
+        self.tex_coords.height = -self.tex_coords.height;
+
+        self
+    }
+
+    /// Turns a sprite drawn facing right to face the direction, mirroring and rotating it around
+    /// its center so that it stays in its rect.
+    pub fn face(&mut self, facing: Facing) -> &mut Self {
+        match facing {
+            Facing::Right => self,
+            Facing::Left => self.flip_x(),
+            // Rounding puts straight up and down a hair on either side, they count as right.
+            Facing::Towards(angle) if angle.cos() < -f32::EPSILON => {
+                self.flip_x().rotate(angle - std::f32::consts::PI)
+            }
+            Facing::Towards(angle) => self.rotate(angle),
+        }
+    }
+
     pub fn translate(&mut self, x: f32, y: f32) -> &mut Self {
         self.transforms.translate(x, y);
 
         self
     }
 
+    /// Sets the opacity, clamped between 0 and 1, on top of the alpha of the color. Calling it
+    /// again replaces the previous value instead of multiplying it.
     pub fn alpha(&mut self, alpha: f32) -> &mut Self {
-        self.color_matrix.matrix[3][3] *= alpha;
+        self.alpha = alpha.clamp(0.0, 1.0);
+
+        self
+    }
+
+    /// Tags this operation so that it can be found with [`crate::Canvas::pick`]. The id `0` is
+    /// reserved for "nothing", and picking must be enabled in the [`CanvasSettings`].
+    pub fn with_id(&mut self, id: u32) -> &mut Self {
+        self.id = id;
+
+        self
+    }
+
+    /// Sets how close to the viewer the operation is, between -100 and 100, higher being in front.
+    /// Only used with [`CanvasSettings::depth_sorting`], otherwise the order of the calls decides.
+    pub fn with_z(&mut self, z: f32) -> &mut Self {
+        self.z = z.clamp(-DEPTH_RANGE, DEPTH_RANGE);
+
+        self
+    }
+
+    /// Marks the operation as transparent for [`CanvasSettings::depth_sorting`], for sprites with
+    /// soft edges or translucent pixels. Operations with an alpha or a color below 1, text and
+    /// smoothed shapes already are.
+    pub fn transparent(&mut self) -> &mut Self {
+        self.transparent = true;
 
         self
     }
+
+    /// Drops the pixels with an alpha under `cutoff`, after the color and the alpha of the
+    /// operation, and draws the others fully opaque instead of blending them. Pixel art with hard
+    /// cutout edges then keeps them crisp when scaled by a fraction. 0 goes back to blending.
+    pub fn alpha_cutoff(&mut self, cutoff: f32) -> &mut Self {
+        self.alpha_cutoff = cutoff.clamp(0.0, 1.0);
+
+        self
+    }
+
+    /// Whether the operation must be blended over what is behind it, rather than hiding it.
+    pub(crate) fn is_transparent(&self) -> bool {
+        self.transparent
+            || self.alpha < 1.0
+            || self.color_matrix.scales_alpha_down()
+            || self.flags & (INSTANCE_SDF | INSTANCE_SMOOTH_X | INSTANCE_SMOOTH_Y | INSTANCE_RING)
+                != 0
+    }
+}
+
+fn operation_instance(operation: &RenderOperation) -> Instance {
+    let mut position: RenderPosition = operation.rect.into();
+    position.transformation = position.transformation * operation.transforms.affine;
+
+    Instance::new(
+        operation.tex_coords,
+        position,
+        operation.color_matrix.multiply_alpha(operation.alpha),
+        operation.id,
+        operation.flags,
+        operation.array_layer,
+        operation.z,
+        operation.alpha_cutoff,
+        operation.ring,
+        operation.uv_rotation,
+    )
+}
+
+/// The instance of a copy of the sprite, see [`crate::Graphics::draw_instances`].
+fn sprite_instance(
+    (dimensions, tex_coords, array_layer): (SizeInPx, Rect, u32),
+    instance: &InstanceDesc,
+    transforms: Transform,
+    opacity: f32,
+) -> Instance {
+    let width = dimensions.width as f32 * instance.scale;
+    let height = dimensions.height as f32 * instance.scale;
+
+    let mut instance_transforms = transforms;
+    instance_transforms.rotate_centered(instance.rotation, width / 2.0, height / 2.0);
+
+    let rect = Rect::new(instance.position.left, instance.position.top, width, height);
+    let mut position: RenderPosition = rect.into();
+    position.transformation = position.transformation * instance_transforms.affine;
+
+    let tint = Color {
+        a: instance.tint.a * instance.alpha * opacity,
+        ..instance.tint
+    };
+    Instance::new(
+        tex_coords,
+        position,
+        ColorMatrix::from_color(tint),
+        0,
+        0,
+        array_layer,
+        0.0,
+        0.0,
+        [0.0; 4],
+        0.0,
+    )
 }
 
 pub(crate) fn prepare_draw_data(
@@ -325,24 +927,213 @@ pub(crate) fn prepare_draw_data(
         return None;
     }
 
-    let instances = (operation_block.operations.iter().map(|operation| {
-        let mut position: RenderPosition = operation.rect.into();
-        position.transformation = position.transformation * operation.transforms.affine;
+    let instances = operation_block.operations.iter().map(operation_instance);
 
-        Instance::new(operation.tex_coords, position, operation.color_matrix)
-    }))
-    .collect::<Vec<_>>();
-
-    let instance_buffer = buffer_cache.get_buffer(
-        device,
-        queue,
-        bytemuck::cast_slice(instances.as_slice()),
-        BufferUsages::VERTEX,
-    );
+    let instance_buffer = buffer_cache.get_instance_buffer(device, queue, instances);
 
     Some(DrawData {
         instance_buffer,
         count: count as u32,
         texture: operation_block.texture.clone(),
+        render_state: operation_block.render_state,
+        camera: operation_block.camera.clone(),
+        viewport: operation_block.viewport,
+        layer: operation_block.layer,
+        transparent: false,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn prepare_instances(
+    buffer_cache: &mut crate::cache::BufferCache,
+    device: &Device,
+    queue: &Queue,
+    sprite: &Sprite,
+    instances: &[InstanceDesc],
+    transforms: Transform,
+    opacity: f32,
+    camera: Option<Rc<BindGroup>>,
+) -> Option<DrawData> {
+    if instances.is_empty() {
+        return None;
+    }
+
+    let count = instances.len() as u32;
+    let sprite_parts = (sprite.dimensions, sprite.tex_coords, sprite.array_layer);
+    let instances = instances
+        .iter()
+        .map(|instance| sprite_instance(sprite_parts, instance, transforms, opacity));
+
+    let instance_buffer = buffer_cache.get_instance_buffer(device, queue, instances);
+
+    Some(DrawData {
+        instance_buffer,
+        count,
+        texture: sprite.texture.clone(),
+        render_state: RenderState::default(),
+        camera,
+        viewport: None,
+        layer: 0,
+        transparent: false,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+    use super::*;
+
+    fn operation(rect: Rect) -> RenderOperation {
+        RenderOperation::new(
+            rect,
+            Rect::new(0.0, 0.0, 1.0, 1.0),
+            ColorMatrix::from_color(Color::rgb(1.0, 1.0, 1.0)),
+            Transform::default(),
+        )
+    }
+
+    fn assert_close(actual: Position, expected: Position) {
+        assert!(
+            (actual.left - expected.left).abs() < 1e-4 && (actual.top - expected.top).abs() < 1e-4,
+            "{actual:?} isn't close to {expected:?}"
+        );
+    }
+
+    #[test]
+    fn rotate_around_the_top_left_corner_by_a_quarter_turn() {
+        let mut operation = operation(Rect::new(0.0, 0.0, 10.0, 20.0));
+        operation.rotate_around(FRAC_PI_2, Position::new(0.0, 0.0));
+
+        let transforms = operation.transforms;
+        assert_close(
+            transforms.transform_point(Position::new(0.0, 0.0)),
+            Position::new(0.0, 0.0),
+        );
+        assert_close(
+            transforms.transform_point(Position::new(10.0, 0.0)),
+            Position::new(0.0, 10.0),
+        );
+        assert_close(
+            transforms.transform_point(Position::new(0.0, 20.0)),
+            Position::new(-20.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn alpha_replaces_the_previous_call() {
+        let mut operation = operation(Rect::new(0.0, 0.0, 10.0, 10.0));
+        operation.alpha(0.5).alpha(0.5);
+
+        assert_eq!(operation.alpha, 0.5);
+    }
+
+    #[test]
+    fn alpha_is_clamped() {
+        let mut operation = operation(Rect::new(0.0, 0.0, 10.0, 10.0));
+        operation.alpha(2.0);
+
+        assert_eq!(operation.alpha, 1.0);
+    }
+
+    fn facing(facing: Facing) -> RenderOperation {
+        let mut operation = operation(Rect::new(0.0, 0.0, 10.0, 20.0));
+        operation.face(facing);
+        operation
+    }
+
+    #[test]
+    fn face_right_keeps_the_sprite() {
+        let operation = facing(Facing::Right);
+
+        assert_eq!(operation.tex_coords, Rect::new(0.0, 0.0, 1.0, 1.0));
+        assert_close(
+            operation
+                .transforms
+                .transform_point(Position::new(0.0, 0.0)),
+            Position::new(0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn face_left_mirrors_the_sprite_in_place() {
+        let operation = facing(Facing::Left);
+
+        assert_eq!(operation.tex_coords, Rect::new(1.0, 0.0, -1.0, 1.0));
+        assert_close(
+            operation
+                .transforms
+                .transform_point(Position::new(0.0, 0.0)),
+            Position::new(0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn face_towards_the_right_half_rotates_around_the_center() {
+        let operation = facing(Facing::Towards(FRAC_PI_2));
+
+        assert_eq!(operation.tex_coords, Rect::new(0.0, 0.0, 1.0, 1.0));
+        assert_close(
+            operation
+                .transforms
+                .transform_point(Position::new(5.0, 10.0)),
+            Position::new(5.0, 10.0),
+        );
+        assert_close(
+            operation
+                .transforms
+                .transform_point(Position::new(0.0, 0.0)),
+            Position::new(15.0, 5.0),
+        );
+    }
+
+    #[test]
+    fn face_towards_the_left_half_mirrors_then_rotates() {
+        let operation = facing(Facing::Towards(PI));
+
+        assert_eq!(operation.tex_coords, Rect::new(1.0, 0.0, -1.0, 1.0));
+        assert_close(
+            operation
+                .transforms
+                .transform_point(Position::new(0.0, 0.0)),
+            Position::new(0.0, 0.0),
+        );
+
+        let operation = facing(Facing::Towards(3.0 * FRAC_PI_4));
+
+        assert_eq!(operation.tex_coords, Rect::new(1.0, 0.0, -1.0, 1.0));
+        let mut expected = Transform::default();
+        expected.rotate_centered(-FRAC_PI_4, 5.0, 10.0);
+        assert_close(
+            operation
+                .transforms
+                .transform_point(Position::new(0.0, 0.0)),
+            expected.transform_point(Position::new(0.0, 0.0)),
+        );
+    }
+
+    #[test]
+    fn uv_rotation_happens_in_the_size_of_the_rotated_quad() {
+        let mut operation = operation(Rect::new(10.0, 20.0, 96.0, 48.0));
+        operation.rotate(FRAC_PI_4).rotate_uv(0.5);
+        let mut position: RenderPosition = operation.rect.into();
+        position.transformation = position.transformation * operation.transforms.affine;
+
+        let instance = Instance::new(
+            operation.tex_coords,
+            position,
+            operation.color_matrix,
+            0,
+            0,
+            0,
+            0.0,
+            0.0,
+            [0.0; 4],
+            operation.uv_rotation,
+        );
+
+        assert_eq!(instance.uv_rotation[0], 0.5);
+        assert!((instance.uv_rotation[1] - 96.0).abs() < 1e-3);
+        assert!((instance.uv_rotation[2] - 48.0).abs() < 1e-3);
+    }
+}
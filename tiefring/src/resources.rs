@@ -1,11 +1,22 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+};
 
+use image::RgbaImage;
 use wgpu::{Device, Queue};
 
 use crate::{
-    sprite::{Sprite, TextureContext, TileSet},
-    text::Font,
-    Error, SizeInPx,
+    futures::AsyncSprite,
+    shared::Shared,
+    sprite::{
+        decode_image, AnimatedSprite, Atlas, ExternalTexture, LoadProgress, Mask, Sprite,
+        SpriteHandle, Texture, TextureContext, TileSet,
+    },
+    text::{Font, FontSettings},
+    Error, Rect, SizeInPx,
 };
 
 pub struct Resources<'a> {
@@ -27,16 +38,212 @@ impl<'a> Resources<'a> {
         }
     }
 
+    /// A 1x1 white sprite, sharing its texture with [`crate::Graphics::draw_rect`] so that both
+    /// batch together.
+    pub fn white_sprite(&self) -> Sprite {
+        Sprite {
+            dimensions: SizeInPx::new(1, 1),
+            tex_coords: Rect::new(0.0, 0.0, 1.0, 1.0),
+            texture: self.texture_context.white_texture.clone(),
+            array_layer: 0,
+            source_color_type: None,
+        }
+    }
+
+    /// The device textures given to [`Resources::import_texture`] must be created with.
+    pub fn device(&self) -> &Device {
+        self.device
+    }
+
+    pub fn queue(&self) -> &Queue {
+        self.queue
+    }
+
+    /// Wraps a texture created with [`Resources::device`] so that it can be drawn. It needs the
+    /// `TEXTURE_BINDING` usage and a filterable format.
+    pub fn import_texture<S: Into<SizeInPx>>(
+        &self,
+        texture: wgpu::Texture,
+        dimensions: S,
+    ) -> ExternalTexture {
+        let dimensions = dimensions.into();
+        let texture = Texture::from_wgpu_texture(
+            self.device,
+            &self.texture_context.texture_bind_group_layout,
+            &self.texture_context.samplers,
+            texture,
+            dimensions,
+        );
+
+        ExternalTexture {
+            dimensions,
+            texture: Shared::new(texture),
+        }
+    }
+
+    /// How many textures are still alive, to check that dropping sprites frees them. This
+    /// includes the textures the crate holds itself, like font atlases.
+    #[cfg(feature = "debug")]
+    pub fn live_texture_count(&self) -> usize {
+        crate::sprite::live_textures().len()
+    }
+
+    /// The dimensions of every texture still alive, oldest first.
+    #[cfg(feature = "debug")]
+    pub fn live_textures(&self) -> Vec<SizeInPx> {
+        crate::sprite::live_textures()
+    }
+
     pub fn load_sprite<P: AsRef<Path>>(&self, path: P) -> Result<Sprite, Error> {
-        Sprite::load_image(
+        Sprite::load_image(self.device, self.queue, self.texture_context, path)
+    }
+
+    /// Loads images of the same size, like the frames of an animation or the tiles of a tileset,
+    /// as the layers of a single texture array. Their sprites then batch together whatever the
+    /// layer, as switching textures is what breaks batching.
+    pub fn load_sprite_array<P: AsRef<Path>>(&self, paths: &[P]) -> Result<Vec<Sprite>, Error> {
+        let mut images = Vec::with_capacity(paths.len());
+        let mut color_types = Vec::with_capacity(paths.len());
+        for path in paths {
+            let (image, color_type) = decode_image(path.as_ref())?;
+            images.push(image);
+            color_types.push(color_type);
+        }
+
+        let mut sprites =
+            Sprite::load_array(self.device, self.queue, self.texture_context, &images)?;
+        for (sprite, color_type) in sprites.iter_mut().zip(color_types) {
+            sprite.source_color_type = Some(color_type);
+        }
+        Ok(sprites)
+    }
+
+    /// Loads every image of a directory, keyed by file stem. Files that are not images are
+    /// skipped, and if some images fail to decode, the error lists them.
+    pub fn load_sprites_dir<P: AsRef<Path>>(
+        &self,
+        dir: P,
+    ) -> Result<HashMap<String, Sprite>, Error> {
+        let mut sprites = HashMap::new();
+        let mut failed = vec![];
+        self.load_sprites_in(dir.as_ref(), "", false, &mut sprites, &mut failed)?;
+
+        if failed.is_empty() {
+            Ok(sprites)
+        } else {
+            Err(Error::DirectoryLoadingFailed(failed))
+        }
+    }
+
+    /// Like [`Resources::load_sprites_dir`], but also goes through sub directories. Sprites are
+    /// then keyed by their path relative to `dir`, without extension, like `"characters/hero"`.
+    pub fn load_sprites_dir_recursive<P: AsRef<Path>>(
+        &self,
+        dir: P,
+    ) -> Result<HashMap<String, Sprite>, Error> {
+        let mut sprites = HashMap::new();
+        let mut failed = vec![];
+        self.load_sprites_in(dir.as_ref(), "", true, &mut sprites, &mut failed)?;
+
+        if failed.is_empty() {
+            Ok(sprites)
+        } else {
+            Err(Error::DirectoryLoadingFailed(failed))
+        }
+    }
+
+    fn load_sprites_in(
+        &self,
+        dir: &Path,
+        prefix: &str,
+        recursive: bool,
+        sprites: &mut HashMap<String, Sprite>,
+        failed: &mut Vec<PathBuf>,
+    ) -> Result<(), Error> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.is_dir() {
+                let dir_name = path.file_name().and_then(|name| name.to_str());
+                if let (true, Some(dir_name)) = (recursive, dir_name) {
+                    let prefix = format!("{prefix}{dir_name}/");
+                    self.load_sprites_in(&path, &prefix, true, sprites, failed)?;
+                }
+            } else if image::ImageFormat::from_path(&path).is_ok() {
+                let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                    Some(name) => format!("{prefix}{name}"),
+                    None => continue,
+                };
+
+                match self.load_sprite(&path) {
+                    Ok(sprite) => {
+                        sprites.insert(name, sprite);
+                    }
+                    Err(_) => failed.push(path),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the image on a worker thread, and uploads it once the future is polled after that.
+    /// The returned [`Sprite`] isn't `Send`, so the future must be polled on the render thread.
+    pub fn load_sprite_async<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> impl Future<Output = Result<Sprite, Error>> + 'a {
+        AsyncSprite::new(
             self.device,
             self.queue,
-            &self.texture_context.texture_bind_group_layout,
-            &self.texture_context.sampler,
-            path,
+            self.texture_context,
+            path.as_ref().to_path_buf(),
+        )
+    }
+
+    /// Like [`Resources::load_sprite_async`], but without a future: the game keeps rendering and
+    /// checks the handle every frame.
+    pub fn load_sprite_handle<P: AsRef<Path>>(&self, path: P) -> SpriteHandle {
+        SpriteHandle::new(path.as_ref().to_path_buf())
+    }
+
+    /// Like [`Resources::load_sprite_handle`], for several sprites at once, with the progress of
+    /// the whole set. The images are decoded on a few worker threads, no more than the machine
+    /// runs in parallel, and the progress advances as [`LoadProgress::update`] uploads them.
+    pub fn load_sprites_in_background<P: AsRef<Path>>(&self, paths: &[P]) -> LoadProgress {
+        LoadProgress::new(
+            paths
+                .iter()
+                .map(|path| path.as_ref().to_path_buf())
+                .collect(),
+        )
+    }
+
+    /// Packs the encoded images, PNG for instance, into shared textures, keyed by the given names.
+    /// If some images fail to decode, the error lists their names. Names must be unique.
+    pub fn pack_atlas(&self, images: &[(&str, &[u8])]) -> Result<Atlas, Error> {
+        Atlas::pack(self.device, self.queue, self.texture_context, images)
+    }
+
+    /// Creates a [`Mask`] from one byte of coverage per pixel, row by row.
+    pub fn load_mask_from_bytes<S: Into<SizeInPx>>(
+        &self,
+        data: &[u8],
+        dimensions: S,
+    ) -> Result<Mask, Error> {
+        Mask::load_data(
+            self.device,
+            self.queue,
+            self.texture_context,
+            data,
+            dimensions.into(),
         )
     }
 
+    pub fn load_gif<P: AsRef<Path>>(&self, path: P) -> Result<AnimatedSprite, Error> {
+        AnimatedSprite::load_gif(self.device, self.queue, self.texture_context, path)
+    }
+
     pub fn load_tileset<P, S>(&self, path: P, tile_dimensions: S) -> Result<TileSet, Error>
     where
         P: AsRef<Path>,
@@ -45,8 +252,7 @@ impl<'a> Resources<'a> {
         TileSet::load_image(
             self.device,
             self.queue,
-            &self.texture_context.texture_bind_group_layout,
-            &self.texture_context.sampler,
+            self.texture_context,
             path,
             tile_dimensions,
         )
@@ -56,40 +262,131 @@ impl<'a> Resources<'a> {
         Font::load_font(path)
     }
 
+    /// Like [`Resources::load_font`], with the settings fontdue parses the font with:
+    /// `collection_index` picks a font in a `.ttc` collection, and `scale` is the size the font
+    /// is optimized for.
+    pub fn load_font_with<P: AsRef<Path>>(
+        &self,
+        path: P,
+        settings: FontSettings,
+    ) -> Result<Font, Error> {
+        Font::load_font_with(path, settings)
+    }
+
+    /// Loads a TrueType or OpenType font held in memory, embedded in the binary for instance.
+    pub fn load_font_from_bytes(&self, bytes: &[u8]) -> Result<Font, Error> {
+        Font::load_font_bytes(bytes, FontSettings::default()).ok_or(Error::FontParsingFailed)
+    }
+
+    pub fn load_bmfont<P, I>(&self, fnt_path: P, page_image_path: I) -> Result<Font, Error>
+    where
+        P: AsRef<Path>,
+        I: AsRef<Path>,
+    {
+        Font::load_bmfont(
+            self.device,
+            self.queue,
+            self.texture_context,
+            fnt_path,
+            page_image_path,
+        )
+    }
+
+    pub(crate) fn upload_rgba(&self, rgba: &RgbaImage) -> Result<Sprite, Error> {
+        Sprite::load_data(
+            self.device,
+            self.queue,
+            self.texture_context,
+            rgba,
+            rgba.dimensions(),
+        )
+    }
+
+    /// Rasterizes the SVG at its own size.
     #[cfg(feature = "svg")]
     pub fn load_svg<P: AsRef<Path>>(&self, path: P) -> Result<Sprite, Error> {
-        let resources_dir = std::fs::canonicalize(&path)
+        self.load_svg_file(path.as_ref(), resvg::usvg::FitTo::Original)
+    }
+
+    /// Rasterizes an SVG held in memory, embedded in the binary for instance, at its own size.
+    /// Images it refers to with relative paths can't be found.
+    #[cfg(feature = "svg")]
+    pub fn load_svg_from_bytes(&self, data: &[u8]) -> Result<Sprite, Error> {
+        self.rasterize_svg(data, None, resvg::usvg::FitTo::Original, Path::new(""))
+    }
+
+    /// Like [`Resources::load_svg_from_bytes`], for SVG source text.
+    #[cfg(feature = "svg")]
+    pub fn load_svg_from_str(&self, svg: &str) -> Result<Sprite, Error> {
+        self.load_svg_from_bytes(svg.as_bytes())
+    }
+
+    /// Rasterizes the SVG to fit in `size` while keeping its aspect ratio, so that it stays crisp
+    /// when drawn at that size instead of being scaled up. The size is clamped to what the device
+    /// supports.
+    #[cfg(feature = "svg")]
+    pub fn load_svg_sized<P, S>(&self, path: P, size: S) -> Result<Sprite, Error>
+    where
+        P: AsRef<Path>,
+        S: Into<SizeInPx>,
+    {
+        let size: SizeInPx = size.into();
+        let max = self.device.limits().max_texture_dimension_2d;
+        let fit_to = resvg::usvg::FitTo::Size(size.width.clamp(1, max), size.height.clamp(1, max));
+        self.load_svg_file(path.as_ref(), fit_to)
+    }
+
+    #[cfg(feature = "svg")]
+    fn load_svg_file(&self, path: &Path, fit_to: resvg::usvg::FitTo) -> Result<Sprite, Error> {
+        // Images referred to by the SVG are relative to it.
+        let resources_dir = std::fs::canonicalize(path)
             .ok()
             .and_then(|p| p.parent().map(|p| p.to_path_buf()));
+        let svg_data = std::fs::read(path)?;
 
+        self.rasterize_svg(&svg_data, resources_dir, fit_to, path)
+    }
+
+    /// `path` is only used to report failures.
+    #[cfg(feature = "svg")]
+    fn rasterize_svg(
+        &self,
+        svg_data: &[u8],
+        resources_dir: Option<PathBuf>,
+        fit_to: resvg::usvg::FitTo,
+        path: &Path,
+    ) -> Result<Sprite, Error> {
         let opt = resvg::usvg::Options {
             resources_dir,
             ..Default::default()
         };
 
-        let svg_data = std::fs::read(&path)?;
-
-        let rtree = resvg::usvg::Tree::from_data(&svg_data, &opt)
-            .map_err(|_e| Error::LoadingFailed(path.as_ref().to_path_buf()))?;
-        let pixmap_size = rtree.size.to_screen_size();
+        let rtree = resvg::usvg::Tree::from_data(svg_data, &opt)
+            .map_err(|_e| Error::LoadingFailed(path.to_path_buf()))?;
+        let pixmap_size = fit_to
+            .fit_to(rtree.size.to_screen_size())
+            .ok_or_else(|| Error::LoadingFailed(path.to_path_buf()))?;
         let mut pixmap = resvg::tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height())
-            .ok_or_else(|| Error::LoadingFailed(path.as_ref().to_path_buf()))?;
+            .ok_or_else(|| Error::LoadingFailed(path.to_path_buf()))?;
 
         resvg::render(
             &rtree,
-            resvg::usvg::FitTo::Original,
+            fit_to,
             resvg::tiny_skia::Transform::default(),
             pixmap.as_mut(),
         )
-        .ok_or_else(|| Error::LoadingFailed(path.as_ref().to_path_buf()))?;
+        .ok_or_else(|| Error::LoadingFailed(path.to_path_buf()))?;
 
-        Ok(Sprite::load_data(
+        // tiny-skia renders with premultiplied alpha, while sprites are uploaded with straight
+        // alpha, premultiplied again if the canvas blends that way. Left as is, the anti-aliased
+        // edges would be premultiplied twice and look dark.
+        let rgba = crate::sprite::unpremultiply(pixmap.data());
+        Sprite::load_data(
             self.device,
             self.queue,
-            &self.texture_context.texture_bind_group_layout,
-            &self.texture_context.sampler,
-            pixmap.data(),
+            self.texture_context,
+            &rgba,
             pixmap_size.dimensions(),
-        ))
+        )
     }
 }
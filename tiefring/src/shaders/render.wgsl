@@ -18,6 +18,14 @@ struct InstanceInput {
     @location(6) color_matrix_2: vec4<f32>,
     @location(7) color_matrix_3: vec4<f32>,
     @location(8) color_adjust: vec4<f32>,
+    @location(9) id: u32,
+    @location(10) flags: u32,
+    @location(11) array_layer: u32,
+    @location(12) z: f32,
+    @location(13) alpha_cutoff: f32,
+    @location(14) ring: vec4<f32>,
+    // The angle, then the size of the quad in pixels.
+    @location(15) uv_rotation: vec3<f32>,
 }
 
 struct VertexOutput {
@@ -28,6 +36,28 @@ struct VertexOutput {
     @location(3) color_matrix_2: vec4<f32>,
     @location(4) color_matrix_3: vec4<f32>,
     @location(5) color_adjust: vec4<f32>,
+    @location(6) @interpolate(flat) id: u32,
+    @location(7) @interpolate(flat) flags: u32,
+    // The position in the quad, from 0 to 1 on both axes.
+    @location(8) local: vec2<f32>,
+    @location(9) @interpolate(flat) array_layer: u32,
+    @location(10) @interpolate(flat) alpha_cutoff: f32,
+    // The inner radius of a ring, as a fraction of the outer one, the softness of its edges, and
+    // the start angle and sweep of the sector drawn.
+    @location(11) @interpolate(flat) ring: vec4<f32>,
+}
+
+// Rotates the position in the quad around its center, so that the texture turns within the quad.
+// The rotation happens in pixels, otherwise the texture would shear on quads that aren't square.
+fn rotate_uv(uv: vec2<f32>, angle: f32, size: vec2<f32>) -> vec2<f32> {
+    let cos_angle = cos(angle);
+    let sin_angle = sin(angle);
+    let centered = (uv - vec2<f32>(0.5)) * size;
+    let rotated = vec2<f32>(
+        cos_angle * centered.x - sin_angle * centered.y,
+        sin_angle * centered.x + cos_angle * centered.y,
+    );
+    return rotated / max(size, vec2<f32>(0.0001)) + vec2<f32>(0.5);
 }
 
 @vertex
@@ -43,10 +73,12 @@ fn vs_main(
     );
 
     var out: VertexOutput;
-    out.clip_position = camera * vec4<f32>(position_matrix * vec3<f32>(model.position, 1.0), 1.0);
+    let position = position_matrix * vec3<f32>(model.position, 1.0);
+    out.clip_position = camera * vec4<f32>(position.xy, instance.z, 1.0);
+    let uv = rotate_uv(model.position, instance.uv_rotation.x, instance.uv_rotation.yz);
     out.tex_coords = (vec2<f32>(
-        model.position.x * instance.tex_coords.x + instance.tex_coords.y, 
-        model.position.y * instance.tex_coords.z + instance.tex_coords.w
+        uv.x * instance.tex_coords.x + instance.tex_coords.y, 
+        uv.y * instance.tex_coords.z + instance.tex_coords.w
     ));
     
     out.color_matrix_0 = instance.color_matrix_0;
@@ -54,17 +86,108 @@ fn vs_main(
     out.color_matrix_2 = instance.color_matrix_2;
     out.color_matrix_3 = instance.color_matrix_3;
     out.color_adjust = instance.color_adjust;
+    out.id = instance.id;
+    out.flags = instance.flags;
+    out.local = model.position;
+    out.array_layer = instance.array_layer;
+    out.alpha_cutoff = instance.alpha_cutoff;
+    out.ring = instance.ring;
     return out;
 }
 
 // Fragment shader
 @group(1) @binding(0)
-var t_diffuse: texture_2d<f32>;
+// Every texture is an array, most of them with a single layer.
+var t_diffuse: texture_2d_array<f32>;
 @group(1) @binding(1)
 var s_diffuse: sampler;
 
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+// Distance fields hold the distance to the edge of the glyph in the red channel, the edge being at
+// 0.5, which we turn back into a coverage as sharp as the screen allows.
+fn sample_texel(in: VertexOutput) -> vec4<f32> {
+    let texel = textureSample(t_diffuse, s_diffuse, in.tex_coords, i32(in.array_layer));
+    let smoothing = max(fwidth(texel.r) * 0.5, 0.001);
+    let coverage = smoothstep(0.5 - smoothing, 0.5 + smoothing, texel.r);
+    return select(texel, vec4<f32>(coverage), (in.flags & 1u) != 0u);
+}
+
+// Fades the smoothed edges of the quad over the pixel they cross, which anti-aliases shapes without
+// multisampling. Edges on pixel boundaries stay sharp.
+fn edge_coverage(in: VertexOutput) -> f32 {
+    // The distance to the closest edge on each axis, in pixels.
+    let pixels = min(in.local, vec2<f32>(1.0) - in.local) / max(fwidth(in.local), vec2<f32>(0.0001));
+    let coverage = clamp(pixels + vec2<f32>(0.5), vec2<f32>(0.0), vec2<f32>(1.0));
+    let x = select(1.0, coverage.x, (in.flags & 2u) != 0u);
+    let y = select(1.0, coverage.y, (in.flags & 4u) != 0u);
+    return x * y;
+}
+
+// How far inside of a straight edge of a sector the point is, faded over `smoothness` pixels.
+fn edge_side(distance: f32, smoothness: f32) -> f32 {
+    return clamp(distance / max(fwidth(distance) * smoothness, 0.00001) + 0.5, 0.0, 1.0);
+}
+
+// Rings and discs are drawn as a distance field over their square, with edges softened over
+// `smoothness` pixels. An inner radius of 0 fills the disc. A sweep under a full turn only keeps
+// the sector between the start angle and the start angle plus the sweep.
+fn ring_coverage(in: VertexOutput) -> f32 {
+    // Derivatives must be taken before branching on the flags.
+    let centered = in.local * 2.0 - vec2<f32>(1.0);
+    let distance = length(centered);
+    let softness = max(fwidth(distance) * in.ring.y, 0.00001);
+    let outer = 1.0 - smoothstep(1.0 - softness, 1.0, distance);
+    let inner = select(1.0, smoothstep(in.ring.x - softness, in.ring.x, distance), in.ring.x > 0.0);
+
+    // The sides of the sector, as the distances to the lines through the center along its edges.
+    let start = vec2<f32>(cos(in.ring.z), sin(in.ring.z));
+    let end = vec2<f32>(cos(in.ring.z + in.ring.w), sin(in.ring.z + in.ring.w));
+    let after_start = edge_side(start.x * centered.y - start.y * centered.x, in.ring.y);
+    let before_end = edge_side(centered.x * end.y - centered.y * end.x, in.ring.y);
+    // Up to half a turn the sector is on both sides, past it on either.
+    let wedge = select(
+        max(after_start, before_end),
+        min(after_start, before_end),
+        in.ring.w <= 3.14159265
+    );
+    let sector = select(wedge, 1.0, in.ring.w >= 6.28318530);
+
+    return select(1.0, outer * inner * sector, (in.flags & 16u) != 0u);
+}
+
+// Opaque operations write the depth, which their transparent pixels mustn't. Cutouts drop the
+// pixels under their cutoff and draw the others opaque, which keeps their edges crisp.
+// Called after anything taking derivatives, which are undefined once pixels of the quad are
+// discarded.
+fn alpha_test(in: VertexOutput, alpha: f32) -> f32 {
+    if ((in.flags & 8u) != 0u && alpha < 0.5) {
+        discard;
+    }
+    if (in.alpha_cutoff > 0.0) {
+        if (alpha < in.alpha_cutoff) {
+            discard;
+        }
+        return 1.0;
+    }
+    return alpha;
+}
+
+fn fragment_color(in: VertexOutput) -> vec4<f32> {
+    let color_matrix = mat4x4<f32>(
+        in.color_matrix_0,
+        in.color_matrix_1,
+        in.color_matrix_2,
+        in.color_matrix_3,
+    );
+
+    let color = color_matrix * sample_texel(in) + in.color_adjust;
+    // Derivatives must be taken before the alpha test may discard.
+    let coverage = edge_coverage(in) * ring_coverage(in);
+    return vec4<f32>(color.rgb, alpha_test(in, color.a) * coverage);
+}
+
+// Textures are premultiplied on upload so that filtering doesn't bleed the color of transparent
+// pixels. The color matrix still works on straight alpha, so we go back and forth around it.
+fn fragment_color_premultiplied(in: VertexOutput) -> vec4<f32> {
     let color_matrix = mat4x4<f32>(
         in.color_matrix_0,
         in.color_matrix_1,
@@ -72,5 +195,60 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         in.color_matrix_3,
     );
 
-    return color_matrix * textureSample(t_diffuse, s_diffuse, in.tex_coords) + in.color_adjust;
+    var texel = sample_texel(in);
+    if (texel.a > 0.0) {
+        texel = vec4<f32>(texel.rgb / texel.a, texel.a);
+    }
+    let color = color_matrix * texel + in.color_adjust;
+    let coverage = edge_coverage(in) * ring_coverage(in);
+    let alpha = alpha_test(in, color.a) * coverage;
+    return vec4<f32>(color.rgb * alpha, alpha);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return fragment_color(in);
+}
+
+@fragment
+fn fs_premultiplied(in: VertexOutput) -> @location(0) vec4<f32> {
+    return fragment_color_premultiplied(in);
+}
+
+struct PickingOutput {
+    @location(0) color: vec4<f32>,
+    @location(1) id: u32,
+}
+
+fn picking_output(color: vec4<f32>, id: u32) -> PickingOutput {
+    var out: PickingOutput;
+    out.color = color;
+    out.id = id;
+    return out;
+}
+
+@fragment
+fn fs_picking(in: VertexOutput) -> PickingOutput {
+    let color = fragment_color(in);
+    // Fully transparent pixels shouldn't hide what is under them.
+    if (color.a <= 0.0) {
+        discard;
+    }
+
+    return picking_output(color, in.id);
+}
+
+@fragment
+fn fs_picking_premultiplied(in: VertexOutput) -> PickingOutput {
+    let color = fragment_color_premultiplied(in);
+    if (color.a <= 0.0) {
+        discard;
+    }
+
+    return picking_output(color, in.id);
+}
+
+@fragment
+fn fs_overdraw(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(0.1, 0.1, 0.1, 1.0);
 }
\ No newline at end of file
@@ -0,0 +1,59 @@
+//! Reference counting for the resources. Everything is drawn from a single thread, so sprites,
+//! textures and fonts use `Rc` and `RefCell` by default, which cost less. The `sync` feature
+//! swaps them for `Arc` and `Mutex`, so that resources can be loaded on other threads and sent to
+//! the render thread.
+
+#[cfg(not(feature = "sync"))]
+use std::cell::{Ref, RefCell, RefMut};
+#[cfg(feature = "sync")]
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(not(feature = "sync"))]
+pub(crate) type Shared<T> = std::rc::Rc<T>;
+#[cfg(feature = "sync")]
+pub(crate) type Shared<T> = std::sync::Arc<T>;
+
+#[cfg(not(feature = "sync"))]
+pub(crate) type OnceCell<T> = std::cell::OnceCell<T>;
+#[cfg(feature = "sync")]
+pub(crate) type OnceCell<T> = std::sync::OnceLock<T>;
+
+/// Mutable state behind a [`Shared`] pointer.
+#[derive(Debug)]
+pub(crate) struct SharedCell<T> {
+    #[cfg(not(feature = "sync"))]
+    value: RefCell<T>,
+    #[cfg(feature = "sync")]
+    value: Mutex<T>,
+}
+
+impl<T> SharedCell<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value: value.into(),
+        }
+    }
+
+    #[cfg(not(feature = "sync"))]
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.value.borrow()
+    }
+
+    #[cfg(not(feature = "sync"))]
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        self.value.borrow_mut()
+    }
+
+    #[cfg(feature = "sync")]
+    pub fn borrow(&self) -> MutexGuard<'_, T> {
+        self.borrow_mut()
+    }
+
+    #[cfg(feature = "sync")]
+    pub fn borrow_mut(&self) -> MutexGuard<'_, T> {
+        // A panic while drawing text leaves the font in a usable state.
+        self.value
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
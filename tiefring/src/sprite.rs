@@ -1,60 +1,82 @@
-use std::{ops::Index, path::Path, rc::Rc, sync::atomic::AtomicUsize};
+#[cfg(feature = "debug")]
+use std::collections::BTreeMap;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    fs::File,
+    hash::Hash,
+    io::BufReader,
+    ops::Index,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::AtomicUsize,
+        mpsc::{channel, Receiver, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
-use wgpu::{BindGroup, BindGroupLayout, Device, Queue, Sampler, SamplerBindingType};
+use image::{ColorType, ImageError, RgbaImage};
+use rect_packer::Packer;
+use wgpu::{BindGroup, BindGroupLayout, Device, Queue, Sampler, SamplerBindingType, TextureView};
 
-use crate::{Error, Rect, SizeInPx};
+use crate::{
+    resources::Resources,
+    shared::{OnceCell, Shared},
+    CanvasSettings, Error, Rect, SizeInPx,
+};
 
 #[derive(Clone)]
 pub struct Sprite {
     pub dimensions: SizeInPx,
     pub(crate) tex_coords: Rect,
-    pub(crate) texture: Rc<Texture>,
+    pub(crate) texture: Shared<Texture>,
+    /// The layer of the texture, for sprites loaded as a texture array.
+    pub(crate) array_layer: u32,
+    pub(crate) source_color_type: Option<ColorType>,
 }
 
 impl Sprite {
     pub(crate) fn load_image<P: AsRef<Path>>(
         device: &Device,
         queue: &Queue,
-        texture_bind_group_layout: &BindGroupLayout,
-        sampler: &Sampler,
+        texture_context: &TextureContext,
         path: P,
     ) -> Result<Self, Error> {
-        let image = image::open(&path).map_err(|_e| Error::LoadingFailed(path.as_ref().into()))?;
+        let (rgba, color_type) = decode_image(path.as_ref())?;
 
-        let rgba = image.to_rgba8();
+        let mut sprite =
+            Sprite::load_data(device, queue, texture_context, &rgba, rgba.dimensions())?;
+        sprite.source_color_type = Some(color_type);
+        Ok(sprite)
+    }
 
-        use image::GenericImageView;
-        let dimensions = image.dimensions();
+    /// Whether the sprite is its whole texture, or its whole layer, rather than a part of an atlas
+    /// or a tileset.
+    pub(crate) fn covers_texture(&self) -> bool {
+        self.tex_coords == Rect::new(0.0, 0.0, 1.0, 1.0)
+    }
 
-        Ok(Sprite::load_data(
-            device,
-            queue,
-            texture_bind_group_layout,
-            sampler,
-            &rgba,
-            dimensions,
-        ))
+    /// The color type of the file the sprite was loaded from, to diagnose washed out or slow
+    /// loads: everything is converted to 8 bit RGBA, which is lossy for 16 bit and float images.
+    /// `None` for sprites that don't come straight from a file.
+    pub fn source_color_type(&self) -> Option<ColorType> {
+        self.source_color_type
     }
 
     pub(crate) fn load_data<S>(
         device: &Device,
         queue: &Queue,
-        texture_bind_group_layout: &BindGroupLayout,
-        sampler: &Sampler,
+        texture_context: &TextureContext,
         rgba: &[u8],
         dimensions: S,
-    ) -> Self
+    ) -> Result<Self, Error>
     where
         S: Into<SizeInPx> + Copy,
     {
-        let texture = Rc::new(Texture::new(
-            device,
-            queue,
-            texture_bind_group_layout,
-            sampler,
-            rgba,
-            dimensions.into(),
-        ));
+        let texture =
+            Shared::new(texture_context.create_texture(device, queue, rgba, dimensions.into())?);
         let tex_coord = Rect {
             left: 0.0,
             top: 0.0,
@@ -62,12 +84,667 @@ impl Sprite {
             height: 1.0,
         };
 
-        Sprite {
+        Ok(Sprite {
             dimensions: dimensions.into(),
             tex_coords: tex_coord,
             texture,
+            array_layer: 0,
+            source_color_type: None,
+        })
+    }
+
+    /// Uploads same sized images as the layers of a single texture, one sprite per layer. The
+    /// sprites all batch together when drawn, like the sprites of an atlas.
+    pub(crate) fn load_array(
+        device: &Device,
+        queue: &Queue,
+        texture_context: &TextureContext,
+        images: &[RgbaImage],
+    ) -> Result<Vec<Self>, Error> {
+        let dimensions = match images.first() {
+            Some(image) => SizeInPx::from(image.dimensions()),
+            None => return Ok(vec![]),
+        };
+        if let Some(image) = images
+            .iter()
+            .find(|image| SizeInPx::from(image.dimensions()) != dimensions)
+        {
+            return Err(Error::DimensionsMismatch {
+                expected: dimensions,
+                found: image.dimensions().into(),
+            });
+        }
+
+        let layers: Vec<&[u8]> = images
+            .iter()
+            .map(|image| image.as_raw().as_slice())
+            .collect();
+        let texture =
+            Shared::new(texture_context.create_texture_array(device, queue, &layers, dimensions)?);
+
+        Ok((0..images.len() as u32)
+            .map(|array_layer| Sprite {
+                dimensions,
+                tex_coords: Rect::new(0.0, 0.0, 1.0, 1.0),
+                texture: texture.clone(),
+                array_layer,
+                source_color_type: None,
+            })
+            .collect())
+    }
+}
+
+/// Decodes the image to 8 bit RGBA, along with its original color type. Images already in that
+/// format are used as is.
+pub(crate) fn decode_image(path: &Path) -> Result<(RgbaImage, ColorType), Error> {
+    let image = image::open(path).map_err(|error| match error {
+        ImageError::Unsupported(_) => Error::UnsupportedImageFormat(path.into()),
+        _ => Error::LoadingFailed(path.into()),
+    })?;
+    let color_type = image.color();
+
+    Ok((image.into_rgba8(), color_type))
+}
+
+/// Decodes an image on a worker thread. Only the decoding happens there: without the `sync`
+/// feature, textures are reference counted with `Rc`, so the upload and the resulting sprite stay
+/// on the render thread.
+pub(crate) fn decode_in_background(
+    path: PathBuf,
+) -> Receiver<Result<(RgbaImage, ColorType), Error>> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let decoded = decode_image(&path);
+        // Nobody is listening anymore if the loading was abandoned, which is fine.
+        let _ = sender.send(decoded);
+    });
+
+    receiver
+}
+
+/// Like [`decode_in_background`] for several images, decoded in order by at most `workers`
+/// threads rather than one thread each. Each image gets its own receiver, in the order of
+/// `paths`.
+pub(crate) fn decode_all_in_background(
+    paths: Vec<PathBuf>,
+    workers: usize,
+) -> Vec<Receiver<Result<(RgbaImage, ColorType), Error>>> {
+    let (jobs, receivers): (VecDeque<_>, Vec<_>) = paths
+        .into_iter()
+        .map(|path| {
+            let (sender, receiver) = channel();
+            ((path, sender), receiver)
+        })
+        .unzip();
+
+    let workers = workers.clamp(1, jobs.len().max(1));
+    let jobs = Arc::new(Mutex::new(jobs));
+    for _ in 0..workers {
+        let jobs = jobs.clone();
+        thread::spawn(move || loop {
+            let job = jobs
+                .lock()
+                .expect("No worker panics with the lock")
+                .pop_front();
+            let (path, sender) = match job {
+                Some(job) => job,
+                None => return,
+            };
+            let decoded = decode_image(&path);
+            // Nobody is listening anymore if the loading was abandoned, which is fine.
+            let _ = sender.send(decoded);
+        });
+    }
+
+    receivers
+}
+
+/// A texture created outside of tiefring, like a video frame or the output of another renderer,
+/// drawn with [`crate::Graphics::draw_texture`].
+#[derive(Clone, Debug)]
+pub struct ExternalTexture {
+    pub dimensions: SizeInPx,
+    pub(crate) texture: Shared<Texture>,
+}
+
+impl ExternalTexture {
+    pub fn wgpu_texture(&self) -> &wgpu::Texture {
+        &self.texture.texture
+    }
+
+    /// A sprite covering the whole texture, sharing it.
+    pub fn to_sprite(&self) -> Sprite {
+        Sprite {
+            dimensions: self.dimensions,
+            tex_coords: Rect::new(0.0, 0.0, 1.0, 1.0),
+            texture: self.texture.clone(),
+            array_layer: 0,
+            source_color_type: None,
+        }
+    }
+}
+
+/// A single channel texture, drawn with [`crate::Graphics::draw_mask`] as the coverage of a color,
+/// like light cookies or brushes.
+#[derive(Clone, Debug)]
+pub struct Mask {
+    pub dimensions: SizeInPx,
+    pub(crate) texture: Shared<Texture>,
+}
+
+impl Mask {
+    pub(crate) fn load_data(
+        device: &Device,
+        queue: &Queue,
+        texture_context: &TextureContext,
+        data: &[u8],
+        dimensions: SizeInPx,
+    ) -> Result<Self, Error> {
+        check_mask_data(data, dimensions)?;
+        check_texture_size(device, dimensions)?;
+
+        let texture_size = wgpu::Extent3d {
+            width: dimensions.width,
+            height: dimensions.height,
+            depth_or_array_layers: 1,
+        };
+        // The same format as the glyph atlases, so that the coverage ends up in the red channel.
+        let wgpu_texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: texture_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            label: Some("mask"),
+        });
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &wgpu_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(dimensions.width),
+                rows_per_image: std::num::NonZeroU32::new(dimensions.height),
+            },
+            texture_size,
+        );
+
+        let texture = Texture::from_wgpu_texture(
+            device,
+            &texture_context.texture_bind_group_layout,
+            &texture_context.samplers,
+            wgpu_texture,
+            dimensions,
+        );
+
+        Ok(Mask {
+            dimensions,
+            texture: Shared::new(texture),
+        })
+    }
+}
+
+/// Checks that a mask has a pixel, and exactly one byte for each of its pixels.
+fn check_mask_data(data: &[u8], dimensions: SizeInPx) -> Result<(), Error> {
+    let len = (dimensions.width as usize).checked_mul(dimensions.height as usize);
+    match len {
+        Some(len) if len != 0 && len == data.len() => Ok(()),
+        _ => Err(Error::InvalidDimensions(dimensions)),
+    }
+}
+
+/// A sprite decoded in the background, see [`Resources::load_sprite_handle`].
+pub struct SpriteHandle {
+    path: PathBuf,
+    state: SpriteHandleState,
+}
+
+enum SpriteHandleState {
+    Decoding(Receiver<Result<(RgbaImage, ColorType), Error>>),
+    Ready(Sprite),
+    Failed(Error),
+}
+
+impl SpriteHandle {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        let receiver = decode_in_background(path.clone());
+        Self::decoding(path, receiver)
+    }
+
+    fn decoding(path: PathBuf, receiver: Receiver<Result<(RgbaImage, ColorType), Error>>) -> Self {
+        Self {
+            path,
+            state: SpriteHandleState::Decoding(receiver),
+        }
+    }
+
+    /// Returns the sprite once it is decoded, the first call after that uploads it to the GPU.
+    pub fn get(&mut self, resources: &Resources) -> Option<&Sprite> {
+        if let SpriteHandleState::Decoding(receiver) = &self.state {
+            match receiver.try_recv() {
+                Ok(Ok((rgba, color_type))) => {
+                    self.state = match resources.upload_rgba(&rgba) {
+                        Ok(mut sprite) => {
+                            sprite.source_color_type = Some(color_type);
+                            SpriteHandleState::Ready(sprite)
+                        }
+                        Err(error) => SpriteHandleState::Failed(error),
+                    }
+                }
+                Ok(Err(error)) => self.state = SpriteHandleState::Failed(error),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => {
+                    self.state = SpriteHandleState::Failed(Error::LoadingFailed(self.path.clone()))
+                }
+            }
+        }
+
+        match &self.state {
+            SpriteHandleState::Ready(sprite) => Some(sprite),
+            _ => None,
+        }
+    }
+
+    /// The reason the sprite couldn't be loaded, if it failed.
+    pub fn error(&self) -> Option<&Error> {
+        match &self.state {
+            SpriteHandleState::Failed(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Whether the sprite is loaded or failed to.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.state, SpriteHandleState::Decoding(_))
+    }
+
+    fn sprite(&self) -> Option<&Sprite> {
+        match &self.state {
+            SpriteHandleState::Ready(sprite) => Some(sprite),
+            _ => None,
+        }
+    }
+}
+
+/// Sprites decoded in the background, see [`Resources::load_sprites_in_background`], with how
+/// far along they are, for a loading screen. The progress only advances when
+/// [`LoadProgress::update`] is called: that is when decoded images are uploaded and counted.
+pub struct LoadProgress {
+    handles: Vec<SpriteHandle>,
+}
+
+impl LoadProgress {
+    /// Decodes the images on as many threads as the machine runs in parallel, at most.
+    pub(crate) fn new(paths: Vec<PathBuf>) -> Self {
+        let workers = thread::available_parallelism().map_or(1, usize::from);
+        let receivers = decode_all_in_background(paths.clone(), workers);
+
+        Self {
+            handles: paths
+                .into_iter()
+                .zip(receivers)
+                .map(|(path, receiver)| SpriteHandle::decoding(path, receiver))
+                .collect(),
         }
     }
+
+    /// Uploads the sprites decoded since the last call. To be called every frame, on the render
+    /// thread, until [`LoadProgress::is_done`].
+    pub fn update(&mut self, resources: &Resources) {
+        for handle in &mut self.handles {
+            handle.get(resources);
+        }
+    }
+
+    /// How many sprites are loaded or failed to, out of the total, as of the last
+    /// [`LoadProgress::update`].
+    pub fn progress(&self) -> (usize, usize) {
+        let finished = self
+            .handles
+            .iter()
+            .filter(|handle| handle.is_finished())
+            .count();
+        (finished, self.handles.len())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.handles.iter().all(SpriteHandle::is_finished)
+    }
+
+    /// The sprite for the path at `index`, once loaded.
+    pub fn sprite(&self, index: usize) -> Option<&Sprite> {
+        self.handles.get(index).and_then(SpriteHandle::sprite)
+    }
+
+    /// Why the path at `index` couldn't be loaded, if it failed.
+    pub fn error(&self, index: usize) -> Option<&Error> {
+        self.handles.get(index).and_then(SpriteHandle::error)
+    }
+}
+
+/// Where a [`TextureCache`] loads a sprite from, again after it was evicted.
+pub enum TextureSource {
+    /// An image file.
+    Path(PathBuf),
+    /// An encoded image, PNG for instance.
+    Bytes(Vec<u8>),
+    /// Anything else, like a generated texture.
+    Custom(LoadSprite),
+}
+
+type LoadSprite = Box<dyn Fn(&Resources) -> Result<Sprite, Error>>;
+
+impl TextureSource {
+    fn load(&self, resources: &Resources) -> Result<Sprite, Error> {
+        match self {
+            TextureSource::Path(path) => resources.load_sprite(path),
+            TextureSource::Bytes(bytes) => {
+                let rgba = image::load_from_memory(bytes)
+                    .map_err(Error::ImageDecodingFailed)?
+                    .into_rgba8();
+                resources.upload_rgba(&rgba)
+            }
+            TextureSource::Custom(load) => load(resources),
+        }
+    }
+}
+
+struct CachedTexture {
+    source: TextureSource,
+    sprite: Option<Sprite>,
+}
+
+/// Sprites loaded on demand from their source, within a budget of texture memory, for worlds too
+/// large to keep every texture around. When a load goes over the budget, the least recently used
+/// sprites are dropped, and loaded again the next time they are needed. A texture is only freed
+/// once no clone of its sprite is left, so sprites are best fetched from the cache every frame
+/// rather than kept.
+pub struct TextureCache<K> {
+    residency: Residency<K>,
+    entries: HashMap<K, CachedTexture>,
+}
+
+impl<K: Eq + Hash + Clone> TextureCache<K> {
+    /// The budget is in bytes, with textures estimated at 4 bytes per pixel, mipmaps excluded.
+    pub fn new(budget: u64) -> Self {
+        Self {
+            residency: Residency::new(budget),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Registers where to load the sprite for `key` from. Nothing is loaded until it is needed.
+    pub fn insert(&mut self, key: K, source: TextureSource) {
+        self.remove(&key);
+        self.entries.insert(
+            key,
+            CachedTexture {
+                source,
+                sprite: None,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        self.evict(key);
+        self.entries.remove(key);
+    }
+
+    /// The sprite for `key`, loaded from its source if it isn't already, or `None` if nothing was
+    /// registered for it.
+    pub fn get(&mut self, key: &K, resources: &Resources) -> Result<Option<Sprite>, Error> {
+        let entry = match self.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        if let Some(sprite) = &entry.sprite {
+            self.residency.touch(key);
+            return Ok(Some(sprite.clone()));
+        }
+
+        let sprite = entry.source.load(resources)?;
+        entry.sprite = Some(sprite.clone());
+        for evicted in self.residency.load(key.clone(), texture_memory(&sprite)) {
+            if let Some(entry) = self.entries.get_mut(&evicted) {
+                entry.sprite = None;
+            }
+        }
+        Ok(Some(sprite))
+    }
+
+    pub fn is_loaded(&self, key: &K) -> bool {
+        matches!(self.entries.get(key), Some(entry) if entry.sprite.is_some())
+    }
+
+    /// Drops the sprite for `key`, to be loaded again the next time it is needed.
+    pub fn evict(&mut self, key: &K) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.sprite = None;
+        }
+        self.residency.unload(key);
+    }
+
+    /// The estimated memory of the sprites currently loaded, in bytes.
+    pub fn memory_used(&self) -> u64 {
+        self.residency.used
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.residency.budget
+    }
+
+    pub fn set_budget(&mut self, budget: u64) {
+        for evicted in self.residency.set_budget(budget) {
+            if let Some(entry) = self.entries.get_mut(&evicted) {
+                entry.sprite = None;
+            }
+        }
+    }
+}
+
+/// The bookkeeping of a [`TextureCache`]: the memory of each loaded key and when it was last
+/// used, to pick what to evict once over the budget.
+#[derive(Debug)]
+struct Residency<K> {
+    /// In bytes.
+    budget: u64,
+    used: u64,
+    use_count: u64,
+    /// The memory and last use of each loaded key.
+    loaded: HashMap<K, (u64, u64)>,
+}
+
+impl<K: Eq + Hash + Clone> Residency<K> {
+    fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            used: 0,
+            use_count: 0,
+            loaded: HashMap::new(),
+        }
+    }
+
+    /// Marks a loaded key as the most recently used.
+    fn touch(&mut self, key: &K) {
+        self.use_count += 1;
+        if let Some((_, last_used)) = self.loaded.get_mut(key) {
+            *last_used = self.use_count;
+        }
+    }
+
+    /// Records `key` as loaded and most recently used, and returns the keys evicted to fit in
+    /// the budget again.
+    fn load(&mut self, key: K, memory: u64) -> Vec<K> {
+        self.unload(&key);
+        self.use_count += 1;
+        self.used += memory;
+        self.loaded.insert(key, (memory, self.use_count));
+        self.evict_over_budget()
+    }
+
+    fn unload(&mut self, key: &K) {
+        if let Some((memory, _)) = self.loaded.remove(key) {
+            self.used -= memory;
+        }
+    }
+
+    /// Changes the budget, and returns the keys evicted to fit in it.
+    fn set_budget(&mut self, budget: u64) -> Vec<K> {
+        self.budget = budget;
+        self.evict_over_budget()
+    }
+
+    /// Evicts the least recently used keys until the memory used fits in the budget. The most
+    /// recently used one is kept even if it doesn't fit on its own.
+    fn evict_over_budget(&mut self) -> Vec<K> {
+        let mut evicted = vec![];
+        while self.used > self.budget {
+            let least_recently_used = self
+                .loaded
+                .iter()
+                .filter(|(_, &(_, last_used))| last_used != self.use_count)
+                .min_by_key(|(_, &(_, last_used))| last_used)
+                .map(|(key, _)| key.clone());
+            match least_recently_used {
+                Some(key) => {
+                    self.unload(&key);
+                    evicted.push(key);
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+}
+
+fn texture_memory(sprite: &Sprite) -> u64 {
+    sprite.dimensions.width as u64 * sprite.dimensions.height as u64 * 4
+}
+
+// Browsers treat a zero delay as "as fast as possible", which in practice means 100ms.
+const DEFAULT_GIF_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
+pub struct AnimatedSprite {
+    frames: Vec<Sprite>,
+    delays: Vec<Duration>,
+    current_frame: usize,
+    elapsed: Duration,
+}
+
+impl AnimatedSprite {
+    pub fn new(frames: Vec<Sprite>, delays: Vec<Duration>) -> Self {
+        assert!(
+            !frames.is_empty(),
+            "An animated sprite needs at least one frame"
+        );
+        assert_eq!(
+            frames.len(),
+            delays.len(),
+            "Each frame needs exactly one delay"
+        );
+        assert!(
+            delays.iter().all(|delay| !delay.is_zero()),
+            "Frame delays must be above zero"
+        );
+
+        Self {
+            frames,
+            delays,
+            current_frame: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn load_gif<P: AsRef<Path>>(
+        device: &Device,
+        queue: &Queue,
+        texture_context: &TextureContext,
+        path: P,
+    ) -> Result<Self, Error> {
+        use image::{codecs::gif::GifDecoder, AnimationDecoder};
+
+        let file = File::open(&path)?;
+        let decoder = GifDecoder::new(BufReader::new(file))
+            .map_err(|_e| Error::LoadingFailed(path.as_ref().into()))?;
+
+        // The decoder composites each frame onto the full canvas, which takes care of the
+        // disposal methods for us.
+        let decoded_frames = decoder
+            .into_frames()
+            .collect_frames()
+            .map_err(|_e| Error::LoadingFailed(path.as_ref().into()))?;
+
+        if decoded_frames.is_empty() {
+            return Err(Error::LoadingFailed(path.as_ref().into()));
+        }
+
+        let mut frames = Vec::with_capacity(decoded_frames.len());
+        let mut delays = Vec::with_capacity(decoded_frames.len());
+        for frame in decoded_frames {
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            let delay = Duration::from_secs_f64(numerator as f64 / denominator as f64 / 1000.0);
+            delays.push(if delay.is_zero() {
+                DEFAULT_GIF_FRAME_DELAY
+            } else {
+                delay
+            });
+
+            let buffer = frame.into_buffer();
+            let dimensions = buffer.dimensions();
+            frames.push(Sprite::load_data(
+                device,
+                queue,
+                texture_context,
+                &buffer,
+                dimensions,
+            )?);
+        }
+
+        Ok(AnimatedSprite::new(frames, delays))
+    }
+
+    pub fn update(&mut self, dt: Duration) {
+        (self.current_frame, self.elapsed) =
+            advance_frame(&self.delays, self.current_frame, self.elapsed + dt);
+    }
+
+    pub fn current(&self) -> &Sprite {
+        &self.frames[self.current_frame]
+    }
+
+    pub fn current_frame(&self) -> usize {
+        self.current_frame
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn reset(&mut self) {
+        self.current_frame = 0;
+        self.elapsed = Duration::ZERO;
+    }
+}
+
+/// The frame shown after `elapsed` was spent since `current_frame` started, wrapping around, and
+/// the time already spent on it.
+fn advance_frame(
+    delays: &[Duration],
+    mut current_frame: usize,
+    mut elapsed: Duration,
+) -> (usize, Duration) {
+    while elapsed >= delays[current_frame] {
+        elapsed -= delays[current_frame];
+        current_frame = (current_frame + 1) % delays.len();
+    }
+    (current_frame, elapsed)
 }
 
 pub struct TileSet {
@@ -77,11 +754,10 @@ pub struct TileSet {
 }
 
 impl TileSet {
-    pub fn load_image<P, S>(
+    pub(crate) fn load_image<P, S>(
         device: &Device,
         queue: &Queue,
-        texture_bind_group_layout: &BindGroupLayout,
-        sampler: &Sampler,
+        texture_context: &TextureContext,
         path: P,
         tile_dimensions: S,
     ) -> Result<Self, Error>
@@ -89,22 +765,17 @@ impl TileSet {
         P: AsRef<Path>,
         S: Into<SizeInPx> + Copy,
     {
-        let image = image::open(&path).map_err(|_e| Error::LoadingFailed(path.as_ref().into()))?;
+        let (rgba, _) = decode_image(path.as_ref())?;
+        let dimensions = rgba.dimensions();
 
-        let rgba = image.to_rgba8();
-
-        use image::GenericImageView;
-        let dimensions = image.dimensions();
-
-        Ok(TileSet::load_data::<(u32, u32), S>(
+        TileSet::load_data::<(u32, u32), S>(
             device,
             queue,
-            texture_bind_group_layout,
-            sampler,
+            texture_context,
             &rgba,
             dimensions,
             tile_dimensions,
-        ))
+        )
     }
 
     pub fn tile_count(&self) -> (u32, u32) {
@@ -131,24 +802,17 @@ impl TileSet {
     fn load_data<S, TS>(
         device: &Device,
         queue: &Queue,
-        texture_bind_group_layout: &BindGroupLayout,
-        sampler: &Sampler,
+        texture_context: &TextureContext,
         rgba: &[u8],
         dimensions: S,
         tile_dimensions: TS,
-    ) -> Self
+    ) -> Result<Self, Error>
     where
         S: Into<SizeInPx> + Copy,
         TS: Into<SizeInPx> + Copy,
     {
-        let texture = Rc::new(Texture::new(
-            device,
-            queue,
-            texture_bind_group_layout,
-            sampler,
-            rgba,
-            dimensions.into(),
-        ));
+        let texture =
+            Shared::new(texture_context.create_texture(device, queue, rgba, dimensions.into())?);
         let dimensions = dimensions.into();
         let tile_dimensions = tile_dimensions.into();
 
@@ -169,16 +833,18 @@ impl TileSet {
                     dimensions: tile_dimensions,
                     tex_coords,
                     texture: texture.clone(),
+                    array_layer: 0,
+                    source_color_type: None,
                 };
                 sprites.push(sprite);
             }
         }
 
-        TileSet {
+        Ok(TileSet {
             dimensions,
             tile_dimensions,
             sprites,
-        }
+        })
     }
 }
 
@@ -190,95 +856,519 @@ impl Index<usize> for TileSet {
     }
 }
 
+/// The size of the pages of an [`Atlas`], which every adapter supports.
+const ATLAS_PAGE_SIZE: u32 = 2048;
+
+/// The images packed in one texture of an [`Atlas`], with where they are.
+struct AtlasPage<'a> {
+    packer: Packer,
+    images: Vec<(&'a str, &'a RgbaImage, rect_packer::Rect)>,
+}
+
+/// The first name that comes up a second time.
+fn first_duplicate<'a>(names: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let mut seen = HashSet::new();
+    names.into_iter().find(|name| !seen.insert(*name))
+}
+
+/// Many small images packed into as few textures as possible, so that drawing them batches
+/// together. Images that don't fit in the first page spill to other ones.
+pub struct Atlas {
+    sprites: HashMap<String, Sprite>,
+    page_count: usize,
+}
+
+impl Atlas {
+    pub(crate) fn pack(
+        device: &Device,
+        queue: &Queue,
+        texture_context: &TextureContext,
+        images: &[(&str, &[u8])],
+    ) -> Result<Self, Error> {
+        if let Some(name) = first_duplicate(images.iter().map(|(name, _)| *name)) {
+            return Err(Error::DuplicateName(name.to_string()));
+        }
+
+        let mut decoded = Vec::with_capacity(images.len());
+        let mut failed = vec![];
+        for (name, data) in images {
+            match image::load_from_memory(data) {
+                Ok(image) => decoded.push((*name, image.to_rgba8())),
+                Err(_) => failed.push(name.to_string()),
+            }
+        }
+        if !failed.is_empty() {
+            return Err(Error::DecodingFailed(failed));
+        }
+
+        // The tallest images first, which leaves less room unused in each row.
+        decoded.sort_by_key(|(_, image)| Reverse(image.height()));
+
+        let mut pages: Vec<AtlasPage> = vec![];
+        for (name, image) in &decoded {
+            let (width, height) = (image.width() as i32, image.height() as i32);
+            let page = pages
+                .iter_mut()
+                .find(|page| page.packer.can_pack(width, height, false));
+            let page = match page {
+                Some(page) => page,
+                None => {
+                    // One pixel between images, so that filtering doesn't bleed one into another.
+                    let packer = Packer::new(rect_packer::Config {
+                        width: ATLAS_PAGE_SIZE as i32,
+                        height: ATLAS_PAGE_SIZE as i32,
+                        border_padding: 0,
+                        rectangle_padding: 1,
+                    });
+                    pages.push(AtlasPage {
+                        packer,
+                        images: vec![],
+                    });
+                    pages.last_mut().expect("Just pushed a page")
+                }
+            };
+            let packed = page
+                .packer
+                .pack(width, height, false)
+                .ok_or_else(|| Error::TooLargeForAtlas(name.to_string()))?;
+            page.images.push((name, image, packed));
+        }
+
+        let mut sprites = HashMap::with_capacity(decoded.len());
+        for page in &pages {
+            // Pages are only as big as what they hold.
+            let (width, height) = page.images.iter().fold((1, 1), |(width, height), packed| {
+                let rect = packed.2;
+                (
+                    width.max((rect.x + rect.width) as u32),
+                    height.max((rect.y + rect.height) as u32),
+                )
+            });
+            let mut pixels = RgbaImage::new(width, height);
+            for (_, image, rect) in &page.images {
+                image::imageops::replace(&mut pixels, *image, rect.x as i64, rect.y as i64);
+            }
+            let texture = Shared::new(texture_context.create_texture(
+                device,
+                queue,
+                &pixels,
+                SizeInPx::new(width, height),
+            )?);
+
+            for (name, _, rect) in &page.images {
+                let sprite = Sprite {
+                    dimensions: SizeInPx::new(rect.width as u32, rect.height as u32),
+                    tex_coords: Rect::new(
+                        rect.x as f32 / width as f32,
+                        rect.y as f32 / height as f32,
+                        rect.width as f32 / width as f32,
+                        rect.height as f32 / height as f32,
+                    ),
+                    texture: texture.clone(),
+                    array_layer: 0,
+                    source_color_type: None,
+                };
+                sprites.insert(name.to_string(), sprite);
+            }
+        }
+
+        Ok(Atlas {
+            sprites,
+            page_count: pages.len(),
+        })
+    }
+
+    pub fn sprite(&self, name: &str) -> Option<&Sprite> {
+        self.sprites.get(name)
+    }
+
+    /// The number of textures the images were packed in.
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
+}
+
+impl Index<&str> for Atlas {
+    type Output = Sprite;
+
+    fn index(&self, name: &str) -> &Self::Output {
+        &self.sprites[name]
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 pub(crate) struct TextureId(pub(crate) usize);
 
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub(crate) enum SamplerKind {
+    #[default]
+    Clamp,
+    Repeat,
+    /// Clamped, filtering with nearest or linear whatever the canvas settings.
+    Nearest,
+    Linear,
+}
+
 #[derive(Debug)]
 pub(crate) struct Texture {
     pub id: TextureId,
     pub texture: wgpu::Texture,
     pub texture_bind_group: BindGroup,
+    pub repeat_texture_bind_group: BindGroup,
+    /// Only created for the textures drawn with an explicit filter, see
+    /// [`Texture::prepare_bind_group`].
+    nearest_texture_bind_group: OnceCell<BindGroup>,
+    linear_texture_bind_group: OnceCell<BindGroup>,
 }
 
 pub(crate) static TEXTURE_INDEX: AtomicUsize = AtomicUsize::new(0);
 
+/// The size of every texture not dropped yet, by id, to hunt for leaks.
+#[cfg(feature = "debug")]
+static LIVE_TEXTURES: Mutex<BTreeMap<TextureId, SizeInPx>> = Mutex::new(BTreeMap::new());
+
+#[cfg(feature = "debug")]
+pub(crate) fn live_textures() -> Vec<SizeInPx> {
+    let live_textures = LIVE_TEXTURES
+        .lock()
+        .unwrap_or_else(|error| error.into_inner());
+    live_textures.values().copied().collect()
+}
+
 impl Texture {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         device: &Device,
         queue: &Queue,
         texture_bind_group_layout: &BindGroupLayout,
-        sampler: &Sampler,
-        rgba: &[u8],
+        samplers: &Samplers,
+        layers: &[&[u8]],
         dimensions: SizeInPx,
-    ) -> Self {
-        let id = TEXTURE_INDEX.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        generate_mipmaps: bool,
+        srgb: bool,
+    ) -> Result<Self, Error> {
+        check_texture_size(device, dimensions)?;
+        let max_layers = device.limits().max_texture_array_layers;
+        if layers.len() as u32 > max_layers {
+            return Err(Error::TooManyArrayLayers {
+                requested: layers.len() as u32,
+                max: max_layers,
+            });
+        }
+
         let texture_size = wgpu::Extent3d {
             width: dimensions.width,
             height: dimensions.height,
-            depth_or_array_layers: 1,
+            depth_or_array_layers: layers.len() as u32,
+        };
+        let mip_level_count = if generate_mipmaps {
+            texture_size.max_mips(wgpu::TextureDimension::D2)
+        } else {
+            1
         };
         let wgpu_texture = device.create_texture(&wgpu::TextureDescriptor {
             size: texture_size,
-            mip_level_count: 1,
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8Unorm,
+            format: if srgb {
+                wgpu::TextureFormat::Rgba8UnormSrgb
+            } else {
+                wgpu::TextureFormat::Rgba8Unorm
+            },
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
             label: Some("texture"),
         });
 
-        queue.write_texture(
-            // Tells wgpu where to copy the pixel data
-            wgpu::ImageCopyTexture {
-                texture: &wgpu_texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            // The actual pixel data
-            rgba,
-            // The layout of the texture
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: std::num::NonZeroU32::new(4 * dimensions.width),
-                rows_per_image: std::num::NonZeroU32::new(dimensions.height),
-            },
-            texture_size,
-        );
-
-        let texture_view = wgpu_texture.create_view(&wgpu::TextureViewDescriptor::default());
-
-        let texture_bind_group: BindGroup = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: texture_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&texture_view),
+        for (layer, rgba) in layers.iter().enumerate() {
+            queue.write_texture(
+                // Tells wgpu where to copy the pixel data
+                wgpu::ImageCopyTexture {
+                    texture: &wgpu_texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                    aspect: wgpu::TextureAspect::All,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(sampler),
+                // The actual pixel data
+                rgba,
+                // The layout of the texture
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(4 * dimensions.width),
+                    rows_per_image: std::num::NonZeroU32::new(dimensions.height),
                 },
-            ],
-            label: Some("diffuse_bind_group"),
-        });
+                wgpu::Extent3d {
+                    depth_or_array_layers: 1,
+                    ..texture_size
+                },
+            );
+
+            if mip_level_count > 1 {
+                Texture::write_mipmaps(
+                    queue,
+                    &wgpu_texture,
+                    rgba,
+                    dimensions,
+                    mip_level_count,
+                    layer as u32,
+                );
+            }
+        }
+
+        Ok(Texture::from_wgpu_texture(
+            device,
+            texture_bind_group_layout,
+            samplers,
+            wgpu_texture,
+            dimensions,
+        ))
+    }
+
+    pub fn from_wgpu_texture(
+        device: &Device,
+        texture_bind_group_layout: &BindGroupLayout,
+        samplers: &Samplers,
+        wgpu_texture: wgpu::Texture,
+        #[cfg_attr(not(feature = "debug"), allow(unused_variables))] dimensions: SizeInPx,
+    ) -> Self {
+        let id = TEXTURE_INDEX.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        #[cfg(feature = "debug")]
+        LIVE_TEXTURES
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .insert(TextureId(id), dimensions);
+        let texture_view = array_view(&wgpu_texture);
+
+        let texture_bind_group = create_bind_group(
+            device,
+            texture_bind_group_layout,
+            &texture_view,
+            &samplers.clamp,
+        );
+        let repeat_texture_bind_group = create_bind_group(
+            device,
+            texture_bind_group_layout,
+            &texture_view,
+            &samplers.repeat,
+        );
 
         Texture {
             id: TextureId(id),
             texture: wgpu_texture,
             texture_bind_group,
+            repeat_texture_bind_group,
+            nearest_texture_bind_group: OnceCell::new(),
+            linear_texture_bind_group: OnceCell::new(),
         }
     }
+
+    fn write_mipmaps(
+        queue: &Queue,
+        wgpu_texture: &wgpu::Texture,
+        rgba: &[u8],
+        dimensions: SizeInPx,
+        mip_level_count: u32,
+        layer: u32,
+    ) {
+        use image::{imageops::FilterType, RgbaImage};
+
+        let mut previous_level =
+            RgbaImage::from_raw(dimensions.width, dimensions.height, rgba.to_vec())
+                .expect("The pixel data should match the dimensions");
+
+        for mip_level in 1..mip_level_count {
+            let width = (dimensions.width >> mip_level).max(1);
+            let height = (dimensions.height >> mip_level).max(1);
+            let level =
+                image::imageops::resize(&previous_level, width, height, FilterType::Triangle);
+
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: wgpu_texture,
+                    mip_level,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &level,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(4 * width),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+            );
+
+            previous_level = level;
+        }
+    }
+
+    /// Creates the bind group for the sampler kind if it isn't created with the texture, which
+    /// must happen before rendering with it.
+    pub fn prepare_bind_group(
+        &self,
+        device: &Device,
+        texture_context: &TextureContext,
+        sampler_kind: SamplerKind,
+    ) {
+        let (bind_group, sampler) = match sampler_kind {
+            SamplerKind::Clamp | SamplerKind::Repeat => return,
+            SamplerKind::Nearest => (
+                &self.nearest_texture_bind_group,
+                &texture_context.nearest_samplers.clamp,
+            ),
+            SamplerKind::Linear => (
+                &self.linear_texture_bind_group,
+                &texture_context.linear_samplers.clamp,
+            ),
+        };
+        bind_group.get_or_init(|| {
+            let texture_view = array_view(&self.texture);
+            create_bind_group(
+                device,
+                &texture_context.texture_bind_group_layout,
+                &texture_view,
+                sampler,
+            )
+        });
+    }
+
+    pub fn bind_group(&self, sampler_kind: SamplerKind) -> &BindGroup {
+        match sampler_kind {
+            SamplerKind::Clamp => &self.texture_bind_group,
+            SamplerKind::Repeat => &self.repeat_texture_bind_group,
+            SamplerKind::Nearest => self
+                .nearest_texture_bind_group
+                .get()
+                .expect("The bind group should be prepared when drawing"),
+            SamplerKind::Linear => self
+                .linear_texture_bind_group
+                .get()
+                .expect("The bind group should be prepared when drawing"),
+        }
+    }
+}
+
+#[cfg(feature = "debug")]
+impl Drop for Texture {
+    fn drop(&mut self) {
+        LIVE_TEXTURES
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// Fails for textures the device can't create, instead of letting wgpu panic.
+fn check_texture_size(device: &Device, dimensions: SizeInPx) -> Result<(), Error> {
+    let max = device.limits().max_texture_dimension_2d;
+    if dimensions.width > max || dimensions.height > max {
+        Err(Error::TextureTooLarge {
+            requested: dimensions,
+            max,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Every texture is bound as an array, single layer ones included, so that the sprites of texture
+/// arrays draw with the same pipeline as the others.
+fn array_view(texture: &wgpu::Texture) -> TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    })
+}
+
+fn create_bind_group(
+    device: &Device,
+    texture_bind_group_layout: &BindGroupLayout,
+    texture_view: &TextureView,
+    sampler: &Sampler,
+) -> BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: texture_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(texture_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+        label: Some("diffuse_bind_group"),
+    })
+}
+
+pub(crate) struct Samplers {
+    pub clamp: Sampler,
+    pub repeat: Sampler,
+}
+
+impl Samplers {
+    fn new(device: &Device, mipmaps: bool) -> Self {
+        let (filter, anisotropy_clamp) = if mipmaps {
+            (wgpu::FilterMode::Linear, std::num::NonZeroU8::new(16))
+        } else {
+            (wgpu::FilterMode::Nearest, None)
+        };
+
+        let clamp = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: filter,
+            min_filter: filter,
+            mipmap_filter: filter,
+            anisotropy_clamp,
+            ..Default::default()
+        });
+
+        let repeat = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: filter,
+            min_filter: filter,
+            mipmap_filter: filter,
+            anisotropy_clamp,
+            ..Default::default()
+        });
+
+        Self { clamp, repeat }
+    }
 }
 
 pub(crate) struct TextureContext {
     pub texture_bind_group_layout: BindGroupLayout,
-    pub sampler: Sampler,
-    pub white_texture: Rc<Texture>,
+    pub samplers: Samplers,
+    /// Always filtering linearly, which distance fields need to be smooth when scaled up.
+    pub linear_samplers: Samplers,
+    pub nearest_samplers: Samplers,
+    pub white_texture: Shared<Texture>,
+    pub mipmaps: bool,
+    pub premultiplied_alpha: bool,
+    pub srgb: bool,
+    pub smooth_shapes: bool,
+    pub depth_sorting: bool,
 }
 
 impl TextureContext {
-    pub fn new(device: &Device, queue: &Queue) -> Self {
+    pub fn new(device: &Device, queue: &Queue, canvas_settings: &CanvasSettings) -> Self {
         let texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[
@@ -287,7 +1377,7 @@ impl TextureContext {
                         visibility: wgpu::ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Texture {
                             multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
                             sample_type: wgpu::TextureSampleType::Float { filterable: true },
                         },
                         count: None,
@@ -302,29 +1392,297 @@ impl TextureContext {
                 label: Some("texture_bind_group_layout"),
             });
 
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
-        });
+        let samplers = Samplers::new(device, canvas_settings.mipmaps);
 
-        let white_texture = Rc::new(Texture::new(
-            device,
-            queue,
-            &texture_bind_group_layout,
-            &sampler,
-            &[255, 255, 255, 255],
-            SizeInPx::new(1, 1),
-        ));
+        let white_texture = Shared::new(
+            Texture::new(
+                device,
+                queue,
+                &texture_bind_group_layout,
+                &samplers,
+                &[&[255, 255, 255, 255]],
+                SizeInPx::new(1, 1),
+                false,
+                canvas_settings.srgb,
+            )
+            .expect("A single pixel texture always fits"),
+        );
 
         Self {
             texture_bind_group_layout,
-            sampler,
+            samplers,
+            linear_samplers: Samplers::new(device, true),
+            nearest_samplers: Samplers::new(device, false),
             white_texture,
+            mipmaps: canvas_settings.mipmaps,
+            premultiplied_alpha: canvas_settings.premultiplied_alpha,
+            srgb: canvas_settings.srgb,
+            smooth_shapes: canvas_settings.smooth_shapes,
+            depth_sorting: canvas_settings.depth_sorting,
+        }
+    }
+
+    pub fn create_texture(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        rgba: &[u8],
+        dimensions: SizeInPx,
+    ) -> Result<Texture, Error> {
+        self.create_texture_array(device, queue, &[rgba], dimensions)
+    }
+
+    /// A texture with one layer per image, all of the given dimensions.
+    pub fn create_texture_array(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        layers: &[&[u8]],
+        dimensions: SizeInPx,
+    ) -> Result<Texture, Error> {
+        let premultiplied: Vec<Vec<u8>>;
+        let layers = if self.premultiplied_alpha {
+            premultiplied = layers.iter().map(|rgba| premultiply(rgba)).collect();
+            premultiplied.iter().map(Vec::as_slice).collect()
+        } else {
+            layers.to_vec()
+        };
+
+        Texture::new(
+            device,
+            queue,
+            &self.texture_bind_group_layout,
+            &self.samplers,
+            &layers,
+            dimensions,
+            self.mipmaps,
+            self.srgb,
+        )
+    }
+}
+
+/// Brings premultiplied pixels, like the ones tiny-skia renders, back to straight alpha.
+#[cfg(feature = "svg")]
+pub(crate) fn unpremultiply(rgba: &[u8]) -> Vec<u8> {
+    rgba.chunks_exact(4)
+        .flat_map(|pixel| {
+            let alpha = pixel[3] as u32;
+            let divide = |channel: u8| match alpha {
+                0 => 0,
+                _ => ((channel as u32 * 255 + alpha / 2) / alpha).min(255) as u8,
+            };
+            [
+                divide(pixel[0]),
+                divide(pixel[1]),
+                divide(pixel[2]),
+                pixel[3],
+            ]
+        })
+        .collect()
+}
+
+fn premultiply(rgba: &[u8]) -> Vec<u8> {
+    rgba.chunks_exact(4)
+        .flat_map(|pixel| {
+            let alpha = pixel[3] as u32;
+            let multiply = |channel: u8| ((channel as u32 * alpha + 127) / 255) as u8;
+            [
+                multiply(pixel[0]),
+                multiply(pixel[1]),
+                multiply(pixel[2]),
+                pixel[3],
+            ]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[cfg(feature = "svg")]
+    use super::unpremultiply;
+    use std::path::PathBuf;
+
+    use super::{
+        advance_frame, check_mask_data, decode_all_in_background, first_duplicate, Residency,
+    };
+    use crate::{Error, SizeInPx};
+
+    #[test]
+    fn masks_need_one_byte_per_pixel() {
+        assert!(check_mask_data(&[0; 12], SizeInPx::new(4, 3)).is_ok());
+        assert!(matches!(
+            check_mask_data(&[0; 11], SizeInPx::new(4, 3)),
+            Err(Error::InvalidDimensions(_))
+        ));
+    }
+
+    #[test]
+    fn empty_masks_are_invalid() {
+        assert!(matches!(
+            check_mask_data(&[], SizeInPx::new(0, 3)),
+            Err(Error::InvalidDimensions(_))
+        ));
+        assert!(matches!(
+            check_mask_data(&[], SizeInPx::new(4, 0)),
+            Err(Error::InvalidDimensions(_))
+        ));
+    }
+
+    #[test]
+    fn huge_masks_dont_overflow() {
+        // 65536 * 65536 wraps to 0 in a u32.
+        assert!(matches!(
+            check_mask_data(&[], SizeInPx::new(65536, 65536)),
+            Err(Error::InvalidDimensions(_))
+        ));
+    }
+
+    #[test]
+    fn the_first_repeated_atlas_name_is_reported() {
+        assert_eq!(first_duplicate(["grass", "rock", "tree"]), None);
+        assert_eq!(
+            first_duplicate(["grass", "rock", "tree", "rock", "grass"]),
+            Some("rock")
+        );
+    }
+
+    #[test]
+    fn images_decoded_by_a_pool_come_back_to_their_own_receiver() {
+        let paths: Vec<PathBuf> = (0..8)
+            .map(|i| PathBuf::from(format!("missing-{i}.png")))
+            .collect();
+
+        let receivers = decode_all_in_background(paths.clone(), 2);
+
+        assert_eq!(receivers.len(), paths.len());
+        for (path, receiver) in paths.iter().zip(receivers) {
+            match receiver.recv().expect("Every image is decoded") {
+                Err(Error::LoadingFailed(failed)) => assert_eq!(&failed, path),
+                _ => panic!("{path:?} doesn't exist"),
+            }
+        }
+    }
+
+    #[test]
+    fn loads_within_the_budget_evict_nothing() {
+        let mut residency = Residency::new(300);
+
+        assert!(residency.load("a", 100).is_empty());
+        assert!(residency.load("b", 200).is_empty());
+        assert_eq!(residency.used, 300);
+    }
+
+    #[test]
+    fn the_least_recently_used_key_is_evicted_first() {
+        let mut residency = Residency::new(300);
+        residency.load("a", 100);
+        residency.load("b", 100);
+        residency.load("c", 100);
+        residency.touch(&"a");
+
+        assert_eq!(residency.load("d", 100), ["b"]);
+        assert_eq!(residency.used, 300);
+    }
+
+    #[test]
+    fn a_key_over_the_budget_on_its_own_stays_loaded() {
+        let mut residency = Residency::new(100);
+        residency.load("a", 50);
+
+        assert_eq!(residency.load("big", 500), ["a"]);
+        assert_eq!(residency.used, 500);
+    }
+
+    #[test]
+    fn lowering_the_budget_evicts_until_it_fits() {
+        let mut residency = Residency::new(400);
+        residency.load("a", 100);
+        residency.load("b", 100);
+        residency.load("c", 100);
+
+        assert_eq!(residency.set_budget(150), ["a", "b"]);
+        assert_eq!(residency.used, 100);
+    }
+
+    #[test]
+    fn unloading_gives_the_memory_back() {
+        let mut residency = Residency::new(300);
+        residency.load("a", 100);
+        residency.unload(&"a");
+        residency.unload(&"a");
+
+        assert_eq!(residency.used, 0);
+        assert!(residency.load("b", 300).is_empty());
+    }
+
+    #[test]
+    fn loading_a_key_again_doesnt_count_it_twice() {
+        let mut residency = Residency::new(300);
+        residency.load("a", 100);
+        residency.load("a", 200);
+
+        assert_eq!(residency.used, 200);
+    }
+
+    #[test]
+    fn advance_frame_moves_on_once_the_delay_is_spent() {
+        let delays = [Duration::from_millis(100), Duration::from_millis(50)];
+
+        assert_eq!(
+            advance_frame(&delays, 0, Duration::from_millis(99)),
+            (0, Duration::from_millis(99))
+        );
+        assert_eq!(
+            advance_frame(&delays, 0, Duration::from_millis(120)),
+            (1, Duration::from_millis(20))
+        );
+    }
+
+    #[test]
+    fn advance_frame_wraps_around() {
+        let delays = [Duration::from_millis(100), Duration::from_millis(50)];
+
+        assert_eq!(
+            advance_frame(&delays, 1, Duration::from_millis(50)),
+            (0, Duration::ZERO)
+        );
+        assert_eq!(
+            advance_frame(&delays, 0, Duration::from_millis(310)),
+            (0, Duration::from_millis(10))
+        );
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn unpremultiply_makes_transparent_pixels_transparent_black() {
+        assert_eq!(unpremultiply(&[0, 0, 0, 0]), [0, 0, 0, 0]);
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn unpremultiply_keeps_opaque_pixels() {
+        assert_eq!(unpremultiply(&[200, 100, 50, 255]), [200, 100, 50, 255]);
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn unpremultiply_keeps_soft_white_edges_white() {
+        // A half covered pixel of a white shape, which would leave a dark fringe as is.
+        assert_eq!(unpremultiply(&[128, 128, 128, 128]), [255, 255, 255, 128]);
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn half_alpha_round_trips_to_straight_alpha() {
+        let straight = [200, 100, 50, 128];
+
+        let round_trip = unpremultiply(&super::premultiply(&straight));
+
+        for (channel, expected) in round_trip.iter().zip(straight) {
+            assert!(channel.abs_diff(expected) <= 1, "{round_trip:?}");
         }
+        assert_eq!(round_trip[3], 128);
     }
 }
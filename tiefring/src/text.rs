@@ -1,102 +1,620 @@
-use std::{cell::RefCell, collections::HashMap, fmt::Debug, fs, path::Path, rc::Rc};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::Debug,
+    fs,
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use fontdue::layout::{CoordinateSystem, Layout, TextStyle};
+use fontdue::layout::{CoordinateSystem, Layout, LayoutSettings, TextStyle};
+pub use fontdue::FontSettings;
 use rect_packer::Packer;
-use wgpu::{BindGroup, BindGroupLayout, Device, Queue, Sampler};
+use wgpu::{BindGroupLayout, Device, Queue};
 
 use crate::{
-    renderer::{ColorMatrix, RenderOperation},
-    sprite::{Texture, TextureContext, TextureId, TEXTURE_INDEX},
-    Color, Error, Position, Rect, Transform,
+    bmfont::BitmapFont,
+    renderer::{BlendMode, ColorMatrix, RenderOperation, INSTANCE_SDF},
+    shared::{Shared, SharedCell},
+    sprite::{Samplers, Texture, TextureContext},
+    Color, Error, Position, Rect, SizeInPx, Transform,
 };
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 pub(crate) struct FontId(pub(crate) usize, pub(crate) u32);
 
 pub struct Font {
-    pub(crate) font: Rc<fontdue::Font>,
-    font_cache: HashMap<u32, Rc<RefCell<SizedFont>>>,
+    pub(crate) kind: FontKind,
+    pub(crate) snap_to_pixel: bool,
+}
+
+pub(crate) enum FontKind {
+    TrueType(TrueTypeFont),
+    Bitmap(BitmapFont),
 }
 
 static CACHE_WIDTH: u32 = 1024;
+/// How many laid out runs of text [`TextConverter`] keeps.
+const RUN_CACHE_CAPACITY: usize = 256;
+/// The size distance field glyphs are rasterized at, whatever the size they are drawn at.
+const SDF_PX: u32 = 48;
+/// How far from the edge of the glyphs, in pixels at [`SDF_PX`], distance fields go.
+const SDF_SPREAD: usize = 6;
+
+static FONT_INDEX: AtomicUsize = AtomicUsize::new(0);
+/// Changes every time an atlas is created or starts over, so that cached runs of text don't
+/// keep pointing to it.
+static ATLAS_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// How [`crate::Graphics::draw_text_with_options`] lays out text.
+#[derive(Clone, Copy, Debug)]
+pub struct TextOptions {
+    /// Expands tabs to the next multiple of this many columns, which suits monospace fonts. With
+    /// `None`, tabs are left to the font.
+    pub tab_width_spaces: Option<u32>,
+    /// Multiplies the height of each line.
+    pub line_height_multiplier: f32,
+    /// Draws a line under the text.
+    pub underline: bool,
+    /// Draws a line through the middle of the lowercase letters.
+    pub strikethrough: bool,
+    /// The color of the underline and strikethrough, the text color if `None`.
+    pub decoration_color: Option<Color>,
+    /// How the glyphs are combined with what is below them. Drawing a spread out copy of the text
+    /// additively under it makes it glow.
+    pub blend_mode: BlendMode,
+    /// Cuts each line wider than this many pixels, ending it with an ellipsis. A line where even
+    /// the ellipsis doesn't fit is left empty.
+    pub truncate: Option<f32>,
+}
+
+impl Default for TextOptions {
+    fn default() -> Self {
+        Self {
+            tab_width_spaces: None,
+            line_height_multiplier: 1.0,
+            underline: false,
+            strikethrough: false,
+            decoration_color: None,
+            truncate: None,
+            blend_mode: BlendMode::Alpha,
+        }
+    }
+}
+
+/// Text made of spans with their own color and size, drawn with
+/// [`crate::Graphics::draw_rich_text`]. The spans are laid out one after the other, and wrap
+/// across each other when a maximum width is set.
+#[derive(Clone, Debug)]
+pub struct RichText {
+    pub(crate) px: u32,
+    pub(crate) spans: Vec<TextSpan>,
+    pub(crate) max_width: Option<f32>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct TextSpan {
+    pub(crate) text: String,
+    pub(crate) color: Color,
+    pub(crate) px: Option<u32>,
+}
+
+impl RichText {
+    /// An empty text, whose spans are `px` high unless they have their own size.
+    pub fn new(px: u32) -> Self {
+        Self {
+            px,
+            spans: vec![],
+            max_width: None,
+        }
+    }
+
+    pub fn push<T: Into<String>>(&mut self, text: T, color: Color, px: Option<u32>) -> &mut Self {
+        self.spans.push(TextSpan {
+            text: text.into(),
+            color,
+            px,
+        });
+        self
+    }
+
+    /// Wraps the lines longer than `max_width`, in pixels.
+    pub fn set_max_width(&mut self, max_width: Option<f32>) {
+        self.max_width = max_width;
+    }
+
+    pub(crate) fn span_px(&self, span: &TextSpan) -> u32 {
+        span.px.unwrap_or(self.px)
+    }
+}
+
+/// The horizontal extent and baseline of a laid out line of text, used to draw decorations.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TextLine {
+    pub(crate) left: f32,
+    pub(crate) right: f32,
+    pub(crate) baseline: f32,
+}
+
+/// Replaces tabs with spaces up to the next tab stop, counting columns from the last new line.
+pub(crate) fn expand_tabs(text: &str, tab_width_spaces: Option<u32>) -> Cow<'_, str> {
+    let tab_width = match tab_width_spaces {
+        Some(tab_width) if text.contains('\t') => tab_width.max(1) as usize,
+        _ => return Cow::Borrowed(text),
+    };
+
+    let mut expanded = String::with_capacity(text.len());
+    let mut column = 0;
+    for character in text.chars() {
+        match character {
+            '\t' => {
+                let spaces = tab_width - column % tab_width;
+                expanded.push_str(&" ".repeat(spaces));
+                column += spaces;
+            }
+            '\n' => {
+                expanded.push(character);
+                column = 0;
+            }
+            _ => {
+                expanded.push(character);
+                column += 1;
+            }
+        }
+    }
+    Cow::Owned(expanded)
+}
 
 impl Font {
     pub(crate) fn load_font<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Font::load_font_with(path, FontSettings::default())
+    }
+
+    pub(crate) fn load_font_with<P: AsRef<Path>>(
+        path: P,
+        settings: FontSettings,
+    ) -> Result<Self, Error> {
         let bytes = fs::read(&path).map_err(|_e| Error::LoadingFailed(path.as_ref().into()))?;
 
-        let font = Rc::new(
-            fontdue::Font::from_bytes(bytes, fontdue::FontSettings::default())
-                .map_err(|_e| Error::LoadingFailed(path.as_ref().into()))?,
-        );
+        Font::load_font_bytes(&bytes, settings)
+            .ok_or_else(|| Error::LoadingFailed(path.as_ref().into()))
+    }
+
+    /// A TrueType or OpenType font from the content of its file.
+    pub(crate) fn load_font_bytes(bytes: &[u8], settings: FontSettings) -> Option<Self> {
+        let font = Shared::new(fontdue::Font::from_bytes(bytes, settings).ok()?);
         let font_cache = HashMap::new();
 
-        Ok(Self { font, font_cache })
+        Some(Self {
+            kind: FontKind::TrueType(TrueTypeFont {
+                id: FONT_INDEX.fetch_add(1, Ordering::Relaxed),
+                sdf: false,
+                font,
+                font_cache,
+                cache_budget: None,
+                use_count: 0,
+            }),
+            snap_to_pixel: false,
+        })
+    }
+
+    pub(crate) fn load_bmfont<P, I>(
+        device: &Device,
+        queue: &Queue,
+        texture_context: &TextureContext,
+        fnt_path: P,
+        page_image_path: I,
+    ) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+        I: AsRef<Path>,
+    {
+        let bitmap_font =
+            BitmapFont::load(device, queue, texture_context, fnt_path, page_image_path)?;
+
+        Ok(Self {
+            kind: FontKind::Bitmap(bitmap_font),
+            snap_to_pixel: false,
+        })
     }
 
     pub fn measure(&self, character: char, px: u32) -> (f32, f32) {
-        let metrics = self.font.metrics(character, px as f32);
-        (metrics.bounds.width, metrics.bounds.height)
+        match &self.kind {
+            FontKind::TrueType(true_type_font) => {
+                let metrics = true_type_font.font.metrics(character, px as f32);
+                (metrics.bounds.width, metrics.bounds.height)
+            }
+            FontKind::Bitmap(bitmap_font) => bitmap_font.measure(character, px),
+        }
+    }
+
+    /// The offsets from the baseline, downwards, of the underline and of the strikethrough, and
+    /// their thickness.
+    pub(crate) fn decoration_metrics(&self, px: u32) -> (f32, f32, f32) {
+        let descent = match &self.kind {
+            FontKind::TrueType(true_type_font) => true_type_font
+                .font
+                .horizontal_line_metrics(px as f32)
+                .map(|line_metrics| -line_metrics.descent)
+                .unwrap_or(0.0),
+            FontKind::Bitmap(bitmap_font) => bitmap_font.descent(px),
+        };
+        let x_height = match self.measure('x', px).1 {
+            x_height if x_height > 0.0 => x_height,
+            _ => self.ascent(px) / 2.0,
+        };
+        let thickness = (px as f32 / 16.0).round().max(1.0);
+
+        (descent / 2.0, -x_height / 2.0, thickness)
+    }
+
+    /// Cuts the lines of the text that are wider than `max_width`, replacing their end with an
+    /// ellipsis.
+    pub(crate) fn truncate<'a>(&self, text: &'a str, px: u32, max_width: f32) -> Cow<'a, str> {
+        let ellipsis = if self.has_character('…') {
+            "…"
+        } else {
+            "..."
+        };
+        let line_width = |line: &str| self.line_width(line, px);
+
+        if text.lines().all(|line| line_width(line) <= max_width) {
+            return Cow::Borrowed(text);
+        }
+
+        let ellipsis_width = line_width(ellipsis);
+        let truncated: Vec<String> = text
+            .split('\n')
+            .map(|line| {
+                if line_width(line) <= max_width {
+                    return line.to_string();
+                }
+                if ellipsis_width > max_width {
+                    return String::new();
+                }
+
+                let mut width = 0.0;
+                let mut previous = None;
+                let mut end = 0;
+                for (index, character) in line.char_indices() {
+                    width += self.advance(previous, character, px);
+                    if width + ellipsis_width > max_width {
+                        break;
+                    }
+                    previous = Some(character);
+                    end = index + character.len_utf8();
+                }
+                format!("{}{}", line[..end].trim_end(), ellipsis)
+            })
+            .collect();
+        Cow::Owned(truncated.join("\n"))
+    }
+
+    /// The size of the box the text fills when drawn: the width of its longest line, and the
+    /// height of all its lines.
+    pub fn measure_text(&self, text: &str, px: u32) -> (f32, f32) {
+        let width = text
+            .lines()
+            .map(|line| self.line_width(line, px))
+            .fold(0.0, f32::max);
+        let line_count = text.split('\n').count();
+        (width, line_count as f32 * self.line_height(px))
+    }
+
+    fn line_width(&self, line: &str, px: u32) -> f32 {
+        let mut previous = None;
+        line.chars()
+            .map(|character| {
+                let advance = self.advance(previous, character, px);
+                previous = Some(character);
+                advance
+            })
+            .sum()
+    }
+
+    /// The distance between the baselines of two lines: the ascent minus the descent plus the
+    /// line gap, rounded up to a whole pixel like the layout does, so that measured and drawn text
+    /// agree.
+    fn line_height(&self, px: u32) -> f32 {
+        match &self.kind {
+            FontKind::TrueType(true_type_font) => true_type_font
+                .font
+                .horizontal_line_metrics(px as f32)
+                .map(|line_metrics| line_metrics.new_line_size.ceil())
+                .unwrap_or(px as f32),
+            FontKind::Bitmap(bitmap_font) => bitmap_font.line_height(px),
+        }
+    }
+
+    fn has_character(&self, character: char) -> bool {
+        match &self.kind {
+            FontKind::TrueType(true_type_font) => {
+                true_type_font.font.lookup_glyph_index(character) != 0
+            }
+            FontKind::Bitmap(bitmap_font) => bitmap_font.has_character(character),
+        }
+    }
+
+    /// How far the pen moves for this character, like the text is laid out when drawn. Bitmap
+    /// fonts include the kerning with the previous character. fontdue's layout doesn't kern and
+    /// rounds every advance up to a whole pixel, so TrueType fonts do the same.
+    fn advance(&self, previous: Option<char>, character: char, px: u32) -> f32 {
+        match &self.kind {
+            FontKind::TrueType(true_type_font) => true_type_font
+                .font
+                .metrics(character, px as f32)
+                .advance_width
+                .ceil(),
+            FontKind::Bitmap(bitmap_font) => bitmap_font.advance(previous, character, px),
+        }
     }
 
     pub fn ascent(&self, px: u32) -> f32 {
-        let line_metrics = self.font.horizontal_line_metrics(px as f32).unwrap();
-        line_metrics.ascent
+        match &self.kind {
+            // Fonts without horizontal metrics are drawn as if they were all ascent.
+            FontKind::TrueType(true_type_font) => true_type_font
+                .font
+                .horizontal_line_metrics(px as f32)
+                .map(|line_metrics| line_metrics.ascent)
+                .unwrap_or(px as f32),
+            FontKind::Bitmap(bitmap_font) => bitmap_font.ascent(px),
+        }
+    }
+
+    /// Rounds each glyph to a whole pixel, which pixel art fonts want. Otherwise, glyphs keep the
+    /// fractional part of their position, which is smoother when text moves or scales.
+    pub fn set_snap_to_pixel(&mut self, snap_to_pixel: bool) {
+        self.snap_to_pixel = snap_to_pixel;
+    }
+
+    /// Rasterizes the glyphs once as signed distance fields, in an atlas shared by every size,
+    /// which keeps text sharp when it is drawn big or zoomed in. Small text looks a bit softer than
+    /// with the default atlases, rasterized for each size. Bitmap fonts ignore it.
+    pub fn set_sdf(&mut self, sdf: bool) {
+        if let FontKind::TrueType(true_type_font) = &mut self.kind {
+            if true_type_font.sdf != sdf {
+                true_type_font.sdf = sdf;
+                true_type_font.font_cache.clear();
+            }
+        }
+    }
+
+    /// Takes the first error met while rasterizing glyphs, like a glyph too big for the atlas.
+    /// Text is still drawn without the glyphs that failed.
+    pub fn take_error(&mut self) -> Option<Error> {
+        match &mut self.kind {
+            FontKind::TrueType(true_type_font) => true_type_font
+                .font_cache
+                .values()
+                .find_map(|font_for_px| font_for_px.borrow_mut().error.take()),
+            FontKind::Bitmap(_) => None,
+        }
     }
 
-    pub(crate) fn get_font_for_px(&mut self, px: u32) -> Rc<RefCell<SizedFont>> {
-        self.font_cache
+    /// Drops every rasterized glyph, freeing the atlas textures. They will be rasterized again
+    /// when needed.
+    pub fn clear_cache(&mut self) {
+        if let FontKind::TrueType(true_type_font) = &mut self.kind {
+            true_type_font.font_cache.clear();
+        }
+    }
+
+    /// Limits the number of 1024x1024 atlas pages kept for this font, `None` meaning no limit.
+    /// When over budget, the least recently used sizes are dropped first, and a size that fills
+    /// the budget on its own starts its atlas over.
+    pub fn set_cache_budget(&mut self, pages: Option<usize>) {
+        if let FontKind::TrueType(true_type_font) = &mut self.kind {
+            true_type_font.cache_budget = pages;
+            for sized_font in true_type_font.font_cache.values() {
+                sized_font.borrow_mut().max_pages = pages;
+            }
+            true_type_font.evict_least_recently_used();
+        }
+    }
+}
+
+pub(crate) struct TrueTypeFont {
+    id: usize,
+    sdf: bool,
+    font: Shared<fontdue::Font>,
+    font_cache: HashMap<u32, Shared<SharedCell<SizedFont>>>,
+    cache_budget: Option<usize>,
+    use_count: u64,
+}
+
+impl TrueTypeFont {
+    pub(crate) fn get_font_for_px(&mut self, px: u32) -> Shared<SharedCell<SizedFont>> {
+        self.use_count += 1;
+
+        // Distance fields are scaled, so a single atlas serves every size.
+        let px = if self.sdf { SDF_PX } else { px };
+        let font = self.font.clone();
+        let font_id = FontId(self.id, px);
+        let (sdf, cache_budget) = (self.sdf, self.cache_budget);
+        let font_for_px = self
+            .font_cache
             .entry(px)
-            .or_insert_with(|| Rc::new(RefCell::new(SizedFont::new(px, self.font.clone()))))
-            .clone()
+            .or_insert_with(|| {
+                Shared::new(SharedCell::new(SizedFont::new(
+                    font_id,
+                    font,
+                    sdf,
+                    cache_budget,
+                )))
+            })
+            .clone();
+        font_for_px.borrow_mut().last_used = self.use_count;
+
+        self.evict_least_recently_used();
+        font_for_px
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let cache_budget = match self.cache_budget {
+            Some(cache_budget) => cache_budget,
+            None => return,
+        };
+
+        // The most recently used size is always kept, even if it is over budget by itself.
+        while self.font_cache.len() > 1 {
+            let page_count: usize = self
+                .font_cache
+                .values()
+                .map(|sized_font| sized_font.borrow().pages.len())
+                .sum();
+            if page_count <= cache_budget {
+                break;
+            }
+
+            let least_recently_used = self
+                .font_cache
+                .iter()
+                .min_by_key(|(_, sized_font)| sized_font.borrow().last_used)
+                .map(|(px, _)| *px);
+            if let Some(px) = least_recently_used {
+                self.font_cache.remove(&px);
+            }
+        }
     }
 }
 
 struct CharacterReference {
     tex_coords: Rect,
+    /// The index of its atlas page in [`SizedFont::pages`].
+    page: usize,
+    width: f32,
+    height: f32,
+    /// The margin around the glyph, for distance fields.
+    padding: f32,
 }
 
-pub(crate) struct SizedFont {
-    px: u32,
-    texture: Option<Rc<Texture>>,
-    packer: Packer,
-    font: Rc<fontdue::Font>,
-    characters: HashMap<char, CharacterReference>,
+/// Uploads the one byte per pixel bitmap of a glyph at `origin` in an atlas page. Rows of odd
+/// widths aren't aligned to [`wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`], which `write_texture` allows.
+fn write_glyph(
+    queue: &Queue,
+    texture: &wgpu::Texture,
+    (x, y): (u32, u32),
+    (width, height): (u32, u32),
+    bitmap: &[u8],
+) {
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d { x, y, z: 0 },
+            aspect: wgpu::TextureAspect::All,
+        },
+        bitmap,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: std::num::NonZeroU32::new(width),
+            rows_per_image: std::num::NonZeroU32::new(height),
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
 }
 
-impl SizedFont {
-    fn new(px: u32, font: Rc<fontdue::Font>) -> Self {
-        let texture = None;
-        let packer = Packer::new(rect_packer::Config {
+/// Where a glyph was placed by a [`PagePacker`].
+struct Placement {
+    page: usize,
+    rect: rect_packer::Rect,
+    /// The pages were all full and at the limit, so they started over.
+    restarted: bool,
+}
+
+/// Places the glyphs of a [`SizedFont`] on its atlas pages, apart from the textures so that it
+/// works without a device.
+#[derive(Default)]
+struct PagePacker {
+    packers: Vec<Packer>,
+}
+
+impl PagePacker {
+    /// Packs the glyph in the first page with room for it, or on a new page, starting over when
+    /// there are already `max_pages`. `None` when the glyph is bigger than a whole page.
+    fn pack(&mut self, width: i32, height: i32, max_pages: Option<usize>) -> Option<Placement> {
+        if width > CACHE_WIDTH as i32 || height > CACHE_WIDTH as i32 {
+            return None;
+        }
+
+        for (page, packer) in self.packers.iter_mut().enumerate() {
+            if let Some(rect) = packer.pack(width, height, false) {
+                return Some(Placement {
+                    page,
+                    rect,
+                    restarted: false,
+                });
+            }
+        }
+
+        let restarted = max_pages.is_some_and(|max_pages| self.packers.len() >= max_pages.max(1));
+        if restarted {
+            self.packers.clear();
+        }
+
+        let mut packer = Packer::new(rect_packer::Config {
             width: CACHE_WIDTH as i32,
             height: CACHE_WIDTH as i32,
             border_padding: 0,
             rectangle_padding: 0,
         });
+        let rect = packer.pack(width, height, false)?;
+        self.packers.push(packer);
+
+        Some(Placement {
+            page: self.packers.len() - 1,
+            rect,
+            restarted,
+        })
+    }
+}
+
+pub(crate) struct SizedFont {
+    font_id: FontId,
+    generation: usize,
+    sdf: bool,
+    /// The atlas textures, more are added when the previous ones are full.
+    pages: Vec<Shared<Texture>>,
+    page_packer: PagePacker,
+    font: Shared<fontdue::Font>,
+    characters: HashMap<char, CharacterReference>,
+    max_pages: Option<usize>,
+    last_used: u64,
+    /// The first glyph that couldn't be added, see [`Font::take_error`].
+    error: Option<Error>,
+}
+
+impl SizedFont {
+    fn new(
+        font_id: FontId,
+        font: Shared<fontdue::Font>,
+        sdf: bool,
+        max_pages: Option<usize>,
+    ) -> Self {
+        let pages = vec![];
         let characters = HashMap::new();
 
         Self {
-            px,
-            texture,
-            packer,
+            font_id,
+            generation: ATLAS_GENERATION.fetch_add(1, Ordering::Relaxed),
+            sdf,
+            pages,
+            page_packer: PagePacker::default(),
             font,
             characters,
+            max_pages,
+            last_used: 0,
+            error: None,
         }
     }
 
-    pub(crate) fn get_or_create_texture(
-        &mut self,
-        device: &Device,
-        texture_context: &TextureContext,
-    ) -> Rc<Texture> {
-        self.texture
-            .get_or_insert_with(|| {
-                Rc::new(SizedFont::font_texture(
-                    device,
-                    &texture_context.texture_bind_group_layout,
-                    &texture_context.sampler,
-                ))
-            })
-            .clone()
+    fn px(&self) -> u32 {
+        self.font_id.1
     }
 
     fn get_or_create_character(
@@ -106,11 +624,13 @@ impl SizedFont {
         queue: &Queue,
         texture_context: &TextureContext,
     ) -> Option<&CharacterReference> {
-        if self.contains(&char) {
-            self.characters.get(&char)
-        } else {
-            self.create_character(char, device, queue, texture_context)
+        if !self.contains(&char) {
+            // Drawing goes on without the glyph, the error is kept for the font to report.
+            if let Err(error) = self.create_character(char, device, queue, texture_context) {
+                self.error.get_or_insert(error);
+            }
         }
+        self.characters.get(&char)
     }
 
     fn contains(&self, character: &char) -> bool {
@@ -123,85 +643,96 @@ impl SizedFont {
         device: &Device,
         queue: &Queue,
         texture_context: &TextureContext,
-    ) -> Option<&CharacterReference> {
-        let (metrics, bitmap) = self.font.rasterize(char, self.px as f32);
+    ) -> Result<Option<&CharacterReference>, Error> {
+        let (metrics, bitmap) = self.font.rasterize(char, self.px() as f32);
 
         if metrics.width == 0 || metrics.height == 0 || bitmap.is_empty() {
-            // A character without dimension, probably white space.
-            let character = CharacterReference {
-                tex_coords: Rect {
-                    left: 0.0,
-                    top: 0.0,
-                    width: 0.0,
-                    height: 0.0,
-                },
-            };
-
-            self.characters.insert(char, character);
-            return self.characters.get(&char);
+            // A character without dimension, probably white space, there is nothing to draw.
+            return Ok(None);
         }
 
-        let packed = self
-            .packer
-            .pack(metrics.width as i32, metrics.height as i32, false);
+        let (width, height, padding, bitmap) = if self.sdf {
+            let field = signed_distance_field(&bitmap, metrics.width, metrics.height, SDF_SPREAD);
+            let margin = 2 * SDF_SPREAD;
+            (
+                metrics.width + margin,
+                metrics.height + margin,
+                SDF_SPREAD,
+                field,
+            )
+        } else {
+            (metrics.width, metrics.height, 0, bitmap)
+        };
 
-        if let Some(packed) = packed {
-            let texture = self.texture.get_or_insert_with(|| {
-                Rc::new(SizedFont::font_texture(
-                    device,
-                    &texture_context.texture_bind_group_layout,
-                    &texture_context.sampler,
-                ))
-            });
+        let (page, packed) = self
+            .pack(width as i32, height as i32, device, texture_context)
+            .ok_or(Error::GlyphAtlasFull(char))?;
+        write_glyph(
+            queue,
+            &self.pages[page].texture,
+            (packed.x as u32, packed.y as u32),
+            (width as u32, height as u32),
+            &bitmap,
+        );
 
-            queue.write_texture(
-                wgpu::ImageCopyTexture {
-                    texture: &texture.texture,
-                    mip_level: 0,
-                    origin: wgpu::Origin3d {
-                        x: packed.x as u32,
-                        y: packed.y as u32,
-                        z: 0,
-                    },
-                    aspect: wgpu::TextureAspect::All,
-                },
-                // The actual pixel data
-                &bitmap,
-                // The layout of the texture
-                wgpu::ImageDataLayout {
-                    offset: 0,
-                    bytes_per_row: std::num::NonZeroU32::new(metrics.width as u32),
-                    rows_per_image: std::num::NonZeroU32::new(metrics.height as u32),
-                },
-                wgpu::Extent3d {
-                    width: metrics.width as u32,
-                    height: metrics.height as u32,
-                    depth_or_array_layers: 1,
-                },
-            );
+        let tex_coords = Rect {
+            left: packed.x as f32 / CACHE_WIDTH as f32,
+            top: packed.y as f32 / CACHE_WIDTH as f32,
+            width: packed.width as f32 / CACHE_WIDTH as f32,
+            height: packed.height as f32 / CACHE_WIDTH as f32,
+        };
 
-            let tex_coords = Rect {
-                left: packed.x as f32 / 1024.0,
-                top: packed.y as f32 / 1024.0,
-                width: packed.width as f32 / 1024.0,
-                height: packed.height as f32 / 1024.0,
-            };
+        let character = CharacterReference {
+            tex_coords,
+            page,
+            width: width as f32,
+            height: height as f32,
+            padding: padding as f32,
+        };
 
-            let character = CharacterReference { tex_coords };
+        self.characters.insert(char, character);
+        Ok(self.characters.get(&char))
+    }
 
-            self.characters.insert(char, character);
-            self.characters.get(&char)
-        } else {
-            None
+    /// Finds room for a glyph in the existing pages, or in a new one if they are all full.
+    fn pack(
+        &mut self,
+        width: i32,
+        height: i32,
+        device: &Device,
+        texture_context: &TextureContext,
+    ) -> Option<(usize, rect_packer::Rect)> {
+        let placement = self.page_packer.pack(width, height, self.max_pages)?;
+
+        if placement.restarted {
+            // Without a way to free single glyphs, the whole atlas starts over. Operations
+            // already created keep their own reference to the old textures.
+            self.pages.clear();
+            self.characters.clear();
+            self.generation = ATLAS_GENERATION.fetch_add(1, Ordering::Relaxed);
         }
+
+        if placement.page == self.pages.len() {
+            let samplers = if self.sdf {
+                &texture_context.linear_samplers
+            } else {
+                &texture_context.samplers
+            };
+            self.pages.push(Shared::new(SizedFont::font_texture(
+                device,
+                &texture_context.texture_bind_group_layout,
+                samplers,
+            )));
+        }
+
+        Some((placement.page, placement.rect))
     }
 
     fn font_texture(
         device: &Device,
         texture_bind_group_layout: &BindGroupLayout,
-        sampler: &Sampler,
+        samplers: &Samplers,
     ) -> Texture {
-        let id = TEXTURE_INDEX.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         let texture_size = wgpu::Extent3d {
             width: CACHE_WIDTH,
             height: CACHE_WIDTH,
@@ -218,96 +749,700 @@ impl SizedFont {
             label: Some("texture"),
         });
 
-        let texture_view = wgpu_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        Texture::from_wgpu_texture(
+            device,
+            texture_bind_group_layout,
+            samplers,
+            wgpu_texture,
+            SizeInPx::new(CACHE_WIDTH, CACHE_WIDTH),
+        )
+    }
+}
 
-        let texture_bind_group: BindGroup = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: texture_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&texture_view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(sampler),
-                },
-            ],
-            label: Some("diffuse_bind_group"),
-        });
+/// A piece of text laid out by [`TextConverter::render_spans`].
+pub(crate) struct SpanLayout<'a> {
+    pub(crate) text: &'a str,
+    pub(crate) px: u32,
+    pub(crate) color: Color,
+    pub(crate) font_for_px: Shared<SharedCell<SizedFont>>,
+}
+
+/// A glyph placed by the layout, with the index of its span. Glyphs only keep the index of their
+/// atlas page, so that cached runs don't keep pages alive once the font drops them.
+struct PlacedGlyph {
+    page: usize,
+    tex_coords: Rect,
+    rect: Rect,
+    position: Position,
+    span: usize,
+    sdf: bool,
+}
 
-        Texture {
-            id: TextureId(id),
-            texture: wgpu_texture,
-            texture_bind_group,
+/// What a run of text laid out by [`TextConverter::render_operation`] depends on, color aside.
+/// Looked up with the text borrowed, and stored with it owned.
+#[derive(PartialEq, Eq, Hash)]
+struct RunKey<T> {
+    font_id: FontId,
+    px: u32,
+    generation: usize,
+    text: T,
+    tab_width_spaces: Option<u32>,
+    line_height_multiplier: u32,
+    snap_to_pixel: bool,
+}
+
+impl<T> RunKey<T> {
+    fn with_text<U>(&self, text: U) -> RunKey<U> {
+        RunKey {
+            font_id: self.font_id,
+            px: self.px,
+            generation: self.generation,
+            text,
+            tab_width_spaces: self.tab_width_spaces,
+            line_height_multiplier: self.line_height_multiplier,
+            snap_to_pixel: self.snap_to_pixel,
         }
     }
 }
 
+impl RunKey<&str> {
+    /// What runs are stored under, computed without copying the text.
+    fn hash_value(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// A run of text laid out at the origin.
+struct CachedRun {
+    /// Compared to the key looked up, in case two keys share a hash.
+    key: RunKey<String>,
+    glyphs: Vec<PlacedGlyph>,
+    lines: Vec<TextLine>,
+    last_used: u64,
+}
+
 pub(crate) struct TextConverter {
-    layout: Layout,
+    // Each glyph remembers the index of its span.
+    layout: Layout<usize>,
+    /// By the hash of their key, so that looking a run up doesn't copy its text.
+    runs: HashMap<u64, CachedRun>,
+    use_count: u64,
+    lines: Vec<TextLine>,
 }
 
 impl TextConverter {
     pub fn new() -> Self {
         Self {
             layout: Layout::new(CoordinateSystem::PositiveYDown),
+            runs: HashMap::new(),
+            use_count: 0,
+            lines: vec![],
         }
     }
+
+    /// Forgets the laid out runs.
+    pub fn clear(&mut self) {
+        self.runs.clear();
+        self.lines.clear();
+    }
 }
 
 impl TextConverter {
+    /// The lines of the text laid out by the last call to [`TextConverter::render_operation`] or
+    /// [`TextConverter::render_spans`].
+    pub fn lines(&self) -> Vec<TextLine> {
+        self.lines.clone()
+    }
+
+    /// Text drawn again and again, like a score, is laid out once and then only moved. With
+    /// pixel snapping, the position is rounded as a whole.
     #[allow(clippy::too_many_arguments)]
     pub fn render_operation(
         &mut self,
         text: &str,
+        px: u32,
         color: Color,
         position: Position,
-        font_for_px: &Rc<RefCell<SizedFont>>,
+        font_for_px: &Shared<SharedCell<SizedFont>>,
         transforms: Transform,
+        snap_to_pixel: bool,
+        options: &TextOptions,
         device: &Device,
         queue: &Queue,
         texture_context: &TextureContext,
-    ) -> Vec<RenderOperation> {
-        let char_count: usize = text.len();
+    ) -> Vec<(Shared<Texture>, RenderOperation)> {
+        self.use_count += 1;
+        let font = font_for_px.borrow();
+        let key = RunKey {
+            font_id: font.font_id,
+            px,
+            generation: font.generation,
+            text,
+            tab_width_spaces: options.tab_width_spaces,
+            line_height_multiplier: options.line_height_multiplier.to_bits(),
+            snap_to_pixel,
+        };
+        let mut hash = key.hash_value();
+        drop(font);
 
-        if char_count == 0 {
-            return vec![];
-        }
+        let cached = self
+            .runs
+            .get(&hash)
+            .is_some_and(|run| run.key.with_text(run.key.text.as_str()) == key);
+        if !cached {
+            if self.runs.len() >= RUN_CACHE_CAPACITY {
+                let least_recently_used = self
+                    .runs
+                    .iter()
+                    .min_by_key(|(_, run)| run.last_used)
+                    .map(|(hash, _)| *hash);
+                if let Some(hash) = least_recently_used {
+                    self.runs.remove(&hash);
+                }
+            }
 
-        let size = font_for_px.borrow().px;
-        let fonts = &[font_for_px.borrow().font.clone()];
+            let span = SpanLayout {
+                text,
+                px,
+                color,
+                font_for_px: font_for_px.clone(),
+            };
+            let glyphs = layout_glyphs(
+                &mut self.layout,
+                &[span],
+                Position::new(0.0, 0.0),
+                None,
+                snap_to_pixel,
+                options,
+                device,
+                queue,
+                texture_context,
+            );
+            // Laying out may have started the atlas over, the run goes with the new one.
+            let key = RunKey {
+                generation: font_for_px.borrow().generation,
+                ..key
+            };
+            hash = key.hash_value();
+            self.runs.insert(
+                hash,
+                CachedRun {
+                    key: key.with_text(text.to_string()),
+                    glyphs,
+                    lines: layout_lines(&self.layout),
+                    last_used: 0,
+                },
+            );
+        }
+        let run = self
+            .runs
+            .get_mut(&hash)
+            .expect("The run was found or just laid out");
+        run.last_used = self.use_count;
 
-        let Position { left: x, top: y } = position;
-        self.layout.reset(&fontdue::layout::LayoutSettings {
-            x,
-            y,
-            ..Default::default()
-        });
+        let offset = if snap_to_pixel {
+            Position::new(position.left.round(), position.top.round())
+        } else {
+            position
+        };
+        self.lines = run
+            .lines
+            .iter()
+            .map(|line| TextLine {
+                left: line.left + offset.left,
+                right: line.right + offset.left,
+                baseline: line.baseline + offset.top,
+            })
+            .collect();
 
         let color_matrix = ColorMatrix::for_text(color);
+        let pages = &font_for_px.borrow().pages;
+        run.glyphs
+            .iter()
+            .filter_map(|glyph| {
+                let texture = pages.get(glyph.page)?;
+                let translation = Transform::from_translation(
+                    offset.left + glyph.position.left,
+                    offset.top + glyph.position.top,
+                );
+                let mut operation = RenderOperation::new(
+                    glyph.rect,
+                    glyph.tex_coords,
+                    color_matrix,
+                    transforms * translation,
+                );
+                if glyph.sdf {
+                    operation.flags = INSTANCE_SDF;
+                }
+                Some((texture.clone(), operation))
+            })
+            .collect()
+    }
 
-        self.layout
-            .append(fonts, &TextStyle::new(text, size as f32, 0));
-        let mut font_for_px = font_for_px.borrow_mut();
+    /// Lays out the spans continuously, wrapping across them when `max_width` is set. The sized
+    /// fonts of the spans must all come from the same font.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_spans(
+        &mut self,
+        spans: &[SpanLayout],
+        position: Position,
+        max_width: Option<f32>,
+        transforms: Transform,
+        snap_to_pixel: bool,
+        options: &TextOptions,
+        device: &Device,
+        queue: &Queue,
+        texture_context: &TextureContext,
+    ) -> Vec<(Shared<Texture>, RenderOperation)> {
+        let glyphs = layout_glyphs(
+            &mut self.layout,
+            spans,
+            position,
+            max_width,
+            snap_to_pixel,
+            options,
+            device,
+            queue,
+            texture_context,
+        );
+        self.lines = layout_lines(&self.layout);
 
-        let operations = self
-            .layout
-            .glyphs()
+        let color_matrices: Vec<ColorMatrix> = spans
             .iter()
+            .map(|span| ColorMatrix::for_text(span.color))
+            .collect();
+
+        glyphs
+            .into_iter()
             .filter_map(|glyph| {
-                let rect = Rect::new(0.0, 0.0, glyph.width as f32, glyph.height as f32);
+                let texture = spans[glyph.span]
+                    .font_for_px
+                    .borrow()
+                    .pages
+                    .get(glyph.page)?
+                    .clone();
+                let translation =
+                    Transform::from_translation(glyph.position.left, glyph.position.top);
+                let mut operation = RenderOperation::new(
+                    glyph.rect,
+                    glyph.tex_coords,
+                    color_matrices[glyph.span],
+                    transforms * translation,
+                );
+                if glyph.sdf {
+                    operation.flags = INSTANCE_SDF;
+                }
+                Some((texture, operation))
+            })
+            .collect()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn layout_glyphs(
+    layout: &mut Layout<usize>,
+    spans: &[SpanLayout],
+    position: Position,
+    max_width: Option<f32>,
+    snap_to_pixel: bool,
+    options: &TextOptions,
+    device: &Device,
+    queue: &Queue,
+    texture_context: &TextureContext,
+) -> Vec<PlacedGlyph> {
+    let Position { left: x, top: y } = position;
+    layout.reset(&LayoutSettings {
+        x,
+        y,
+        max_width,
+        line_height: options.line_height_multiplier,
+        ..Default::default()
+    });
 
-                font_for_px
-                    .get_or_create_character(glyph.parent, device, queue, texture_context)
-                    .map(|character| RenderOperation {
+    let fonts = match spans.first() {
+        Some(span) => [span.font_for_px.borrow().font.clone()],
+        None => return vec![],
+    };
+    for (index, span) in spans.iter().enumerate() {
+        if span.text.is_empty() {
+            continue;
+        }
+        let text = expand_tabs(span.text, options.tab_width_spaces);
+        layout.append(
+            &fonts,
+            &TextStyle::with_user_data(&text, span.px as f32, 0, index),
+        );
+    }
+
+    // A glyph that doesn't fit may start an atlas over, which drops the pages of the glyphs placed
+    // before it. They are placed again, on the new pages. Generations only go up, so the greatest
+    // one changes when any atlas starts over.
+    let generation = || {
+        spans
+            .iter()
+            .map(|span| span.font_for_px.borrow().generation)
+            .max()
+    };
+    let before = generation();
+    let glyphs = place_glyphs(layout, spans, snap_to_pixel, device, queue, texture_context);
+    if generation() == before {
+        glyphs
+    } else {
+        place_glyphs(layout, spans, snap_to_pixel, device, queue, texture_context)
+    }
+}
+
+/// Rasterizes the glyphs laid out, if they aren't already, and places them.
+fn place_glyphs(
+    layout: &Layout<usize>,
+    spans: &[SpanLayout],
+    snap_to_pixel: bool,
+    device: &Device,
+    queue: &Queue,
+    texture_context: &TextureContext,
+) -> Vec<PlacedGlyph> {
+    layout
+        .glyphs()
+        .iter()
+        .filter(|glyph| glyph.width > 0 && glyph.height > 0)
+        .filter_map(|glyph| {
+            let (x, y) = if snap_to_pixel {
+                (glyph.x.round(), glyph.y.round())
+            } else {
+                (glyph.x, glyph.y)
+            };
+
+            let span = &spans[glyph.user_data];
+            let mut font_for_px = span.font_for_px.borrow_mut();
+            // Only distance fields are drawn at another size than the one they were rasterized at.
+            let scale = span.px as f32 / font_for_px.px() as f32;
+            let sdf = font_for_px.sdf;
+            font_for_px
+                .get_or_create_character(glyph.parent, device, queue, texture_context)
+                .map(|character| {
+                    let padding = character.padding * scale;
+                    PlacedGlyph {
+                        page: character.page,
                         tex_coords: character.tex_coords,
-                        rect,
-                        color_matrix,
-                        transforms: transforms * Transform::from_translation(glyph.x, glyph.y),
-                    })
+                        rect: Rect::new(
+                            0.0,
+                            0.0,
+                            character.width * scale,
+                            character.height * scale,
+                        ),
+                        position: Position::new(x - padding, y - padding),
+                        span: glyph.user_data,
+                        sdf,
+                    }
+                })
+        })
+        .collect()
+}
+
+/// Turns a coverage bitmap into a signed distance field with a margin of `spread` pixels around
+/// it. The edge is at 128, and each pixel away from it moves the value by `127 / spread`.
+fn signed_distance_field(coverage: &[u8], width: usize, height: usize, spread: usize) -> Vec<u8> {
+    let inside = |x: isize, y: isize| {
+        x >= 0
+            && y >= 0
+            && (x as usize) < width
+            && (y as usize) < height
+            && coverage[y as usize * width + x as usize] >= 128
+    };
+
+    let radius = spread as isize;
+    let (field_width, field_height) = (width + 2 * spread, height + 2 * spread);
+    let mut field = Vec::with_capacity(field_width * field_height);
+    for y in 0..field_height as isize {
+        for x in 0..field_width as isize {
+            let (x, y) = (x - radius, y - radius);
+            let is_inside = inside(x, y);
+
+            // The closest pixel on the other side of the edge, by brute force as glyphs are small.
+            let mut closest = (radius + 1) * (radius + 1);
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    if inside(x + dx, y + dy) != is_inside {
+                        closest = closest.min(dx * dx + dy * dy);
+                    }
+                }
+            }
+
+            // The edge lies about halfway between the two pixels.
+            let distance = ((closest as f32).sqrt() - 0.5).min(spread as f32);
+            let signed = if is_inside { distance } else { -distance };
+            let value = 128.0 + signed * 127.0 / spread as f32;
+            field.push(value.clamp(0.0, 255.0) as u8);
+        }
+    }
+    field
+}
+
+/// The extent of each line of the text in the layout.
+fn layout_lines(layout: &Layout<usize>) -> Vec<TextLine> {
+    let glyphs = layout.glyphs();
+    let lines = match layout.lines() {
+        Some(lines) => lines,
+        None => return vec![],
+    };
+
+    lines
+        .iter()
+        .filter_map(|line| {
+            let visible = glyphs[line.glyph_start..=line.glyph_end]
+                .iter()
+                .filter(|glyph| glyph.width > 0 && glyph.height > 0);
+            let (left, right) = visible.fold(None, |extent, glyph| {
+                let (left, right) = (glyph.x, glyph.x + glyph.width as f32);
+                Some(match extent {
+                    Some((min, max)) => (left.min(min), right.max(max)),
+                    None => (left, right),
+                })
+            })?;
+
+            Some(TextLine {
+                left,
+                right,
+                baseline: line.baseline_y,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CanvasSettings;
+
+    const VT323: &[u8] = include_bytes!("../fonts/VT323-Regular.ttf");
+    const ROBOTO: &[u8] = include_bytes!("../../resources/fonts/Roboto-Regular.ttf");
+
+    fn font(bytes: &[u8]) -> Shared<fontdue::Font> {
+        Shared::new(fontdue::Font::from_bytes(bytes, FontSettings::default()).unwrap())
+    }
+
+    fn run_key(text: &str, generation: usize) -> RunKey<&str> {
+        RunKey {
+            font_id: FontId(0, 16),
+            px: 16,
+            generation,
+            text,
+            tab_width_spaces: None,
+            line_height_multiplier: 1.0f32.to_bits(),
+            snap_to_pixel: false,
+        }
+    }
+
+    #[test]
+    fn a_stored_run_key_matches_the_key_it_was_stored_with() {
+        let key = run_key("Score: 42", 3);
+        let stored = key.with_text(key.text.to_string());
+
+        assert!(stored.with_text(stored.text.as_str()) == key);
+        assert!(stored.with_text(stored.text.as_str()).hash_value() == key.hash_value());
+    }
+
+    #[test]
+    fn runs_of_a_restarted_atlas_are_stored_apart() {
+        assert!(run_key("Score: 42", 3) != run_key("Score: 42", 4));
+        assert_ne!(
+            run_key("Score: 42", 3).hash_value(),
+            run_key("Score: 42", 4).hash_value()
+        );
+    }
+
+    /// The sizes of the glyphs of VT323 from A to z, which take more than a page at 400px.
+    fn large_glyph_sizes() -> Vec<(i32, i32)> {
+        let font = font(VT323);
+        ('A'..='Z')
+            .chain('a'..='z')
+            .map(|character| {
+                let metrics = font.metrics(character, 400.0);
+                (metrics.width as i32, metrics.height as i32)
             })
+            .collect()
+    }
+
+    fn overlaps(a: &rect_packer::Rect, b: &rect_packer::Rect) -> bool {
+        a.left() < b.right() && b.left() < a.right() && a.top() < b.bottom() && b.top() < a.bottom()
+    }
+
+    #[test]
+    fn glyphs_that_overflow_a_page_are_packed_on_the_next_one() {
+        let mut page_packer = PagePacker::default();
+
+        let placements: Vec<Placement> = large_glyph_sizes()
+            .into_iter()
+            .map(|(width, height)| page_packer.pack(width, height, None).unwrap())
             .collect();
 
-        operations
+        assert!(page_packer.packers.len() > 1);
+        for (index, placement) in placements.iter().enumerate() {
+            assert!(!placement.restarted);
+            assert!(placement.page < page_packer.packers.len());
+            assert!(placement.rect.right() <= CACHE_WIDTH as i32);
+            assert!(placement.rect.bottom() <= CACHE_WIDTH as i32);
+            for other in &placements[..index] {
+                assert!(other.page != placement.page || !overlaps(&other.rect, &placement.rect));
+            }
+        }
+    }
+
+    #[test]
+    fn pages_start_over_past_the_budget() {
+        let mut page_packer = PagePacker::default();
+
+        let restarts: Vec<bool> = large_glyph_sizes()
+            .into_iter()
+            .map(|(width, height)| {
+                let placement = page_packer.pack(width, height, Some(1)).unwrap();
+                assert_eq!(placement.page, 0);
+                placement.restarted
+            })
+            .collect();
+
+        assert!(!restarts[0]);
+        assert!(restarts.contains(&true));
+        assert_eq!(page_packer.packers.len(), 1);
+    }
+
+    #[test]
+    fn glyphs_bigger_than_a_page_are_not_packed() {
+        let mut page_packer = PagePacker::default();
+
+        assert!(page_packer.pack(CACHE_WIDTH as i32 + 1, 8, None).is_none());
+        assert!(page_packer.packers.is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn glyphs_that_overflow_a_page_go_on_the_next_one() {
+        let (device, queue) = crate::tests::device();
+        let texture_context = TextureContext::new(&device, &queue, &CanvasSettings::default());
+        // At this size, the letters take more than one page.
+        let mut sized_font = SizedFont::new(FontId(0, 400), font(VT323), false, None);
+        let characters = ('A'..='Z').chain('a'..='z');
+
+        for character in characters.clone() {
+            let created = sized_font
+                .create_character(character, &device, &queue, &texture_context)
+                .unwrap();
+            assert!(created.is_some(), "{character} wasn't created");
+        }
+
+        assert!(sized_font.pages.len() > 1);
+        for character in characters {
+            assert!(sized_font.characters[&character].page < sized_font.pages.len());
+        }
+    }
+
+    #[test]
+    fn line_breaks_are_measured_like_they_are_laid_out_with_every_font() {
+        let text = "One\nTwo\nThree";
+        let px = 24;
+
+        for bytes in [VT323, ROBOTO] {
+            let mut layout = Layout::<usize>::new(CoordinateSystem::PositiveYDown);
+            layout.reset(&LayoutSettings::default());
+            layout.append(
+                &[font(bytes)],
+                &TextStyle::with_user_data(text, px as f32, 0, 0),
+            );
+            let baselines: Vec<f32> = layout_lines(&layout)
+                .iter()
+                .map(|line| line.baseline)
+                .collect();
+
+            let font = Font::load_font_bytes(bytes, FontSettings::default()).unwrap();
+            let line_height = font.line_height(px);
+            assert_eq!(baselines.len(), 3);
+            assert_eq!(baselines[1] - baselines[0], line_height);
+            assert_eq!(baselines[2] - baselines[1], line_height);
+            assert_eq!(font.measure_text(text, px).1, 3.0 * line_height);
+        }
+    }
+
+    #[test]
+    fn lines_are_measured_as_wide_as_they_are_laid_out() {
+        // Pairs that fonts usually kern, and advances that aren't whole pixels.
+        let text = "AVATAR Today";
+        let px = 24;
+        let max_width = 1000.0;
+
+        for bytes in [VT323, ROBOTO] {
+            let mut layout = Layout::<usize>::new(CoordinateSystem::PositiveYDown);
+            layout.reset(&LayoutSettings {
+                max_width: Some(max_width),
+                ..LayoutSettings::default()
+            });
+            layout.append(
+                &[font(bytes)],
+                &TextStyle::with_user_data(text, px as f32, 0, 0),
+            );
+            let laid_out_width = max_width - layout.lines().unwrap()[0].padding;
+
+            let font = Font::load_font_bytes(bytes, FontSettings::default()).unwrap();
+            assert_eq!(font.measure_text(text, px).0, laid_out_width);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn a_glyph_of_odd_width_is_uploaded_row_by_row() {
+        let (device, queue) = crate::tests::device();
+        let size = wgpu::Extent3d {
+            width: 32,
+            height: 8,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
+        });
+        let (width, height) = (17, 5);
+        let bitmap: Vec<u8> = (0..width * height).map(|index| index as u8 + 1).collect();
+
+        write_glyph(&queue, &texture, (3, 2), (width, height), &bitmap);
+
+        // Reading back needs aligned rows, which a single byte per pixel fits in one of.
+        let bytes_per_row = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: (bytes_per_row * size.height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&Default::default());
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(bytes_per_row),
+                    rows_per_image: std::num::NonZeroU32::new(size.height),
+                },
+            },
+            size,
+        );
+        queue.submit(Some(encoder.finish()));
+        let pixels = crate::tests::block_on(crate::futures::AsyncBufferView::new(
+            buffer.slice(..),
+            &device,
+        ))
+        .unwrap();
+
+        for row in 0..height {
+            let start = ((row + 2) * bytes_per_row + 3) as usize;
+            let expected = &bitmap[(row * width) as usize..((row + 1) * width) as usize];
+            assert_eq!(&pixels[start..start + width as usize], expected);
+        }
     }
 }
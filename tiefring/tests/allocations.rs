@@ -0,0 +1,98 @@
+//! Allocation counts of a frame, in their own test binary since counting them replaces the global
+//! allocator.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use tiefring::{CanvasSettings, Color, GraphicsRenderer, Rect};
+use wgpu::{Device, Queue};
+
+/// Counts the allocations of each thread, so that wgpu's own threads don't add up.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// How many times `function` allocated on this thread.
+fn count_allocations(function: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    function();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+/// Polls the future until it's ready, which wgpu's native futures are right away.
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct NoopWaker;
+    impl std::task::Wake for NoopWaker {
+        fn wake(self: std::sync::Arc<Self>) {}
+    }
+
+    let waker = std::task::Waker::from(std::sync::Arc::new(NoopWaker));
+    let mut context = std::task::Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+/// A device from a Vulkan, Metal or DX12 adapter, `None` on machines without one, like most CI
+/// runners.
+fn device() -> Option<(Device, Queue)> {
+    let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
+    let adapter = block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))?;
+    block_on(adapter.request_device(&wgpu::DeviceDescriptor::default(), None)).ok()
+}
+
+/// What a frame of 50,000 rects may allocate once the caches are warm. Rects are batched in
+/// blocks of 2048, and each block costs some allocations inside wgpu to upload its instances and
+/// submit: the limit leaves plenty of room for those, yet is a tenth of one allocation per rect.
+const MAX_ALLOCATIONS_PER_FRAME: usize = 5_000;
+
+#[test]
+fn a_frame_of_50k_rects_allocates_per_block_rather_than_per_rect() {
+    let (device, queue) = match device() {
+        Some(device) => device,
+        None => return,
+    };
+    let mut graphics_renderer =
+        GraphicsRenderer::new(&device, &queue, 64, 64, &CanvasSettings::default());
+    let color = Color::rgb(1.0, 0.0, 0.0);
+
+    let mut frame = || {
+        graphics_renderer.prepare(&device, &queue, |graphics| {
+            for index in 0..50_000 {
+                graphics.draw_rect(Rect::new(index as f32 % 64.0, 0.0, 1.0, 1.0), color);
+            }
+        });
+        device.poll(wgpu::Maintain::Wait);
+    };
+    // The first frames fill the caches.
+    frame();
+    frame();
+
+    assert!(count_allocations(frame) <= MAX_ALLOCATIONS_PER_FRAME);
+}